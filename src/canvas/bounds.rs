@@ -0,0 +1,76 @@
+use std::time::SystemTime;
+
+use crate::data::{Area, Coordinate};
+
+/// The area in which pixels can be placed, which can optionally grow over time.
+#[derive(Clone, Debug)]
+pub struct CanvasBounds {
+    /// The initial bounds
+    initial: Area,
+    /// Sorted by time, each area replaces the previous one once its time has come.
+    expansions: Vec<(SystemTime, Area)>,
+}
+
+impl CanvasBounds {
+    /// A canvas which covers every coordinate that can be encoded.
+    pub fn unbounded() -> Self {
+        Self::fixed(Area::EVERYTHING)
+    }
+
+    pub fn fixed(area: Area) -> Self {
+        Self {
+            initial: area,
+            expansions: Vec::new(),
+        }
+    }
+
+    /// At `time`, replace the bounds with `area`.
+    /// Despite the name, `area` does not have to contain the previous bounds.
+    pub fn expand_at(mut self, time: SystemTime, area: Area) -> Self {
+        let index = self.expansions.partition_point(|(t, _)| *t <= time);
+        self.expansions.insert(index, (time, area));
+        self
+    }
+
+    pub fn current(&self, now: SystemTime) -> Area {
+        self.expansions
+            .iter()
+            .take_while(|(time, _)| *time <= now)
+            .last()
+            .map_or(self.initial, |(_, area)| *area)
+    }
+
+    /// The next time after `now` at which the bounds will change, if any.
+    pub fn next_change(&self, now: SystemTime) -> Option<SystemTime> {
+        self.expansions
+            .iter()
+            .map(|(time, _)| *time)
+            .find(|time| *time > now)
+    }
+
+    pub fn contains(&self, coord: Coordinate, now: SystemTime) -> bool {
+        self.current(now).contains(coord)
+    }
+}
+
+#[test]
+fn test_canvas_bounds_expansion() {
+    use std::time::Duration;
+
+    let area =
+        |size| Area::try_new(Coordinate { x: 0, y: 0 }, Coordinate { x: size, y: size }).unwrap();
+    let start = SystemTime::UNIX_EPOCH + Duration::from_secs(1000);
+    let bounds = CanvasBounds::fixed(area(10))
+        .expand_at(start + Duration::from_secs(20), area(30))
+        .expand_at(start + Duration::from_secs(10), area(20));
+    assert_eq!(bounds.current(start), area(10));
+    assert_eq!(
+        bounds.next_change(start),
+        Some(start + Duration::from_secs(10))
+    );
+    assert_eq!(bounds.current(start + Duration::from_secs(10)), area(20));
+    assert_eq!(bounds.current(start + Duration::from_secs(25)), area(30));
+    assert_eq!(bounds.next_change(start + Duration::from_secs(25)), None);
+    assert!(!bounds.contains(Coordinate { x: 15, y: 0 }, start));
+    assert!(bounds.contains(Coordinate { x: 15, y: 0 }, start + Duration::from_secs(15)));
+}
@@ -0,0 +1,29 @@
+mod bounds;
+mod palette;
+mod protected;
+
+pub use bounds::CanvasBounds;
+pub use palette::Palette;
+pub use protected::ProtectedArea;
+
+use crate::{
+    data::Color,
+    ratelimit::{PixelCooldownSettings, RatelimitSettings},
+};
+
+/// Everything which can be configured separately for each canvas.
+#[derive(Clone)]
+pub struct CanvasSettings {
+    /// The default ratelimit for clients using this canvas,
+    /// unless it is overridden for a specific user.
+    pub ratelimit: RatelimitSettings,
+    /// If set, each user can only place pixels at the configured rate.
+    pub pixel_cooldown: Option<PixelCooldownSettings>,
+    pub bounds: CanvasBounds,
+    /// If set, only colors from the palette can be placed.
+    pub palette: Option<Palette>,
+    /// The color of pixels which have never been painted
+    pub background: Color,
+    /// Areas which only some users may paint
+    pub protected: Vec<ProtectedArea>,
+}
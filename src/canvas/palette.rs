@@ -0,0 +1,85 @@
+use crate::data::Color;
+
+/// A list of colors which are the only colors that can be placed on a canvas.
+#[derive(Clone, Debug)]
+pub struct Palette {
+    colors: Vec<Color>,
+    snap_to_nearest: bool,
+}
+
+impl Palette {
+    /// Colors which are not in the palette will be rejected.
+    /// Panics if `colors` is empty.
+    pub fn new(colors: Vec<Color>) -> Self {
+        assert!(
+            !colors.is_empty(),
+            "a palette must contain at least one color"
+        );
+        Self {
+            colors,
+            snap_to_nearest: false,
+        }
+    }
+    /// Instead of rejecting colors which are not in the palette,
+    /// replace them with the most similar color from the palette.
+    pub fn snap_to_nearest(mut self) -> Self {
+        self.snap_to_nearest = true;
+        self
+    }
+    /// This is the inverse of `snap_to_nearest()`.
+    pub fn reject_others(mut self) -> Self {
+        self.snap_to_nearest = false;
+        self
+    }
+
+    pub fn colors(&self) -> &[Color] {
+        &self.colors
+    }
+
+    /// Returns the color which should be placed instead of `color`,
+    /// or `None` if the color should be rejected.
+    pub fn apply(&self, color: Color) -> Option<Color> {
+        if self.colors.contains(&color) {
+            Some(color)
+        } else if self.snap_to_nearest {
+            Some(self.nearest(color))
+        } else {
+            None
+        }
+    }
+
+    /// The color from the palette which is most similar to `color`.
+    pub fn nearest(&self, color: Color) -> Color {
+        *self
+            .colors
+            .iter()
+            .min_by_key(|c| {
+                let dr = c.r as i32 - color.r as i32;
+                let dg = c.g as i32 - color.g as i32;
+                let db = c.b as i32 - color.b as i32;
+                dr * dr + dg * dg + db * db
+            })
+            .unwrap()
+    }
+}
+
+#[test]
+fn test_palette() {
+    let black = Color { r: 0, g: 0, b: 0 };
+    let white = Color {
+        r: 31,
+        g: 31,
+        b: 31,
+    };
+    let gray = Color {
+        r: 20,
+        g: 20,
+        b: 20,
+    };
+    let palette = Palette::new(vec![black, white]);
+    assert_eq!(palette.apply(white), Some(white));
+    assert_eq!(palette.apply(gray), None);
+    let palette = palette.snap_to_nearest();
+    assert_eq!(palette.apply(gray), Some(white));
+    assert_eq!(palette.apply(Color { r: 9, g: 3, b: 0 }), Some(black));
+}
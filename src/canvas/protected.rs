@@ -0,0 +1,21 @@
+use crate::{
+    data::{Area, Coordinate},
+    users::{Role, UserId},
+};
+
+/// An area which only some users may paint, such as an event's logo or a zone owned by the moderators.
+#[derive(Clone, Debug)]
+pub struct ProtectedArea {
+    pub area: Area,
+    /// Users with at least this role may paint in the area.
+    pub min_role: Role,
+    /// These users may paint in the area regardless of their role.
+    pub users: Vec<UserId>,
+}
+
+impl ProtectedArea {
+    /// Whether the user may paint the pixel, if it is inside of this area.
+    pub fn allows(&self, coord: Coordinate, user: &UserId, role: Role) -> bool {
+        !self.area.contains(coord) || role >= self.min_role || self.users.contains(user)
+    }
+}
@@ -0,0 +1,171 @@
+use std::{collections::HashMap, path::PathBuf, time::SystemTime};
+
+use clap::Args;
+
+use crate::{
+    cli::{canvas_history, parse_area, parse_color, parse_time, scaled_size},
+    config::Config,
+    data::{Area, Color},
+    history::{EditFilter, read_edits},
+    image::RgbImage,
+};
+
+#[derive(Args)]
+pub struct ExportArgs {
+    /// The canvas' name, if not set, the default canvas is used
+    #[arg(long)]
+    canvas: Option<String>,
+    /// The area to render, `left,top,right,bottom`
+    #[arg(long, value_parser = parse_area, allow_hyphen_values = true)]
+    area: Area,
+    /// Render the canvas as it was at this unix timestamp (in seconds) instead of its latest state
+    #[arg(long, value_parser = parse_time)]
+    at: Option<SystemTime>,
+    /// Makes every pixel this many pixels wide and tall.
+    /// The image can have at most 16384×16384 pixels.
+    #[arg(long, default_value_t = 1)]
+    scale: u32,
+    /// The color of pixels which have never been painted, `r,g,b` with values from 0 to 31.
+    /// Defaults to the canvas' background.
+    #[arg(long, value_parser = parse_color)]
+    background: Option<Color>,
+    /// The image file to create. If it ends with `.ppm`, a PPM image is created, otherwise a PNG image.
+    #[arg(long)]
+    output: PathBuf,
+}
+
+pub async fn run(config: &Config, args: ExportArgs) -> Result<(), String> {
+    let (directory, canvas_settings) = canvas_history(config, args.canvas.as_deref())?;
+    scaled_size(args.area, args.scale)?;
+    let filter = EditFilter {
+        area: Some(args.area),
+        to: args.at,
+        ..Default::default()
+    };
+    let mut pixels = HashMap::new();
+    for edit in read_edits(&directory, &filter)
+        .await
+        .map_err(|e| format!("Could not read {}: {e}", directory.display()))?
+    {
+        pixels.insert(edit.coord, edit.new_color);
+    }
+    let image = RgbImage::render(
+        args.area,
+        args.background.unwrap_or(canvas_settings.background),
+        &pixels,
+    )
+    .scaled(args.scale);
+    let bytes = if args
+        .output
+        .extension()
+        .is_some_and(|extension| extension.eq_ignore_ascii_case("ppm"))
+    {
+        image.encode_ppm()
+    } else {
+        image
+            .encode_png()
+            .map_err(|e| format!("Could not encode the image: {e}"))?
+    };
+    tokio::fs::write(&args.output, bytes)
+        .await
+        .map_err(|e| format!("Could not write {}: {e}", args.output.display()))
+}
+
+#[tokio::test]
+async fn test_export() {
+    use std::time::Duration;
+
+    use crate::{
+        config::DEFAULT_CANVAS_NAME,
+        data::Coordinate,
+        history::{Edit, HistoryLog},
+        users::UserId,
+    };
+
+    let directory = std::env::temp_dir().join(format!("p2ws-export-test-{}", std::process::id()));
+    let mut config = Config::default();
+    let history = config.history.as_mut().unwrap();
+    history.directory = directory.join("history");
+    let mut log = HistoryLog::open(
+        history.canvas_directory(DEFAULT_CANVAS_NAME),
+        history.rotation,
+    )
+    .await
+    .unwrap();
+    let start = SystemTime::UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+    let (black, red) = (Color { r: 0, g: 0, b: 0 }, Color { r: 31, g: 0, b: 0 });
+    for (secs, x, color) in [(0, 0, black), (30, 1, black), (60, 0, red)] {
+        log.append(&Edit {
+            time: start + Duration::from_secs(secs),
+            user: UserId::new("painter".to_owned()),
+            coord: Coordinate { x, y: 0 },
+            old_color: None,
+            new_color: color,
+        })
+        .await
+        .unwrap();
+    }
+    log.flush().await.unwrap();
+    let args = |output: &str| ExportArgs {
+        canvas: None,
+        area: parse_area("0,0,2,0").unwrap(),
+        at: None,
+        scale: 1,
+        background: None,
+        output: directory.join(output),
+    };
+    let read = |file: &str| std::fs::read(directory.join(file)).unwrap();
+    let ppm = |pixels: [[u8; 3]; 3]| [&b"P6\n3 1\n255\n"[..], pixels.as_flattened()].concat();
+
+    run(&config, args("latest.ppm")).await.unwrap();
+    assert_eq!(
+        read("latest.ppm"),
+        ppm([[255, 0, 0], [0, 0, 0], [255, 255, 255]])
+    );
+    let earlier = ExportArgs {
+        at: Some(start + Duration::from_secs(30)),
+        background: Some(Color { r: 0, g: 0, b: 31 }),
+        ..args("earlier.ppm")
+    };
+    run(&config, earlier).await.unwrap();
+    assert_eq!(
+        read("earlier.ppm"),
+        ppm([[0, 0, 0], [0, 0, 0], [0, 0, 255]])
+    );
+    run(
+        &config,
+        ExportArgs {
+            scale: 2,
+            ..args("latest.png")
+        },
+    )
+    .await
+    .unwrap();
+    assert!(read("latest.png").starts_with(b"\x89PNG"));
+
+    let error = run(
+        &config,
+        ExportArgs {
+            scale: u32::MAX,
+            ..args("large.png")
+        },
+    )
+    .await
+    .unwrap_err();
+    assert!(error.starts_with("The image would be too large"));
+    let whole_canvas = ExportArgs {
+        area: parse_area("-32768,-32768,32767,32767").unwrap(),
+        ..args("whole.png")
+    };
+    let error = run(&config, whole_canvas).await.unwrap_err();
+    assert!(error.contains("65536×65536 pixels"), "{error}");
+    assert!(!directory.join("whole.png").exists());
+    config.history = None;
+    assert_eq!(
+        run(&config, args("disabled.png")).await.unwrap_err(),
+        "The history is disabled in the config"
+    );
+    assert!(!directory.join("disabled.png").exists());
+
+    std::fs::remove_dir_all(&directory).unwrap();
+}
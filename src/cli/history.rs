@@ -0,0 +1,64 @@
+use std::time::{Duration, SystemTime};
+
+use clap::Args;
+
+use crate::{
+    cli::{canvas_history, parse_area, parse_time},
+    config::Config,
+    data::{Area, Color},
+    history::{EditFilter, read_edits},
+    users::UserId,
+};
+
+#[derive(Args)]
+pub struct HistoryArgs {
+    /// The canvas' name, if not set, the default canvas is used
+    #[arg(long)]
+    canvas: Option<String>,
+    /// Only edits within `left,top,right,bottom`
+    #[arg(long, value_parser = parse_area, allow_hyphen_values = true)]
+    area: Option<Area>,
+    /// Only edits made at or after this unix timestamp (in seconds)
+    #[arg(long, value_parser = parse_time)]
+    from: Option<SystemTime>,
+    /// Only edits made at or before this unix timestamp (in seconds)
+    #[arg(long, value_parser = parse_time)]
+    to: Option<SystemTime>,
+    /// Only edits made by this user
+    #[arg(long)]
+    user: Option<String>,
+}
+
+pub async fn run(config: &Config, args: HistoryArgs) -> Result<(), String> {
+    let (directory, _) = canvas_history(config, args.canvas.as_deref())?;
+    let filter = EditFilter {
+        area: args.area,
+        from: args.from,
+        to: args.to,
+        user: args.user.map(UserId::new),
+    };
+    let edits = read_edits(&directory, &filter)
+        .await
+        .map_err(|e| format!("Could not read {}: {e}", directory.display()))?;
+    let fmt_color = |color: Option<Color>| match color {
+        Some(Color { r, g, b }) => format!("{r},{g},{b}"),
+        None => "-".to_owned(),
+    };
+    for edit in edits {
+        let time = edit
+            .time
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap_or(Duration::ZERO);
+        println!(
+            "{}.{:03} {:?} {} {} {} {}",
+            time.as_secs(),
+            time.subsec_millis(),
+            edit.user.username(),
+            edit.coord.x,
+            edit.coord.y,
+            fmt_color(edit.old_color),
+            fmt_color(Some(edit.new_color)),
+        );
+    }
+    Ok(())
+}
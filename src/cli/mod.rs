@@ -0,0 +1,182 @@
+mod export;
+mod history;
+mod timelapse;
+mod user;
+
+use std::{
+    path::{Path, PathBuf},
+    process::ExitCode,
+    time::{Duration, SystemTime},
+};
+
+use clap::{Parser, Subcommand};
+
+use crate::{
+    canvas::{CanvasSettings, Palette},
+    config::Config,
+    data::{Area, Color, Coordinate},
+};
+
+/// a pixelplace websocket server
+#[derive(Parser)]
+pub struct Args {
+    /// The server's config file. If it doesn't exist, the default config is used.
+    #[arg(long, default_value = "config.toml")]
+    pub config: PathBuf,
+    /// The file containing the users who can authenticate
+    #[arg(long, default_value = "users.toml")]
+    pub users: PathBuf,
+    /// The file in which bans are saved. It is created when the first user is banned.
+    #[arg(long, default_value = "bans.toml")]
+    pub bans: PathBuf,
+    /// A file containing the key used to encrypt OTP settings in the users file.
+    /// If not set, the key is read from the P2WS_OTP_KEY environment variable, if it is set.
+    #[arg(long)]
+    pub otp_key_file: Option<PathBuf>,
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Subcommand)]
+pub enum Command {
+    /// Accept connections (this is the default)
+    Serve,
+    /// Print edits from a canvas' history, oldest first
+    History(history::HistoryArgs),
+    /// Render an area of a canvas to a PNG image, using the canvas' history
+    ///
+    /// The image is built from the history files, so this requires the history to be enabled in the config.
+    /// Pixels painted while it was disabled are missing. Use the admin socket's `snapshot` command
+    /// to save the current state of a running server instead.
+    Export(export::ExportArgs),
+    /// Replay a canvas' history and save it as a sequence of images or as an animated GIF
+    Timelapse(timelapse::TimelapseArgs),
+    /// Add, remove or list users, or replace a user's OTP secret
+    User(user::UserArgs),
+}
+
+impl Command {
+    pub async fn run(self, args: &Args) -> ExitCode {
+        let config = match load_config(&args.config).await {
+            Ok(config) => config,
+            Err(e) => {
+                eprintln!("{e}");
+                return ExitCode::FAILURE;
+            }
+        };
+        let result = match self {
+            Self::Serve => unreachable!("the serve command is handled in main"),
+            Self::History(history_args) => history::run(&config, history_args).await,
+            Self::Export(export_args) => export::run(&config, export_args).await,
+            Self::Timelapse(timelapse_args) => timelapse::run(&config, timelapse_args).await,
+            Self::User(user_args) => user::run(args, user_args).await,
+        };
+        match result {
+            Ok(()) => ExitCode::SUCCESS,
+            Err(e) => {
+                eprintln!("{e}");
+                ExitCode::FAILURE
+            }
+        }
+    }
+}
+
+pub async fn load_config(path: &Path) -> Result<Config, String> {
+    match tokio::fs::read_to_string(path).await {
+        Ok(config) => Config::from_toml(&config)
+            .map_err(|e| format!("Could not parse {}: {e}", path.display())),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(Config::default()),
+        Err(e) => Err(format!("Could not read {}: {e}", path.display())),
+    }
+}
+
+/// The directory containing the history of the canvas with the given name,
+/// or of the default canvas if `canvas_name` is `None`, and the canvas' settings.
+pub fn canvas_history<'a>(
+    config: &'a Config,
+    canvas_name: Option<&str>,
+) -> Result<(PathBuf, &'a CanvasSettings), String> {
+    let Some(history_settings) = &config.history else {
+        return Err("The history is disabled in the config".to_owned());
+    };
+    let (name, settings) = match canvas_name {
+        Some(canvas_name) => config
+            .canvases
+            .iter()
+            .find(|(name, _)| name == canvas_name)
+            .ok_or_else(|| format!("There is no canvas named {canvas_name:?}"))?,
+        None => &config.canvases[0],
+    };
+    Ok((history_settings.canvas_directory(name), settings))
+}
+
+/// The largest image which is rendered, 16384×16384 pixels.
+pub const MAX_IMAGE_PIXELS: u64 = 1 << 28;
+
+/// The size of an image of `area` in which every pixel is `scale` pixels wide and tall,
+/// or an error if it would have more than `MAX_IMAGE_PIXELS` pixels.
+pub fn scaled_size(area: Area, scale: u32) -> Result<(u32, u32), String> {
+    let scale = u64::from(scale.max(1));
+    let width = (area.right() as i64 - area.left() as i64 + 1) as u64 * scale;
+    let height = (area.bottom() as i64 - area.top() as i64 + 1) as u64 * scale;
+    if width.saturating_mul(height) > MAX_IMAGE_PIXELS {
+        return Err(format!(
+            "The image would be too large ({width}×{height} pixels), it can have at most {MAX_IMAGE_PIXELS} pixels"
+        ));
+    }
+    Ok((width as u32, height as u32))
+}
+
+/// Parses `left,top,right,bottom`
+pub fn parse_area(s: &str) -> Result<Area, String> {
+    let numbers = s
+        .split(',')
+        .map(|n| n.trim().parse::<i16>())
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|e| e.to_string())?;
+    let [left, top, right, bottom] = numbers[..] else {
+        return Err("expected left,top,right,bottom".to_owned());
+    };
+    Area::try_new(
+        Coordinate { x: left, y: top },
+        Coordinate {
+            x: right,
+            y: bottom,
+        },
+    )
+    .ok_or_else(|| {
+        "left must not be greater than right, and top not greater than bottom".to_owned()
+    })
+}
+
+/// Parses `r,g,b` where each component is 0 to 31.
+pub fn parse_color(s: &str) -> Result<Color, String> {
+    let numbers = s
+        .split(',')
+        .map(|n| n.trim().parse::<u8>())
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|e| e.to_string())?;
+    match numbers[..] {
+        [r, g, b] if r < 32 && g < 32 && b < 32 => Ok(Color { r, g, b }),
+        _ => Err("expected r,g,b with values from 0 to 31".to_owned()),
+    }
+}
+
+/// Parses colors like `parse_color`, separated by `/`.
+pub fn parse_palette(s: &str) -> Result<Palette, String> {
+    let colors = s
+        .split('/')
+        .map(parse_color)
+        .collect::<Result<Vec<_>, _>>()?;
+    Ok(Palette::new(colors))
+}
+
+/// Parses a unix timestamp in seconds
+pub fn parse_time(s: &str) -> Result<SystemTime, String> {
+    let seconds = s.parse::<f64>().map_err(|e| e.to_string())?;
+    if seconds.is_finite() && seconds >= 0.0 {
+        Ok(SystemTime::UNIX_EPOCH + Duration::from_secs_f64(seconds))
+    } else {
+        Err("expected a unix timestamp in seconds".to_owned())
+    }
+}
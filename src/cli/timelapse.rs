@@ -0,0 +1,279 @@
+use std::{
+    collections::HashMap,
+    path::PathBuf,
+    time::{Duration, SystemTime},
+};
+
+use clap::{Args, ValueEnum};
+
+use crate::{
+    cli::{canvas_history, parse_area, parse_color, parse_time, scaled_size},
+    config::Config,
+    data::{Area, Color},
+    history::{EditFilter, read_edits},
+    image::{GifWriter, RgbImage},
+};
+
+/// Timelapses with more frames are rejected, so that a small `--interval` can't fill the disk by accident.
+const MAX_FRAMES: u64 = 100_000;
+
+#[derive(Args)]
+pub struct TimelapseArgs {
+    /// The canvas' name, if not set, the default canvas is used
+    #[arg(long)]
+    canvas: Option<String>,
+    /// The area to render, `left,top,right,bottom`
+    #[arg(long, value_parser = parse_area, allow_hyphen_values = true)]
+    area: Area,
+    /// The time of the first frame as a unix timestamp (in seconds), defaults to the time of the first edit
+    #[arg(long, value_parser = parse_time)]
+    from: Option<SystemTime>,
+    /// The time of the last frame as a unix timestamp (in seconds), defaults to the time of the last edit
+    #[arg(long, value_parser = parse_time)]
+    to: Option<SystemTime>,
+    /// How many seconds of the canvas' history each frame covers
+    #[arg(long, default_value_t = 60.0)]
+    interval: f64,
+    /// Makes every pixel this many pixels wide and tall.
+    /// Frames can have at most 16384×16384 pixels.
+    #[arg(long, default_value_t = 1)]
+    scale: u32,
+    /// The color of pixels which have never been painted, `r,g,b` with values from 0 to 31.
+    /// Defaults to the canvas' background.
+    #[arg(long, value_parser = parse_color)]
+    background: Option<Color>,
+    /// If this ends with `.gif`, an animated GIF is created,
+    /// otherwise, this is a directory in which the frames are saved as numbered files.
+    #[arg(long)]
+    output: PathBuf,
+    /// The format of the numbered files, ignored for GIFs
+    #[arg(long, value_enum, default_value_t = FrameFormat::Png)]
+    format: FrameFormat,
+    /// How many milliseconds each frame is shown in a GIF
+    #[arg(long, default_value_t = 100)]
+    frame_delay: u32,
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum FrameFormat {
+    Png,
+    Ppm,
+}
+
+pub async fn run(config: &Config, args: TimelapseArgs) -> Result<(), String> {
+    if !(args.interval.is_finite() && args.interval > 0.0) {
+        return Err("--interval must be a positive number".to_owned());
+    }
+    let interval = Duration::from_secs_f64(args.interval);
+    let gif_output = args
+        .output
+        .extension()
+        .is_some_and(|extension| extension.eq_ignore_ascii_case("gif"));
+    let (width, height) = scaled_size(args.area, args.scale)?;
+    if gif_output && (width > u16::MAX as u32 || height > u16::MAX as u32) {
+        return Err(format!(
+            "GIFs can be at most 65535 pixels wide and tall, but the frames would be {width}x{height} pixels"
+        ));
+    }
+    let (directory, canvas_settings) = canvas_history(config, args.canvas.as_deref())?;
+    let background = args.background.unwrap_or(canvas_settings.background);
+    let filter = EditFilter {
+        area: Some(args.area),
+        to: args.to,
+        ..Default::default()
+    };
+    let edits = read_edits(&directory, &filter)
+        .await
+        .map_err(|e| format!("Could not read {}: {e}", directory.display()))?;
+    let (Some(first_edit), Some(last_edit)) = (edits.first(), edits.last()) else {
+        return Err("There are no edits in this area and time range".to_owned());
+    };
+    let from = args.from.unwrap_or(first_edit.time);
+    let to = args.to.unwrap_or(last_edit.time);
+    if to < from {
+        return Err("--to must not be before --from".to_owned());
+    }
+    let frames =
+        (to.duration_since(from).unwrap_or_default().as_secs_f64() / args.interval).ceil() + 1.0;
+    if frames > MAX_FRAMES as f64 {
+        return Err(format!(
+            "This would create {frames} frames, but at most {MAX_FRAMES} are allowed. Use a larger --interval or a shorter time range."
+        ));
+    }
+
+    let mut gif_writer = None;
+    if gif_output {
+        let file = std::fs::File::create(&args.output)
+            .map_err(|e| format!("Could not create {}: {e}", args.output.display()))?;
+        gif_writer = Some(
+            GifWriter::new(
+                std::io::BufWriter::new(file),
+                width,
+                height,
+                args.frame_delay,
+            )
+            .map_err(|e| format!("Could not write {}: {e}", args.output.display()))?,
+        );
+    } else {
+        tokio::fs::create_dir_all(&args.output)
+            .await
+            .map_err(|e| format!("Could not create {}: {e}", args.output.display()))?;
+    }
+
+    let mut pixels = HashMap::new();
+    let mut edits = edits.iter().peekable();
+    let mut frame_time = from;
+    let mut frame_number = 0;
+    loop {
+        while let Some(edit) = edits.next_if(|edit| edit.time <= frame_time) {
+            pixels.insert(edit.coord, edit.new_color);
+        }
+        let frame = RgbImage::render(args.area, background, &pixels).scaled(args.scale);
+        if let Some(gif_writer) = &mut gif_writer {
+            gif_writer
+                .write_frame(&frame)
+                .map_err(|e| format!("Could not write {}: {e}", args.output.display()))?;
+        } else {
+            let (extension, bytes) = match args.format {
+                FrameFormat::Png => (
+                    "png",
+                    frame
+                        .encode_png()
+                        .map_err(|e| format!("Could not encode frame {frame_number}: {e}"))?,
+                ),
+                FrameFormat::Ppm => ("ppm", frame.encode_ppm()),
+            };
+            let path = args
+                .output
+                .join(format!("frame_{frame_number:06}.{extension}"));
+            tokio::fs::write(&path, bytes)
+                .await
+                .map_err(|e| format!("Could not write {}: {e}", path.display()))?;
+        }
+        frame_number += 1;
+
+        if frame_time >= to {
+            break;
+        }
+        // the last frame always shows the canvas at `to`
+        frame_time = (frame_time + interval).min(to);
+    }
+    eprintln!("Wrote {frame_number} frames");
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_timelapse() {
+    use crate::{
+        config::DEFAULT_CANVAS_NAME,
+        data::Coordinate,
+        history::{Edit, HistoryLog},
+        users::UserId,
+    };
+
+    let directory =
+        std::env::temp_dir().join(format!("p2ws-timelapse-test-{}", std::process::id()));
+    let mut config = Config::default();
+    let history = config.history.as_mut().unwrap();
+    history.directory = directory.join("history");
+    let mut log = HistoryLog::open(
+        history.canvas_directory(DEFAULT_CANVAS_NAME),
+        history.rotation,
+    )
+    .await
+    .unwrap();
+    let start = SystemTime::UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+    let black = Color { r: 0, g: 0, b: 0 };
+    for (secs, x) in [(0, 0), (30, 1), (90, 2)] {
+        log.append(&Edit {
+            time: start + Duration::from_secs(secs),
+            user: UserId::new("painter".to_owned()),
+            coord: Coordinate { x, y: 0 },
+            old_color: None,
+            new_color: black,
+        })
+        .await
+        .unwrap();
+    }
+    log.flush().await.unwrap();
+    let args = |output: &str| TimelapseArgs {
+        canvas: None,
+        area: parse_area("0,0,2,0").unwrap(),
+        from: None,
+        to: None,
+        interval: 60.0,
+        scale: 1,
+        background: None,
+        output: directory.join(output),
+        format: FrameFormat::Ppm,
+        frame_delay: 100,
+    };
+
+    // frames at 0, 60 and 90 seconds
+    run(&config, args("frames")).await.unwrap();
+    let mut frames = std::fs::read_dir(directory.join("frames"))
+        .unwrap()
+        .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+        .collect::<Vec<_>>();
+    frames.sort();
+    assert_eq!(
+        frames,
+        ["frame_000000.ppm", "frame_000001.ppm", "frame_000002.ppm"]
+    );
+    let second_frame = std::fs::read(directory.join("frames/frame_000001.ppm")).unwrap();
+    let white = [255; 3];
+    assert_eq!(
+        second_frame,
+        [&b"P6\n3 1\n255\n"[..], &[0; 6], &white].concat()
+    );
+
+    run(
+        &config,
+        TimelapseArgs {
+            scale: 2,
+            ..args("timelapse.gif")
+        },
+    )
+    .await
+    .unwrap();
+    assert!(
+        std::fs::read(directory.join("timelapse.gif"))
+            .unwrap()
+            .starts_with(b"GIF89a")
+    );
+
+    let error = run(
+        &config,
+        TimelapseArgs {
+            area: parse_area("-32768,0,32767,0").unwrap(),
+            ..args("large.gif")
+        },
+    )
+    .await
+    .unwrap_err();
+    assert!(error.starts_with("GIFs can be at most 65535 pixels wide and tall"));
+    assert!(!directory.join("large.gif").exists());
+    let error = run(
+        &config,
+        TimelapseArgs {
+            scale: u32::MAX,
+            ..args("large")
+        },
+    )
+    .await
+    .unwrap_err();
+    assert!(error.starts_with("The image would be too large"));
+    let error = run(
+        &config,
+        TimelapseArgs {
+            interval: 0.0005,
+            ..args("many")
+        },
+    )
+    .await
+    .unwrap_err();
+    assert!(error.starts_with("This would create 180001 frames"));
+    assert!(!directory.join("many").exists());
+
+    std::fs::remove_dir_all(&directory).unwrap();
+}
@@ -0,0 +1,342 @@
+use std::path::Path;
+
+use clap::{Args, Subcommand};
+use qrcode::{QrCode, render::unicode::Dense1x2};
+use toml_edit::{DocumentMut, Item, Table, value};
+
+use crate::{
+    cli::Args as GlobalArgs,
+    one_time_password::{OTP_KEY_VARIABLE, OneTimePasswordGenerator, OtpKey, Totp},
+    users::{Role, TomlUserStore, UserStore},
+};
+
+/// The issuer shown by authenticator apps
+const ISSUER: &str = "p2ws";
+
+#[derive(Args)]
+pub struct UserArgs {
+    #[command(subcommand)]
+    command: UserCommand,
+}
+
+#[derive(Subcommand)]
+enum UserCommand {
+    /// Add a user with a new random TOTP secret
+    Add {
+        username: String,
+        /// viewer, painter, moderator or admin
+        #[arg(long, value_parser = parse_role)]
+        role: Option<Role>,
+    },
+    /// Remove a user
+    Remove { username: String },
+    /// List all users
+    List,
+    /// Replace a user's OTP with a new random TOTP secret
+    RotateSecret { username: String },
+    /// Print a new random key for encrypting OTP secrets
+    GenerateKey,
+    /// Encrypt all OTP secrets which are stored in plaintext
+    Encrypt,
+}
+
+fn parse_role(s: &str) -> Result<Role, String> {
+    Role::from_name(s).ok_or_else(|| "expected viewer, painter, moderator or admin".to_owned())
+}
+
+/// Edits the users file, keeping its comments and formatting.
+/// A running server only sees the changes after it reloads the file.
+/// If an OTP key is configured, new secrets are encrypted with it.
+pub async fn run(global_args: &GlobalArgs, args: UserArgs) -> Result<(), String> {
+    if let UserCommand::GenerateKey = args.command {
+        let key = OtpKey::generate().map_err(|e| format!("Could not generate a key: {e}"))?;
+        println!("{}", key.to_base64());
+        return Ok(());
+    }
+    let users_file = global_args.users.as_path();
+    let key = OtpKey::load(global_args.otp_key_file.as_deref()).await?;
+    let content = match tokio::fs::read_to_string(users_file).await {
+        Ok(content) => content,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => String::new(),
+        Err(e) => return Err(format!("Could not read {}: {e}", users_file.display())),
+    };
+    let mut document = content
+        .parse::<DocumentMut>()
+        .map_err(|e| format!("Could not parse {}: {e}", users_file.display()))?;
+
+    match args.command {
+        UserCommand::List => {
+            let users = TomlUserStore::from_toml_with_key(&content, key.as_ref())
+                .map_err(|e| format!("Could not parse {}: {e}", users_file.display()))?;
+            for (user, role) in users.list().await? {
+                println!("{} {}", user.username(), role.name());
+            }
+            return Ok(());
+        }
+        UserCommand::Add { username, role } => {
+            if document.contains_key(&username) {
+                return Err(format!("The user {username:?} already exists"));
+            }
+            let totp = Totp::generate().map_err(|e| format!("Could not generate a secret: {e}"))?;
+            let mut table = Table::new();
+            table.insert("otp", otp_item(&totp, &username, key.as_ref())?);
+            if let Some(role) = role {
+                table.insert("role", value(role.name()));
+            }
+            document.insert(&username, Item::Table(table));
+            write_users_file(users_file, &document, key.as_ref()).await?;
+            print_secret(&totp, &username)?;
+        }
+        UserCommand::Remove { username } => {
+            if document.remove(&username).is_none() {
+                return Err(format!("There is no user {username:?}"));
+            }
+            write_users_file(users_file, &document, key.as_ref()).await?;
+        }
+        UserCommand::RotateSecret { username } => {
+            let Some(user) = document
+                .get_mut(&username)
+                .and_then(|user| user.as_table_like_mut())
+            else {
+                return Err(format!("There is no user {username:?}"));
+            };
+            let totp = Totp::generate().map_err(|e| format!("Could not generate a secret: {e}"))?;
+            user.insert("otp", otp_item(&totp, &username, key.as_ref())?);
+            write_users_file(users_file, &document, key.as_ref()).await?;
+            print_secret(&totp, &username)?;
+        }
+        UserCommand::Encrypt => {
+            let Some(key) = key else {
+                return Err(format!(
+                    "Set {OTP_KEY_VARIABLE} or use --otp-key-file to choose the key"
+                ));
+            };
+            let mut encrypted = 0;
+            for (username, user) in document.iter_mut() {
+                let Some(user) = user.as_table_like_mut() else {
+                    continue;
+                };
+                let Some(otp) = user.get("otp").and_then(plaintext_otp) else {
+                    continue;
+                };
+                user.insert(
+                    "otp",
+                    encrypted_otp_item(&key, &username, &otp.to_plaintext())?,
+                );
+                encrypted += 1;
+            }
+            write_users_file(users_file, &document, Some(&key)).await?;
+            println!("Encrypted the OTP settings of {encrypted} users.");
+        }
+        UserCommand::GenerateKey => unreachable!("handled before reading the users file"),
+    }
+    println!(
+        "Send SIGHUP to the server or use the reload-users admin command to apply the change."
+    );
+    Ok(())
+}
+
+/// `otp.Totp = "<secret>"`, or `otp.Encrypted = "<encrypted secret>"` if there is a key
+fn otp_item(totp: &Totp, username: &str, key: Option<&OtpKey>) -> Result<Item, String> {
+    if let Some(key) = key {
+        return encrypted_otp_item(key, username, &format!("Totp:{}", totp.secret_base32()));
+    }
+    let mut table = Table::new();
+    table.set_dotted(true);
+    table.insert("Totp", value(totp.secret_base32()));
+    Ok(Item::Table(table))
+}
+
+fn encrypted_otp_item(key: &OtpKey, username: &str, plaintext: &str) -> Result<Item, String> {
+    let encrypted = key
+        .encrypt(username, plaintext)
+        .map_err(|e| format!("Could not encrypt the OTP settings: {e}"))?;
+    let mut table = Table::new();
+    table.set_dotted(true);
+    table.insert("Encrypted", value(encrypted));
+    Ok(Item::Table(table))
+}
+
+/// The OTP settings of an `otp.Static` or `otp.Totp` entry
+fn plaintext_otp(otp: &Item) -> Option<OneTimePasswordGenerator> {
+    let otp = otp.as_table_like()?;
+    if let Some(pin) = otp.get("Static").and_then(Item::as_integer) {
+        return Some(OneTimePasswordGenerator::Static(pin.try_into().ok()?));
+    }
+    let secret = otp.get("Totp").and_then(Item::as_str)?;
+    Some(OneTimePasswordGenerator::Totp(Totp::from_base32(secret)?))
+}
+
+fn print_secret(totp: &Totp, username: &str) -> Result<(), String> {
+    let uri = totp.otpauth_uri(ISSUER, username);
+    let qr_code = QrCode::new(&uri).map_err(|e| format!("Could not create a QR code: {e}"))?;
+    // light modules are printed as spaces, so terminals with a dark background show the code correctly
+    let qr_code = qr_code
+        .render::<Dense1x2>()
+        .dark_color(Dense1x2::Light)
+        .light_color(Dense1x2::Dark)
+        .build();
+    println!("{qr_code}");
+    println!("Secret: {}", totp.secret_base32());
+    println!("{uri}");
+    Ok(())
+}
+
+/// Refuses to write a file which the server couldn't load,
+/// and replaces the file atomically so that the server never reads a partially written file.
+async fn write_users_file(
+    users_file: &Path,
+    document: &DocumentMut,
+    key: Option<&OtpKey>,
+) -> Result<(), String> {
+    let content = document.to_string();
+    TomlUserStore::from_toml_with_key(&content, key)
+        .map_err(|e| format!("Refusing to write an invalid users file: {e}"))?;
+    let temporary_file = users_file.with_extension("tmp");
+    // a leftover from an interrupted run; this removes a symlink itself, not its target
+    if let Err(e) = tokio::fs::remove_file(&temporary_file).await
+        && e.kind() != std::io::ErrorKind::NotFound
+    {
+        return Err(format!(
+            "Could not remove {}: {e}",
+            temporary_file.display()
+        ));
+    }
+    let mut options = tokio::fs::OpenOptions::new();
+    // `create_new` doesn't follow symlinks
+    options.write(true).create_new(true);
+    // the file contains secrets, so keep the original permissions, or make it private if it's new,
+    // before anything is written to it
+    #[cfg(unix)]
+    options.mode(match tokio::fs::metadata(users_file).await {
+        Ok(metadata) => std::os::unix::fs::PermissionsExt::mode(&metadata.permissions()) & 0o777,
+        Err(_) => 0o600,
+    });
+    let write = async {
+        let mut file = options.open(&temporary_file).await?;
+        tokio::io::AsyncWriteExt::write_all(&mut file, content.as_bytes()).await?;
+        file.sync_all().await
+    };
+    if let Err(e) = write.await {
+        tokio::fs::remove_file(&temporary_file).await.ok();
+        return Err(format!("Could not write {}: {e}", temporary_file.display()));
+    }
+    tokio::fs::rename(&temporary_file, users_file)
+        .await
+        .map_err(|e| format!("Could not replace {}: {e}", users_file.display()))
+}
+
+#[cfg(test)]
+fn test_args(name: &str, key_file: Option<&Path>) -> GlobalArgs {
+    let directory = std::env::temp_dir().join(format!("p2ws-user-test-{}", std::process::id()));
+    std::fs::create_dir_all(&directory).unwrap();
+    GlobalArgs {
+        config: directory.join("config.toml"),
+        users: directory.join(name),
+        bans: directory.join("bans.toml"),
+        otp_key_file: key_file.map(Path::to_owned),
+        command: None,
+    }
+}
+
+#[tokio::test]
+async fn test_add_and_remove() {
+    let args = test_args("add-remove.toml", None);
+    let original = "# the users\n[bob] # a painter\notp.Static = 123456\nrole = \"painter\"\n";
+    std::fs::write(&args.users, original).unwrap();
+    let command = |command| UserArgs { command };
+
+    run(
+        &args,
+        command(UserCommand::Add {
+            username: "alice".to_owned(),
+            role: Some(Role::Moderator),
+        }),
+    )
+    .await
+    .unwrap();
+    let content = std::fs::read_to_string(&args.users).unwrap();
+    assert!(content.starts_with(original));
+    let users = TomlUserStore::from_toml(&content)
+        .unwrap()
+        .list()
+        .await
+        .unwrap();
+    assert_eq!(users.len(), 2);
+    assert!(
+        users
+            .iter()
+            .any(|(user, role)| user.username() == "alice" && *role == Role::Moderator)
+    );
+
+    let existing = UserCommand::Add {
+        username: "alice".to_owned(),
+        role: None,
+    };
+    assert!(run(&args, command(existing)).await.is_err());
+    let removed = UserCommand::Remove {
+        username: "alice".to_owned(),
+    };
+    run(&args, command(removed)).await.unwrap();
+    assert_eq!(std::fs::read_to_string(&args.users).unwrap(), original);
+    let missing = UserCommand::Remove {
+        username: "alice".to_owned(),
+    };
+    assert!(run(&args, command(missing)).await.is_err());
+    std::fs::remove_file(&args.users).unwrap();
+}
+
+#[tokio::test]
+async fn test_encrypt() {
+    let key = OtpKey::generate().unwrap();
+    let key_file = test_args("key", None).users;
+    std::fs::write(&key_file, key.to_base64()).unwrap();
+    let args = test_args("encrypt.toml", Some(&key_file));
+    let secret = Totp::generate().unwrap().secret_base32();
+    let original = format!("# the users\n[bob]\notp.Totp = \"{secret}\"\n");
+    std::fs::write(&args.users, &original).unwrap();
+
+    let without_key = test_args("encrypt.toml", None);
+    let encrypt = || UserArgs {
+        command: UserCommand::Encrypt,
+    };
+    assert!(run(&without_key, encrypt()).await.is_err());
+    run(&args, encrypt()).await.unwrap();
+    let add = UserArgs {
+        command: UserCommand::Add {
+            username: "alice".to_owned(),
+            role: None,
+        },
+    };
+    run(&args, add).await.unwrap();
+
+    let content = std::fs::read_to_string(&args.users).unwrap();
+    assert!(content.starts_with("# the users\n"));
+    assert!(!content.contains(&secret));
+    assert!(!content.contains("Totp"));
+    assert_eq!(content.matches("otp.Encrypted").count(), 2);
+    assert!(TomlUserStore::from_toml(&content).is_err());
+    let users = TomlUserStore::from_toml_with_key(&content, Some(&key)).unwrap();
+    assert_eq!(users.list().await.unwrap().len(), 2);
+    std::fs::remove_file(&args.users).unwrap();
+    std::fs::remove_file(&key_file).unwrap();
+}
+
+#[tokio::test]
+async fn test_refuse_invalid_file() {
+    let args = test_args("invalid.toml", None);
+    // bob has no OTP settings, so the server can't load the file
+    let original = "[bob]\nrole = \"painter\"\n";
+    std::fs::write(&args.users, original).unwrap();
+    let add = UserArgs {
+        command: UserCommand::Add {
+            username: "alice".to_owned(),
+            role: None,
+        },
+    };
+    let error = run(&args, add).await.unwrap_err();
+    assert!(error.starts_with("Refusing to write"), "{error}");
+    assert_eq!(std::fs::read_to_string(&args.users).unwrap(), original);
+    assert!(!args.users.with_extension("tmp").exists());
+    std::fs::remove_file(&args.users).unwrap();
+}
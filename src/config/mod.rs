@@ -0,0 +1,524 @@
+use std::{
+    path::PathBuf,
+    time::{Duration, SystemTime},
+};
+
+use serde::Deserialize;
+
+use crate::{
+    canvas::{CanvasBounds, CanvasSettings, Palette, ProtectedArea},
+    data::{Area, Color, Coordinate},
+    history::{HistoryRotation, HistorySettings},
+    logging::{LogFormat, LoggingSettings},
+    ratelimit::{PixelCooldownSettings, RatelimitSettings},
+    server::{SessionSettings, SpectatorSettings},
+    users::{Role, UserId, UserStoreSettings},
+};
+
+/// The name of the default canvas, unless the config specifies a different name.
+pub const DEFAULT_CANVAS_NAME: &str = "default";
+
+/// Server settings, loaded from `config.toml`.
+pub struct Config {
+    /// All canvases hosted by the server. Contains at least one canvas.
+    /// The first canvas is the default canvas, which clients use unless they select a different one.
+    pub canvases: Vec<(String, CanvasSettings)>,
+    /// If set, every accepted Put is recorded in the canvas' history.
+    pub history: Option<HistorySettings>,
+    /// If set, clients can connect as read-only spectators.
+    pub spectators: Option<SpectatorSettings>,
+    /// If set, administrators can control the server through this Unix socket.
+    pub admin_socket: Option<PathBuf>,
+    /// If set, users are read from this store instead of the users file.
+    pub user_store: Option<UserStoreSettings>,
+    /// If set, clients can get session tokens and use them instead of an OTP.
+    pub sessions: Option<SessionSettings>,
+    /// If set, connections are rejected while this many connections (including HTTP requests) are open.
+    pub max_connections: Option<usize>,
+    pub logging: LoggingSettings,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            canvases: vec![(DEFAULT_CANVAS_NAME.to_owned(), CanvasSettings::default())],
+            history: Some(HistorySettings {
+                directory: PathBuf::from("history"),
+                rotation: HistoryRotation {
+                    max_file_size: 64 * 1024 * 1024,
+                    max_file_age: Duration::from_secs(24 * 60 * 60),
+                },
+            }),
+            spectators: None,
+            admin_socket: None,
+            user_store: None,
+            sessions: None,
+            max_connections: None,
+            logging: LoggingSettings::default(),
+        }
+    }
+}
+
+impl Default for CanvasSettings {
+    fn default() -> Self {
+        Self {
+            ratelimit: RatelimitSettings::new(Duration::from_secs_f64(1.0 / 10000.0))
+                .allow_bursts(1000)
+                .drop_instead_of_blocking(),
+            pixel_cooldown: None,
+            bounds: CanvasBounds::unbounded(),
+            palette: None,
+            background: Color {
+                r: 31,
+                g: 31,
+                b: 31,
+            },
+            protected: Vec::new(),
+        }
+    }
+}
+
+impl Config {
+    pub fn from_toml(file_content: &str) -> Result<Self, toml::de::Error> {
+        #[derive(Deserialize)]
+        #[serde(deny_unknown_fields)]
+        struct DeConfig {
+            ratelimit: Option<DeRatelimit>,
+            cooldown: Option<DeCooldown>,
+            canvas: Option<DeCanvas>,
+            #[serde(default)]
+            canvases: toml::Table,
+            history: Option<DeHistory>,
+            spectators: Option<DeSpectators>,
+            admin: Option<DeAdmin>,
+            users: Option<DeUsers>,
+            sessions: Option<DeSessions>,
+            connections: Option<DeConnections>,
+            logging: Option<DeLogging>,
+        }
+        #[derive(Deserialize)]
+        #[serde(deny_unknown_fields)]
+        struct DeLogging {
+            level: Option<String>,
+            format: Option<String>,
+        }
+        #[derive(Deserialize)]
+        #[serde(deny_unknown_fields)]
+        struct DeConnections {
+            max_connections: Option<usize>,
+        }
+        #[derive(Deserialize)]
+        #[serde(deny_unknown_fields)]
+        struct DeSessions {
+            #[serde(default = "default_session_hours")]
+            lifetime_hours: f64,
+        }
+        fn default_session_hours() -> f64 {
+            24.0
+        }
+        #[derive(Deserialize)]
+        #[serde(deny_unknown_fields)]
+        struct DeUsers {
+            sqlite: Option<PathBuf>,
+            http: Option<String>,
+        }
+        #[derive(Deserialize)]
+        #[serde(deny_unknown_fields)]
+        struct DeAdmin {
+            socket: Option<PathBuf>,
+        }
+        #[derive(Deserialize)]
+        #[serde(deny_unknown_fields)]
+        struct DeSpectators {
+            #[serde(default = "default_true")]
+            anonymous: bool,
+            #[serde(default)]
+            guest_users: Vec<String>,
+            #[serde(default = "default_max_spectators")]
+            max_connections: usize,
+            ratelimit: Option<DeRatelimit>,
+        }
+        fn default_max_spectators() -> usize {
+            1000
+        }
+        #[derive(Deserialize)]
+        #[serde(deny_unknown_fields)]
+        struct DeHistory {
+            #[serde(default = "default_true")]
+            enabled: bool,
+            directory: Option<PathBuf>,
+            max_file_size_mb: Option<f64>,
+            max_file_age_hours: Option<f64>,
+        }
+        fn default_true() -> bool {
+            true
+        }
+        #[derive(Deserialize)]
+        #[serde(deny_unknown_fields)]
+        struct DeRatelimit {
+            messages_per_second: f64,
+            #[serde(default)]
+            burst: u32,
+            #[serde(default)]
+            drop: bool,
+        }
+        #[derive(Deserialize)]
+        #[serde(deny_unknown_fields)]
+        struct DeCooldown {
+            seconds_per_pixel: f64,
+            #[serde(default)]
+            stock: u32,
+        }
+        #[derive(Deserialize, Default)]
+        #[serde(deny_unknown_fields)]
+        struct DeCanvas {
+            /// only allowed in `[canvas]`, as other canvases are named by their table
+            name: Option<String>,
+            ratelimit: Option<DeRatelimit>,
+            cooldown: Option<DeCooldown>,
+            bounds: Option<DeArea>,
+            #[serde(default)]
+            expansions: Vec<DeExpansion>,
+            palette: Option<Vec<[u8; 3]>>,
+            #[serde(default)]
+            palette_mode: DePaletteMode,
+            background: Option<[u8; 3]>,
+            #[serde(default)]
+            protected: Vec<DeProtectedArea>,
+        }
+        #[derive(Deserialize)]
+        #[serde(deny_unknown_fields)]
+        struct DeProtectedArea {
+            area: DeArea,
+            /// only admins can paint the area by default
+            role: Option<String>,
+            #[serde(default)]
+            users: Vec<String>,
+        }
+        #[derive(Deserialize, Default)]
+        #[serde(rename_all = "snake_case")]
+        enum DePaletteMode {
+            #[default]
+            Reject,
+            Snap,
+        }
+        #[derive(Deserialize)]
+        #[serde(deny_unknown_fields)]
+        struct DeExpansion {
+            /// unix timestamp in seconds
+            at: u64,
+            bounds: DeArea,
+        }
+        /// `[left, top, right, bottom]`
+        #[derive(Deserialize)]
+        struct DeArea([i16; 4]);
+        impl DeArea {
+            fn area(&self) -> Result<Area, toml::de::Error> {
+                let [left, top, right, bottom] = self.0;
+                Area::try_new(
+                    Coordinate { x: left, y: top },
+                    Coordinate {
+                        x: right,
+                        y: bottom,
+                    },
+                )
+                .and_then(|area| area.intersection(Area::EVERYTHING))
+                .ok_or_else(|| {
+                    serde::de::Error::custom(format!(
+                        "invalid area {:?}, expected [left, top, right, bottom]",
+                        self.0
+                    ))
+                })
+            }
+        }
+        impl DeRatelimit {
+            fn settings(&self) -> Result<RatelimitSettings, toml::de::Error> {
+                let settings =
+                    RatelimitSettings::new(positive_seconds(1.0 / self.messages_per_second)?)
+                        .allow_bursts(self.burst);
+                Ok(if self.drop {
+                    settings.drop_instead_of_blocking()
+                } else {
+                    settings.block_instead_of_dropping()
+                })
+            }
+        }
+        impl DeCooldown {
+            fn settings(&self) -> Result<PixelCooldownSettings, toml::de::Error> {
+                Ok(
+                    PixelCooldownSettings::new(positive_seconds(self.seconds_per_pixel)?)
+                        .allow_stock(self.stock),
+                )
+            }
+        }
+        impl DeCanvas {
+            /// `defaults` contains the top-level ratelimit and cooldown settings,
+            /// which are used if the canvas doesn't set its own.
+            fn settings(
+                self,
+                defaults: &CanvasSettings,
+            ) -> Result<CanvasSettings, toml::de::Error> {
+                let mut bounds = CanvasBounds::fixed(match &self.bounds {
+                    Some(area) => area.area()?,
+                    None => Area::EVERYTHING,
+                });
+                for expansion in self.expansions {
+                    bounds = bounds.expand_at(
+                        SystemTime::UNIX_EPOCH + Duration::from_secs(expansion.at),
+                        expansion.bounds.area()?,
+                    );
+                }
+                let palette = match self.palette {
+                    Some(colors) => {
+                        if colors.is_empty() || colors.iter().flatten().any(|c| *c > 31) {
+                            return Err(serde::de::Error::custom(
+                                "a palette must contain at least one color, and colors are [r, g, b] with values from 0 to 31",
+                            ));
+                        }
+                        let palette = Palette::new(
+                            colors
+                                .into_iter()
+                                .map(|[r, g, b]| Color { r, g, b })
+                                .collect(),
+                        );
+                        Some(match self.palette_mode {
+                            DePaletteMode::Reject => palette.reject_others(),
+                            DePaletteMode::Snap => palette.snap_to_nearest(),
+                        })
+                    }
+                    None => None,
+                };
+                let background = match self.background {
+                    Some([r, g, b]) if r < 32 && g < 32 && b < 32 => Color { r, g, b },
+                    Some(_) => {
+                        return Err(serde::de::Error::custom(
+                            "background must be [r, g, b] with values from 0 to 31",
+                        ));
+                    }
+                    None => defaults.background,
+                };
+                let mut protected = Vec::with_capacity(self.protected.len());
+                for de_protected in self.protected {
+                    protected.push(ProtectedArea {
+                        area: de_protected.area.area()?,
+                        min_role: match &de_protected.role {
+                            Some(name) => Role::from_name(name).ok_or_else(|| {
+                                serde::de::Error::custom(format!(
+                                    "unknown role {name:?}, expected viewer, painter, moderator or admin"
+                                ))
+                            })?,
+                            None => Role::Admin,
+                        },
+                        users: de_protected.users.into_iter().map(UserId::new).collect(),
+                    });
+                }
+                Ok(CanvasSettings {
+                    ratelimit: match &self.ratelimit {
+                        Some(ratelimit) => ratelimit.settings()?,
+                        None => defaults.ratelimit,
+                    },
+                    pixel_cooldown: match &self.cooldown {
+                        Some(cooldown) => Some(cooldown.settings()?),
+                        None => defaults.pixel_cooldown,
+                    },
+                    bounds,
+                    palette,
+                    background,
+                    protected,
+                })
+            }
+        }
+
+        let de = toml::from_str::<DeConfig>(file_content)?;
+
+        let mut defaults = CanvasSettings::default();
+        if let Some(ratelimit) = &de.ratelimit {
+            defaults.ratelimit = ratelimit.settings()?;
+        }
+        if let Some(cooldown) = &de.cooldown {
+            defaults.pixel_cooldown = Some(cooldown.settings()?);
+        }
+
+        let mut default_canvas = de.canvas.unwrap_or_default();
+        let default_canvas_name = default_canvas
+            .name
+            .take()
+            .unwrap_or_else(|| DEFAULT_CANVAS_NAME.to_owned());
+        let mut canvases = vec![(default_canvas_name, default_canvas.settings(&defaults)?)];
+        for (name, canvas) in de.canvases {
+            let canvas = canvas.try_into::<DeCanvas>()?;
+            if canvas.name.is_some() {
+                return Err(serde::de::Error::custom(format!(
+                    "canvas {name}: the name of a canvas in [canvases] is its key, it can't have a `name` field"
+                )));
+            }
+            canvases.push((name, canvas.settings(&defaults)?));
+        }
+        for (i, (name, _)) in canvases.iter().enumerate() {
+            if name.is_empty() || name.len() > 255 {
+                return Err(serde::de::Error::custom(format!(
+                    "canvas name {name:?} must be 1 to 255 bytes long"
+                )));
+            }
+            if canvases[..i].iter().any(|(other, _)| other == name) {
+                return Err(serde::de::Error::custom(format!(
+                    "there are multiple canvases named {name:?}"
+                )));
+            }
+        }
+
+        let mut history = Self::default().history;
+        if let Some(de_history) = de.history {
+            history = if de_history.enabled {
+                history
+                    .map(|mut history| -> Result<_, toml::de::Error> {
+                        if let Some(directory) = de_history.directory {
+                            history.directory = directory;
+                        }
+                        if let Some(max_file_size_mb) = de_history.max_file_size_mb {
+                            if !(max_file_size_mb.is_finite() && max_file_size_mb > 0.0) {
+                                return Err(serde::de::Error::custom(
+                                    "history.max_file_size_mb must be a positive number",
+                                ));
+                            }
+                            history.rotation.max_file_size =
+                                (max_file_size_mb * 1024.0 * 1024.0) as u64;
+                        }
+                        if let Some(max_file_age_hours) = de_history.max_file_age_hours {
+                            history.rotation.max_file_age =
+                                positive_seconds(max_file_age_hours * 3600.0)?;
+                        }
+                        Ok(history)
+                    })
+                    .transpose()?
+            } else {
+                None
+            };
+        }
+
+        let spectators = match de.spectators {
+            Some(de_spectators) => Some(SpectatorSettings {
+                allow_anonymous: de_spectators.anonymous,
+                guest_users: de_spectators
+                    .guest_users
+                    .into_iter()
+                    .map(UserId::new)
+                    .collect(),
+                max_connections: de_spectators.max_connections,
+                ratelimit: match &de_spectators.ratelimit {
+                    Some(ratelimit) => ratelimit.settings()?,
+                    None => RatelimitSettings::new(Duration::from_millis(100)).allow_bursts(10),
+                },
+            }),
+            None => None,
+        };
+
+        let user_store = match de.users {
+            Some(DeUsers {
+                sqlite: Some(_),
+                http: Some(_),
+            }) => {
+                return Err(serde::de::Error::custom(
+                    "users.sqlite and users.http can't both be set",
+                ));
+            }
+            Some(DeUsers {
+                sqlite: Some(path), ..
+            }) => Some(UserStoreSettings::Sqlite(path)),
+            Some(DeUsers {
+                http: Some(url), ..
+            }) => Some(UserStoreSettings::Http(url)),
+            _ => None,
+        };
+
+        let mut logging = LoggingSettings::default();
+        if let Some(de_logging) = de.logging {
+            if let Some(level) = de_logging.level {
+                tracing_subscriber::EnvFilter::try_new(&level).map_err(|e| {
+                    serde::de::Error::custom(format!("invalid logging.level {level:?}: {e}"))
+                })?;
+                logging.level = level;
+            }
+            if let Some(format) = de_logging.format {
+                logging.format = LogFormat::from_name(&format).ok_or_else(|| {
+                    serde::de::Error::custom(format!(
+                        "unknown logging.format {format:?}, expected text or json"
+                    ))
+                })?;
+            }
+        }
+
+        Ok(Self {
+            canvases,
+            history,
+            spectators,
+            admin_socket: de.admin.and_then(|admin| admin.socket),
+            user_store,
+            sessions: match de.sessions {
+                Some(de_sessions) => Some(SessionSettings {
+                    lifetime: positive_seconds(de_sessions.lifetime_hours * 60.0 * 60.0)?,
+                }),
+                None => None,
+            },
+            max_connections: de
+                .connections
+                .and_then(|connections| connections.max_connections),
+            logging,
+        })
+    }
+}
+
+fn positive_seconds(seconds: f64) -> Result<Duration, toml::de::Error> {
+    if !(seconds.is_finite() && seconds > 0.0) {
+        return Err(serde::de::Error::custom(
+            "durations and rates must be positive numbers",
+        ));
+    }
+    Duration::try_from_secs_f64(seconds).map_err(|_| {
+        serde::de::Error::custom(format!("{seconds} seconds is too long for a duration"))
+    })
+}
+
+#[test]
+fn test_parse_canvases() {
+    let config = Config::from_toml(
+        r#"
+        [cooldown]
+        seconds_per_pixel = 60.0
+
+        [canvas]
+        name = "main"
+        bounds = [0, 0, 99, 99]
+
+        [canvases.event]
+        palette = [[0, 0, 0], [31, 31, 31]]
+        ratelimit = { messages_per_second = 10.0 }
+        protected = [{ area = [0, 0, 9, 9], role = "moderator", users = ["artist"] }]
+        "#,
+    )
+    .unwrap();
+    let names = config
+        .canvases
+        .iter()
+        .map(|(name, _)| name.as_str())
+        .collect::<Vec<_>>();
+    assert_eq!(names, ["main", "event"]);
+    let (_, main) = &config.canvases[0];
+    let (_, event) = &config.canvases[1];
+    assert!(main.pixel_cooldown.is_some() && event.pixel_cooldown.is_some());
+    assert!(main.palette.is_none() && event.palette.is_some());
+    assert!(
+        !main
+            .bounds
+            .contains(Coordinate { x: 100, y: 0 }, SystemTime::now())
+    );
+    assert!(
+        event
+            .bounds
+            .contains(Coordinate { x: 100, y: 0 }, SystemTime::now())
+    );
+    assert_eq!(event.protected[0].min_role, Role::Moderator);
+    assert!(Config::from_toml("[canvas]\nname = \"a\"\n[canvases.a]\n").is_err());
+    assert!(Config::from_toml("[ratelimit]\nmessages_per_second = 1e-20\n").is_err());
+    assert!(Config::from_toml("[sessions]\nlifetime_hours = 1e300\n").is_err());
+}
@@ -5,7 +5,32 @@ pub struct Color {
     pub b: u8,
 }
 
-#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+impl Color {
+    /// The color as a 15-bit number `0b0rrrrrgggggbbbbb`
+    pub fn to_bits(self) -> u16 {
+        ((self.r as u16 & 0b11111) << 10)
+            | ((self.g as u16 & 0b11111) << 5)
+            | (self.b as u16 & 0b11111)
+    }
+
+    /// The color with 8 bits per channel. The 5 bits of each channel are repeated
+    /// in the lower bits, so that `0` becomes `0` and `31` becomes `255`.
+    pub fn to_rgb8(self) -> [u8; 3] {
+        let expand = |c: u8| ((c & 0b11111) << 3) | ((c & 0b11111) >> 2);
+        [expand(self.r), expand(self.g), expand(self.b)]
+    }
+
+    /// The inverse of `to_bits`, ignores the most significant bit.
+    pub fn from_bits(bits: u16) -> Self {
+        Self {
+            r: ((bits >> 10) & 0b11111) as u8,
+            g: ((bits >> 5) & 0b11111) as u8,
+            b: (bits & 0b11111) as u8,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
 pub struct Coordinate {
     pub x: i16,
     pub y: i16,
@@ -18,6 +43,15 @@ pub struct Area {
 }
 
 impl Area {
+    /// Contains all coordinates which can be encoded in the p² protocol
+    pub const EVERYTHING: Self = Self {
+        top_left: Coordinate {
+            x: -32512,
+            y: -32512,
+        },
+        bottom_right: Coordinate { x: 32512, y: 32512 },
+    };
+
     pub fn try_new(top_left: Coordinate, bottom_right: Coordinate) -> Option<Self> {
         if top_left.x > bottom_right.x || top_left.y > bottom_right.y {
             None
@@ -49,6 +83,20 @@ impl Area {
             && coord.y <= self.bottom()
     }
 
+    /// The area contained in both `self` and `other`, if they intersect.
+    pub fn intersection(&self, other: Area) -> Option<Area> {
+        Area::try_new(
+            Coordinate {
+                x: self.left().max(other.left()),
+                y: self.top().max(other.top()),
+            },
+            Coordinate {
+                x: self.right().min(other.right()),
+                y: self.bottom().min(other.bottom()),
+            },
+        )
+    }
+
     pub fn intersects(&self, other: Area) -> bool {
         !(other.right() < self.left()
             || self.right() < other.left()
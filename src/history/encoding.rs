@@ -0,0 +1,113 @@
+//! The history is stored in files which start with `MAGIC`, followed by any number of edits.
+//! Each edit is encoded as (all numbers are little-endian):
+//!
+//! - the time as milliseconds since the unix epoch (`u64`)
+//! - the byte-length of the username minus one (`u8`), followed by the username in UTF-8
+//! - the coordinate's `x` and `y` (`i16` each)
+//! - the old color (`u16`, see `Color::to_bits`, or `NO_COLOR` if the pixel was never painted)
+//! - the new color (`u16`)
+
+use std::time::{Duration, SystemTime};
+
+use crate::{
+    data::{Color, Coordinate},
+    history::Edit,
+    users::UserId,
+};
+
+pub const MAGIC: &[u8; 4] = b"P2H1";
+
+const NO_COLOR: u16 = 0xFFFF;
+
+/// Fails if the username isn't 1 to 256 bytes long, and then doesn't change `out`.
+pub fn encode_edit(edit: &Edit, out: &mut Vec<u8>) -> std::io::Result<()> {
+    let username = edit.user.username().as_bytes();
+    let Some(username_len) = username.len().checked_sub(1).filter(|len| *len <= 255) else {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            "usernames in the history must be 1 to 256 bytes long",
+        ));
+    };
+    let millis = edit
+        .time
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or(Duration::ZERO)
+        .as_millis() as u64;
+    out.extend(millis.to_le_bytes());
+    out.push(username_len as u8);
+    out.extend(username);
+    out.extend(edit.coord.x.to_le_bytes());
+    out.extend(edit.coord.y.to_le_bytes());
+    out.extend(
+        edit.old_color
+            .map_or(NO_COLOR, Color::to_bits)
+            .to_le_bytes(),
+    );
+    out.extend(edit.new_color.to_bits().to_le_bytes());
+    Ok(())
+}
+
+/// Decodes the edit at the start of `bytes` and returns it and the number of bytes it used,
+/// or returns `None` if `bytes` ends before the edit does or if the edit is invalid.
+pub fn decode_edit(bytes: &[u8]) -> Option<(Edit, usize)> {
+    let millis = u64::from_le_bytes(bytes.get(0..8)?.try_into().unwrap());
+    let username_len = *bytes.get(8)? as usize + 1;
+    let username = std::str::from_utf8(bytes.get(9..9 + username_len)?).ok()?;
+    let rest = bytes.get(9 + username_len..9 + username_len + 8)?;
+    let x = i16::from_le_bytes([rest[0], rest[1]]);
+    let y = i16::from_le_bytes([rest[2], rest[3]]);
+    let old_color = u16::from_le_bytes([rest[4], rest[5]]);
+    let new_color = u16::from_le_bytes([rest[6], rest[7]]);
+    Some((
+        Edit {
+            time: SystemTime::UNIX_EPOCH + Duration::from_millis(millis),
+            user: UserId::new(username.to_owned()),
+            coord: Coordinate { x, y },
+            old_color: (old_color != NO_COLOR).then(|| Color::from_bits(old_color)),
+            new_color: Color::from_bits(new_color),
+        },
+        9 + username_len + 8,
+    ))
+}
+
+#[test]
+fn test_edit_encoding_and_decoding() {
+    let edits = [
+        Edit {
+            time: SystemTime::UNIX_EPOCH + Duration::from_millis(1_700_000_000_123),
+            user: UserId::new("py1".to_owned()),
+            coord: Coordinate { x: -32512, y: 7 },
+            old_color: None,
+            new_color: Color { r: 31, g: 0, b: 17 },
+        },
+        Edit {
+            time: SystemTime::UNIX_EPOCH,
+            user: UserId::new("ü".repeat(128)),
+            coord: Coordinate { x: 32512, y: -1 },
+            old_color: Some(Color {
+                r: 31,
+                g: 31,
+                b: 31,
+            }),
+            new_color: Color { r: 0, g: 0, b: 0 },
+        },
+    ];
+    let mut bytes = Vec::new();
+    for edit in &edits {
+        encode_edit(edit, &mut bytes).unwrap();
+    }
+    let (first, first_len) = decode_edit(&bytes).unwrap();
+    let (second, second_len) = decode_edit(&bytes[first_len..]).unwrap();
+    assert_eq!([first, second], edits);
+    assert_eq!(first_len + second_len, bytes.len());
+    assert!(decode_edit(&bytes[first_len..bytes.len() - 1]).is_none());
+
+    for username in [String::new(), "a".repeat(257)] {
+        let edit = Edit {
+            user: UserId::new(username),
+            ..edits[0].clone()
+        };
+        assert!(encode_edit(&edit, &mut bytes).is_err());
+    }
+    assert_eq!(first_len + second_len, bytes.len());
+}
@@ -0,0 +1,301 @@
+mod encoding;
+
+use std::{
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime},
+};
+
+use tokio::{
+    fs::File,
+    io::{AsyncWriteExt, BufWriter},
+};
+
+use crate::{
+    data::{Area, Color, Coordinate},
+    users::UserId,
+};
+
+/// Where history files are stored and how they are rotated.
+#[derive(Clone, Debug)]
+pub struct HistorySettings {
+    /// Each canvas' history is stored in a subdirectory of this directory.
+    pub directory: PathBuf,
+    pub rotation: HistoryRotation,
+}
+
+impl HistorySettings {
+    /// The directory containing the canvas' history files.
+    /// Characters which may not be allowed in file names are percent-encoded.
+    pub fn canvas_directory(&self, canvas_name: &str) -> PathBuf {
+        let mut escaped = String::new();
+        for byte in canvas_name.bytes() {
+            if byte.is_ascii_alphanumeric() || byte == b'-' || byte == b'_' {
+                escaped.push(byte as char);
+            } else {
+                escaped.push_str(&format!("%{byte:02X}"));
+            }
+        }
+        self.directory.join(escaped)
+    }
+}
+
+/// One accepted change of a pixel.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Edit {
+    pub time: SystemTime,
+    pub user: UserId,
+    pub coord: Coordinate,
+    /// `None` if the pixel had never been painted before
+    pub old_color: Option<Color>,
+    pub new_color: Color,
+}
+
+/// When to stop appending to a history file and start a new one.
+#[derive(Clone, Copy, Debug)]
+pub struct HistoryRotation {
+    pub max_file_size: u64,
+    pub max_file_age: Duration,
+}
+
+/// Appends edits to the history files in a directory.
+///
+/// Each file is named after the time of its first edit, so the files
+/// are sorted by time and each file contains the edits made
+/// between its own time and the next file's time.
+pub struct HistoryLog {
+    directory: PathBuf,
+    rotation: HistoryRotation,
+    current_file: Option<CurrentFile>,
+    buf: Vec<u8>,
+}
+
+struct CurrentFile {
+    writer: BufWriter<File>,
+    started: SystemTime,
+    len: u64,
+}
+
+impl HistoryLog {
+    /// Creates the directory if it doesn't exist.
+    /// Existing files are never modified, the first edit will be appended to a new file.
+    pub async fn open(directory: PathBuf, rotation: HistoryRotation) -> tokio::io::Result<Self> {
+        tokio::fs::create_dir_all(&directory).await?;
+        Ok(Self {
+            directory,
+            rotation,
+            current_file: None,
+            buf: Vec::new(),
+        })
+    }
+
+    pub fn directory(&self) -> &Path {
+        &self.directory
+    }
+
+    pub async fn append(&mut self, edit: &Edit) -> tokio::io::Result<()> {
+        self.buf.clear();
+        encoding::encode_edit(edit, &mut self.buf)?;
+        if let Some(current_file) = &self.current_file
+            && (current_file.len >= self.rotation.max_file_size
+                || edit
+                    .time
+                    .duration_since(current_file.started)
+                    .is_ok_and(|age| age >= self.rotation.max_file_age))
+        {
+            let mut current_file = self.current_file.take().unwrap();
+            current_file.writer.flush().await?;
+        }
+        let current_file = match &mut self.current_file {
+            Some(current_file) => current_file,
+            None => {
+                let (writer, started) = create_history_file(&self.directory, edit.time).await?;
+                self.current_file.insert(CurrentFile {
+                    writer,
+                    started,
+                    len: encoding::MAGIC.len() as u64,
+                })
+            }
+        };
+        current_file.writer.write_all(&self.buf).await?;
+        current_file.len += self.buf.len() as u64;
+        Ok(())
+    }
+
+    /// Makes sure all appended edits have been written to the file.
+    pub async fn flush(&mut self) -> tokio::io::Result<()> {
+        if let Some(current_file) = &mut self.current_file {
+            current_file.writer.flush().await?;
+        }
+        Ok(())
+    }
+}
+
+/// Creates a new file named after `time`, or a slightly later time if a file with that name already exists.
+async fn create_history_file(
+    directory: &Path,
+    time: SystemTime,
+) -> tokio::io::Result<(BufWriter<File>, SystemTime)> {
+    let mut millis = time
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or(Duration::ZERO)
+        .as_millis() as u64;
+    loop {
+        match File::options()
+            .write(true)
+            .create_new(true)
+            .open(directory.join(format!("{millis:020}.p2h")))
+            .await
+        {
+            Ok(file) => {
+                let mut writer = BufWriter::new(file);
+                writer.write_all(encoding::MAGIC).await?;
+                return Ok((
+                    writer,
+                    SystemTime::UNIX_EPOCH + Duration::from_millis(millis),
+                ));
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => millis += 1,
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+/// Which edits to return from `read_edits`. Fields which are `None` don't filter anything.
+#[derive(Clone, Debug, Default)]
+pub struct EditFilter {
+    pub area: Option<Area>,
+    /// Only edits made at or after this time
+    pub from: Option<SystemTime>,
+    /// Only edits made at or before this time
+    pub to: Option<SystemTime>,
+    pub user: Option<UserId>,
+}
+
+impl EditFilter {
+    pub fn matches(&self, edit: &Edit) -> bool {
+        self.area.is_none_or(|area| area.contains(edit.coord))
+            && self.from.is_none_or(|from| edit.time >= from)
+            && self.to.is_none_or(|to| edit.time <= to)
+            && self.user.as_ref().is_none_or(|user| edit.user == *user)
+    }
+}
+
+/// Reads all edits matching the filter from the history files in the directory, oldest first.
+/// Edits which have not been flushed by the `HistoryLog` yet are not included.
+pub async fn read_edits(directory: &Path, filter: &EditFilter) -> tokio::io::Result<Vec<Edit>> {
+    let mut edits = Vec::new();
+    for_each_edit(directory, filter, |edit| edits.push(edit)).await?;
+    Ok(edits)
+}
+
+/// Like `read_edits`, but calls `f` with each edit instead of collecting them,
+/// so that only one history file is in memory at a time.
+pub async fn for_each_edit(
+    directory: &Path,
+    filter: &EditFilter,
+    mut f: impl FnMut(Edit),
+) -> tokio::io::Result<()> {
+    let mut files = Vec::new();
+    let mut entries = match tokio::fs::read_dir(directory).await {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e),
+    };
+    while let Some(entry) = entries.next_entry().await? {
+        let file_name = entry.file_name();
+        if let Some(millis) = file_name
+            .to_str()
+            .and_then(|name| name.strip_suffix(".p2h"))
+            .and_then(|millis| millis.parse::<u64>().ok())
+        {
+            files.push((
+                SystemTime::UNIX_EPOCH + Duration::from_millis(millis),
+                entry.path(),
+            ));
+        }
+    }
+    files.sort();
+
+    for (i, (started, path)) in files.iter().enumerate() {
+        // skip files which only contain edits from outside of the time range
+        if filter.to.is_some_and(|to| *started > to)
+            || filter.from.is_some_and(|from| {
+                files
+                    .get(i + 1)
+                    .is_some_and(|(next_started, _)| *next_started < from)
+            })
+        {
+            continue;
+        }
+        let bytes = tokio::fs::read(path).await?;
+        let Some(mut bytes) = bytes.strip_prefix(encoding::MAGIC) else {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!("{} is not a history file", path.display()),
+            ));
+        };
+        // if the server stopped while writing an edit, the last edit may be incomplete and is ignored
+        while let Some((edit, len)) = encoding::decode_edit(bytes) {
+            bytes = &bytes[len..];
+            if filter.matches(&edit) {
+                f(edit);
+            }
+        }
+    }
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_history_log_rotation_and_queries() {
+    let directory = std::env::temp_dir().join(format!("p2ws-history-test-{}", std::process::id()));
+    let mut log = HistoryLog::open(
+        directory.clone(),
+        HistoryRotation {
+            max_file_size: 1000,
+            max_file_age: Duration::from_secs(60),
+        },
+    )
+    .await
+    .unwrap();
+    let start = SystemTime::UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+    let edit = |secs: u64, user: &str, x: i16| Edit {
+        time: start + Duration::from_secs(secs),
+        user: UserId::new(user.to_owned()),
+        coord: Coordinate { x, y: 0 },
+        old_color: None,
+        new_color: Color { r: 1, g: 2, b: 3 },
+    };
+    let edits = [
+        edit(0, "a", 0),
+        edit(30, "b", 1),
+        edit(90, "a", 2),
+        edit(200, "b", 3),
+    ];
+    for edit in &edits {
+        log.append(edit).await.unwrap();
+    }
+    log.flush().await.unwrap();
+    // rotated after 60 seconds
+    assert_eq!(std::fs::read_dir(&directory).unwrap().count(), 3);
+
+    assert_eq!(
+        read_edits(&directory, &EditFilter::default())
+            .await
+            .unwrap(),
+        edits
+    );
+    let filter = EditFilter {
+        from: Some(start + Duration::from_secs(30)),
+        to: Some(start + Duration::from_secs(90)),
+        ..Default::default()
+    };
+    assert_eq!(read_edits(&directory, &filter).await.unwrap(), edits[1..3]);
+    let filter = EditFilter {
+        area: Area::try_new(Coordinate { x: 2, y: 0 }, Coordinate { x: 5, y: 0 }),
+        user: Some(UserId::new("b".to_owned())),
+        ..Default::default()
+    };
+    assert_eq!(read_edits(&directory, &filter).await.unwrap(), edits[3..]);
+
+    std::fs::remove_dir_all(&directory).unwrap();
+}
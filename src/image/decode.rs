@@ -0,0 +1,138 @@
+use std::io::Cursor;
+
+/// A decoded image with 8 bits per channel, used to import images onto a canvas.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RgbaImage {
+    pub width: u32,
+    pub height: u32,
+    /// row by row
+    pub pixels: Vec<[u8; 4]>,
+}
+
+impl RgbaImage {
+    /// Decodes a PNG or binary PPM (`P6`) image.
+    pub fn decode(bytes: &[u8]) -> Result<Self, String> {
+        if bytes.starts_with(b"\x89PNG") {
+            Self::decode_png(bytes)
+        } else if bytes.starts_with(b"P6") {
+            Self::decode_ppm(bytes)
+        } else {
+            Err("unsupported image format, expected PNG or binary PPM (P6)".to_owned())
+        }
+    }
+
+    pub fn get(&self, x: u32, y: u32) -> [u8; 4] {
+        self.pixels[y as usize * self.width as usize + x as usize]
+    }
+
+    fn decode_png(bytes: &[u8]) -> Result<Self, String> {
+        let mut decoder = png::Decoder::new(Cursor::new(bytes));
+        decoder.set_transformations(png::Transformations::normalize_to_color8());
+        let mut reader = decoder.read_info().map_err(|e| e.to_string())?;
+        let mut buf = vec![
+            0;
+            reader
+                .output_buffer_size()
+                .ok_or("the image is too large")?
+        ];
+        let info = reader.next_frame(&mut buf).map_err(|e| e.to_string())?;
+        let buf = &buf[..info.buffer_size()];
+        let pixels = match info.color_type {
+            png::ColorType::Grayscale => buf.iter().map(|&l| [l, l, l, 255]).collect(),
+            png::ColorType::GrayscaleAlpha => buf
+                .chunks_exact(2)
+                .map(|la| [la[0], la[0], la[0], la[1]])
+                .collect(),
+            png::ColorType::Rgb => buf
+                .chunks_exact(3)
+                .map(|rgb| [rgb[0], rgb[1], rgb[2], 255])
+                .collect(),
+            png::ColorType::Rgba => buf
+                .chunks_exact(4)
+                .map(|rgba| [rgba[0], rgba[1], rgba[2], rgba[3]])
+                .collect(),
+            png::ColorType::Indexed => {
+                return Err("indexed PNG images should have been expanded".to_owned());
+            }
+        };
+        Ok(Self {
+            width: info.width,
+            height: info.height,
+            pixels,
+        })
+    }
+
+    fn decode_ppm(bytes: &[u8]) -> Result<Self, String> {
+        // the header consists of `P6`, width, height, and maxval,
+        // separated by whitespace and optionally comments, followed by one whitespace character
+        let mut fields = Vec::with_capacity(4);
+        let mut i = 0;
+        while fields.len() < 4 {
+            match bytes.get(i) {
+                Some(b'#') => {
+                    while bytes.get(i).is_some_and(|b| *b != b'\n') {
+                        i += 1;
+                    }
+                }
+                Some(b) if b.is_ascii_whitespace() => i += 1,
+                Some(_) => {
+                    let start = i;
+                    while bytes.get(i).is_some_and(|b| !b.is_ascii_whitespace()) {
+                        i += 1;
+                    }
+                    fields.push(&bytes[start..i]);
+                }
+                None => return Err("incomplete PPM header".to_owned()),
+            }
+        }
+        let number = |field: &[u8]| {
+            std::str::from_utf8(field)
+                .ok()
+                .and_then(|field| field.parse::<u32>().ok())
+                .ok_or_else(|| "invalid PPM header".to_owned())
+        };
+        let (width, height, maxval) = (number(fields[1])?, number(fields[2])?, number(fields[3])?);
+        if maxval == 0 || maxval > 255 {
+            return Err("only PPM images with a maxval from 1 to 255 are supported".to_owned());
+        }
+        let data = bytes
+            .get(i + 1..i + 1 + width as usize * height as usize * 3)
+            .ok_or("the PPM image is incomplete")?;
+        let scale = |c: u8| (c as u32 * 255 / maxval).min(255) as u8;
+        Ok(Self {
+            width,
+            height,
+            pixels: data
+                .chunks_exact(3)
+                .map(|rgb| [scale(rgb[0]), scale(rgb[1]), scale(rgb[2]), 255])
+                .collect(),
+        })
+    }
+}
+
+#[test]
+fn test_decode() {
+    use crate::{data::Color, image::RgbImage};
+
+    let mut image = RgbImage::new(3, 2, Color { r: 0, g: 0, b: 0 });
+    image.set(2, 1, Color { r: 31, g: 8, b: 0 });
+    let expected = RgbaImage {
+        width: 3,
+        height: 2,
+        pixels: (0..6)
+            .map(|i| {
+                if i == 5 {
+                    [255, 66, 0, 255]
+                } else {
+                    [0, 0, 0, 255]
+                }
+            })
+            .collect(),
+    };
+    assert_eq!(
+        RgbaImage::decode(&image.encode_png().unwrap()),
+        Ok(expected.clone())
+    );
+    assert_eq!(RgbaImage::decode(&image.encode_ppm()), Ok(expected));
+    assert!(RgbaImage::decode(b"GIF89a").is_err());
+}
@@ -0,0 +1,184 @@
+mod decode;
+mod quantize;
+
+use std::io::Write;
+
+pub use decode::RgbaImage;
+pub use quantize::{Dithering, quantize};
+
+use crate::data::{Area, Color, Coordinate};
+
+/// An image with 8 bits per channel, used to export canvases.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RgbImage {
+    width: u32,
+    height: u32,
+    /// `[r, g, b, r, g, b, ...]`, row by row
+    data: Vec<u8>,
+}
+
+impl RgbImage {
+    pub fn new(width: u32, height: u32, fill: Color) -> Self {
+        Self {
+            width,
+            height,
+            data: fill.to_rgb8().repeat(width as usize * height as usize),
+        }
+    }
+
+    /// Renders `area`, where pixels are `background` unless they are contained in `pixels`.
+    /// The image's top left pixel is `area.top_left`.
+    pub fn render<'a>(
+        area: Area,
+        background: Color,
+        pixels: impl IntoIterator<Item = (&'a Coordinate, &'a Color)>,
+    ) -> Self {
+        let mut image = Self::new(
+            (area.right() as i32 - area.left() as i32 + 1) as u32,
+            (area.bottom() as i32 - area.top() as i32 + 1) as u32,
+            background,
+        );
+        for (coord, color) in pixels {
+            if area.contains(*coord) {
+                image.set(
+                    (coord.x as i32 - area.left() as i32) as u32,
+                    (coord.y as i32 - area.top() as i32) as u32,
+                    *color,
+                );
+            }
+        }
+        image
+    }
+
+    pub fn width(&self) -> u32 {
+        self.width
+    }
+    pub fn height(&self) -> u32 {
+        self.height
+    }
+
+    pub fn set(&mut self, x: u32, y: u32, color: Color) {
+        let i = (y as usize * self.width as usize + x as usize) * 3;
+        self.data[i..i + 3].copy_from_slice(&color.to_rgb8());
+    }
+
+    pub fn get(&self, x: u32, y: u32) -> [u8; 3] {
+        let i = (y as usize * self.width as usize + x as usize) * 3;
+        [self.data[i], self.data[i + 1], self.data[i + 2]]
+    }
+
+    /// Makes each pixel `factor` pixels wide and tall.
+    pub fn scaled(&self, factor: u32) -> Self {
+        if factor <= 1 {
+            return self.clone();
+        }
+        let width = self.width * factor;
+        let mut data = Vec::with_capacity(self.data.len() * factor as usize * factor as usize);
+        for row in self.data.chunks_exact(self.width as usize * 3) {
+            let start = data.len();
+            for pixel in row.chunks_exact(3) {
+                for _ in 0..factor {
+                    data.extend_from_slice(pixel);
+                }
+            }
+            for _ in 1..factor {
+                data.extend_from_within(start..start + width as usize * 3);
+            }
+        }
+        Self {
+            width,
+            height: self.height * factor,
+            data,
+        }
+    }
+
+    /// Encodes the image as a binary PPM (`P6`) file.
+    pub fn encode_ppm(&self) -> Vec<u8> {
+        let mut out = format!("P6\n{} {}\n255\n", self.width, self.height).into_bytes();
+        out.extend_from_slice(&self.data);
+        out
+    }
+
+    pub fn encode_png(&self) -> Result<Vec<u8>, png::EncodingError> {
+        let mut out = Vec::new();
+        let mut encoder = png::Encoder::new(&mut out, self.width, self.height);
+        encoder.set_color(png::ColorType::Rgb);
+        encoder.set_depth(png::BitDepth::Eight);
+        let mut writer = encoder.write_header()?;
+        writer.write_image_data(&self.data)?;
+        writer.finish()?;
+        Ok(out)
+    }
+}
+
+/// Writes images as the frames of an animated GIF.
+/// GIFs can only contain 256 colors per frame, so frames with more colors are quantized.
+pub struct GifWriter<W: Write> {
+    encoder: gif::Encoder<W>,
+    width: u16,
+    height: u16,
+    /// in units of 10 milliseconds
+    delay: u16,
+}
+
+impl<W: Write> GifWriter<W> {
+    /// The GIF is `width` by `height` pixels, which must be at most `65535`,
+    /// and shows each frame for `delay_ms` milliseconds. The animation repeats forever.
+    pub fn new(out: W, width: u32, height: u32, delay_ms: u32) -> Result<Self, gif::EncodingError> {
+        let (width, height) = (
+            u16::try_from(width).map_err(|_| too_large_for_gif())?,
+            u16::try_from(height).map_err(|_| too_large_for_gif())?,
+        );
+        let mut encoder = gif::Encoder::new(out, width, height, &[])?;
+        encoder.set_repeat(gif::Repeat::Infinite)?;
+        Ok(Self {
+            encoder,
+            width,
+            height,
+            delay: (delay_ms / 10).clamp(1, u16::MAX as u32) as u16,
+        })
+    }
+
+    /// Panics if the image's size is not the size passed to `new`.
+    pub fn write_frame(&mut self, image: &RgbImage) -> Result<(), gif::EncodingError> {
+        assert_eq!(
+            (image.width, image.height),
+            (self.width as u32, self.height as u32)
+        );
+        let mut frame = gif::Frame::from_rgb_speed(self.width, self.height, &image.data, 10);
+        frame.delay = self.delay;
+        self.encoder.write_frame(&frame)
+    }
+}
+
+fn too_large_for_gif() -> gif::EncodingError {
+    gif::EncodingError::Io(std::io::Error::new(
+        std::io::ErrorKind::InvalidInput,
+        "GIFs can be at most 65535 pixels wide and tall",
+    ))
+}
+
+#[test]
+fn test_render_and_scale() {
+    let black = Color { r: 0, g: 0, b: 0 };
+    let white = Color {
+        r: 31,
+        g: 31,
+        b: 31,
+    };
+    let area = Area::try_new(Coordinate { x: -1, y: -1 }, Coordinate { x: 0, y: 0 }).unwrap();
+    let pixels = [
+        (Coordinate { x: 0, y: -1 }, black),
+        (Coordinate { x: 5, y: 5 }, black),
+    ];
+    let image = RgbImage::render(area, white, pixels.iter().map(|(c, p)| (c, p)));
+    assert_eq!((image.width(), image.height()), (2, 2));
+    assert_eq!(image.get(0, 0), [255, 255, 255]);
+    assert_eq!(image.get(1, 0), [0, 0, 0]);
+    let scaled = image.scaled(3);
+    assert_eq!((scaled.width(), scaled.height()), (6, 6));
+    assert_eq!(scaled.get(2, 2), [255, 255, 255]);
+    assert_eq!(scaled.get(3, 2), [0, 0, 0]);
+    assert_eq!(scaled.get(5, 3), [255, 255, 255]);
+    assert!(image.encode_png().unwrap().starts_with(b"\x89PNG"));
+}
@@ -0,0 +1,129 @@
+use crate::{canvas::Palette, data::Color, image::RgbaImage};
+
+/// How to spread the difference between an image's colors and the colors which can be placed.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub enum Dithering {
+    /// Use the most similar color for each pixel
+    #[default]
+    None,
+    Ordered,
+    FloydSteinberg,
+}
+
+/// Converts each pixel to the most similar color which can be placed on a canvas,
+/// which is any `Color` or, if `palette` is set, a color from the palette.
+/// Pixels which are mostly transparent become `None`.
+pub fn quantize(
+    image: &RgbaImage,
+    palette: Option<&Palette>,
+    dithering: Dithering,
+) -> Vec<Option<Color>> {
+    let nearest = |rgb: [f32; 3]| {
+        let to_5_bits = |c: f32| (c.clamp(0.0, 255.0) * 31.0 / 255.0).round() as u8;
+        let color = Color {
+            r: to_5_bits(rgb[0]),
+            g: to_5_bits(rgb[1]),
+            b: to_5_bits(rgb[2]),
+        };
+        match palette {
+            Some(palette) => palette.nearest(color),
+            None => color,
+        }
+    };
+
+    let (width, height) = (image.width as usize, image.height as usize);
+    // the desired color of each pixel, including errors spread from its neighbors
+    let mut desired = image
+        .pixels
+        .iter()
+        .map(|[r, g, b, _]| [*r as f32, *g as f32, *b as f32])
+        .collect::<Vec<_>>();
+    let mut out = Vec::with_capacity(desired.len());
+    for y in 0..height {
+        for x in 0..width {
+            let i = y * width + x;
+            if image.pixels[i][3] < 128 {
+                out.push(None);
+                continue;
+            }
+            let color = match dithering {
+                Dithering::None | Dithering::FloydSteinberg => nearest(desired[i]),
+                Dithering::Ordered => {
+                    // 4x4 Bayer matrix, offsets of up to half of the distance between two 5-bit values
+                    const BAYER: [[f32; 4]; 4] = [
+                        [0.0, 8.0, 2.0, 10.0],
+                        [12.0, 4.0, 14.0, 6.0],
+                        [3.0, 11.0, 1.0, 9.0],
+                        [15.0, 7.0, 13.0, 5.0],
+                    ];
+                    let offset = (BAYER[y % 4][x % 4] / 16.0 - 0.5) * 255.0 / 31.0;
+                    nearest(desired[i].map(|c| c + offset))
+                }
+            };
+            if dithering == Dithering::FloydSteinberg {
+                let actual = color.to_rgb8();
+                let error: [f32; 3] = std::array::from_fn(|c| desired[i][c] - actual[c] as f32);
+                let mut spread = |x: usize, y: usize, weight: f32| {
+                    if x < width && y < height {
+                        for c in 0..3 {
+                            desired[y * width + x][c] += error[c] * weight;
+                        }
+                    }
+                };
+                spread(x + 1, y, 7.0 / 16.0);
+                if x > 0 {
+                    spread(x - 1, y + 1, 3.0 / 16.0);
+                }
+                spread(x, y + 1, 5.0 / 16.0);
+                spread(x + 1, y + 1, 1.0 / 16.0);
+            }
+            out.push(Some(color));
+        }
+    }
+    out
+}
+
+#[test]
+fn test_quantize() {
+    let black = Color { r: 0, g: 0, b: 0 };
+    let white = Color {
+        r: 31,
+        g: 31,
+        b: 31,
+    };
+    // a 4x1 image: transparent, orange, and two 50% gray pixels
+    let image = RgbaImage {
+        width: 4,
+        height: 1,
+        pixels: vec![
+            [0, 0, 0, 0],
+            [255, 128, 0, 255],
+            [128, 128, 128, 255],
+            [128, 128, 128, 255],
+        ],
+    };
+    assert_eq!(
+        quantize(&image, None, Dithering::None),
+        [
+            None,
+            Some(Color { r: 31, g: 16, b: 0 }),
+            Some(Color {
+                r: 16,
+                g: 16,
+                b: 16
+            }),
+            Some(Color {
+                r: 16,
+                g: 16,
+                b: 16
+            }),
+        ]
+    );
+    let palette = Palette::new(vec![black, white]);
+    // without dithering, both gray pixels become the same color,
+    // with dithering, the error of the first one makes the second one a different color
+    let without_dithering = quantize(&image, Some(&palette), Dithering::None);
+    assert_eq!(without_dithering[2], without_dithering[3]);
+    let with_dithering = quantize(&image, Some(&palette), Dithering::FloydSteinberg);
+    assert_ne!(with_dithering[2], with_dithering[3]);
+}
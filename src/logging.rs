@@ -0,0 +1,52 @@
+use tracing_subscriber::EnvFilter;
+
+/// The environment variable which overrides the configured log level
+pub const LOG_LEVEL_VARIABLE: &str = "RUST_LOG";
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct LoggingSettings {
+    /// Which events are logged, like `info` or `p2ws_server=debug,warn`
+    pub level: String,
+    pub format: LogFormat,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum LogFormat {
+    /// One human-readable line per event
+    Text,
+    /// One JSON object per line, with the fields of the event and its spans
+    Json,
+}
+
+impl Default for LoggingSettings {
+    fn default() -> Self {
+        Self {
+            level: "info".to_owned(),
+            format: LogFormat::Text,
+        }
+    }
+}
+
+impl LogFormat {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "text" => Some(Self::Text),
+            "json" => Some(Self::Json),
+            _ => None,
+        }
+    }
+}
+
+/// Writes log events to stderr. `RUST_LOG` takes precedence over the configured level.
+pub fn init(settings: &LoggingSettings) {
+    let filter = EnvFilter::try_from_env(LOG_LEVEL_VARIABLE)
+        .or_else(|_| EnvFilter::try_new(&settings.level))
+        .unwrap_or_else(|_| EnvFilter::new("info"));
+    let subscriber = tracing_subscriber::fmt()
+        .with_env_filter(filter)
+        .with_writer(std::io::stderr);
+    match settings.format {
+        LogFormat::Text => subscriber.init(),
+        LogFormat::Json => subscriber.json().with_current_span(false).init(),
+    }
+}
@@ -1,10 +1,24 @@
 #![allow(dead_code)]
 
-use std::{process::ExitCode, time::Duration};
+use std::process::ExitCode;
 
-use crate::{ratelimit::RatelimitSettings, server::WebsocketServer, users::Users};
+use clap::Parser;
 
+use crate::{
+    cli::{Args, Command},
+    config::Config,
+    one_time_password::OtpKey,
+    server::WebsocketServer,
+    users::{Bans, HttpUserStore, SqliteUserStore, TomlUserStore, UserStoreSettings, Users},
+};
+
+mod canvas;
+mod cli;
+mod config;
 mod data;
+mod history;
+mod image;
+mod logging;
 mod one_time_password;
 mod protocol;
 mod ratelimit;
@@ -13,16 +27,134 @@ mod users;
 
 #[tokio::main]
 async fn main() -> ExitCode {
-    let time_per_message = Duration::from_secs_f64(1.0 / 10000.0);
-    let ratelimit = RatelimitSettings::new(time_per_message)
-        .allow_bursts(1000)
-        .drop_instead_of_blocking();
-
-    let users = Users::from_toml(&tokio::fs::read_to_string("users.toml").await.unwrap()).unwrap();
-
-    let Err(e) = WebsocketServer::new(ratelimit)
-        .accept_connections("127.0.0.1:8080", users)
-        .await;
-    eprintln!("Error accepting connections: {e:?}");
-    ExitCode::FAILURE
+    let mut args = Args::parse();
+    match args.command.take() {
+        None | Some(Command::Serve) => {}
+        Some(command) => return command.run(&args).await,
+    }
+
+    let config = match cli::load_config(&args.config).await {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("{e}");
+            return ExitCode::FAILURE;
+        }
+    };
+    logging::init(&config.logging);
+
+    let bans = match Bans::load(args.bans.clone()).await {
+        Ok(bans) => bans,
+        Err(e) => {
+            tracing::error!("{e}");
+            return ExitCode::FAILURE;
+        }
+    };
+    let users = match load_users(&args, &config).await {
+        Ok(users) => users.with_bans(bans),
+        Err(e) => {
+            tracing::error!("{e}");
+            return ExitCode::FAILURE;
+        }
+    };
+
+    let server = match WebsocketServer::new(&config, users).await {
+        Ok(server) => server,
+        Err(e) => {
+            tracing::error!("Could not restore the canvases from their history: {e}");
+            return ExitCode::FAILURE;
+        }
+    };
+    #[cfg(unix)]
+    if let Some(admin_socket) = config.admin_socket.clone() {
+        let server = server.clone();
+        tokio::task::spawn(async move {
+            if let Err(e) = server::serve_admin_socket(server, admin_socket.clone()).await {
+                tracing::error!(socket = %admin_socket.display(), "Error on the admin socket: {e}");
+            }
+        });
+    }
+
+    #[cfg(unix)]
+    tokio::task::spawn(reload_users_on_sighup(server.clone()));
+    tokio::task::spawn(shut_down_on_signal(server.clone()));
+
+    if let Err(e) = server.clone().accept_connections("127.0.0.1:8080").await {
+        tracing::error!("Error accepting connections: {e:?}");
+        return ExitCode::FAILURE;
+    }
+    if let Err(e) = server.flush_history().await {
+        tracing::error!("Could not write to the history: {e}");
+        return ExitCode::FAILURE;
+    }
+    ExitCode::SUCCESS
+}
+
+/// Opens the user store from the config, or the users file if there is none.
+async fn load_users(args: &Args, config: &Config) -> Result<Users, String> {
+    let otp_key = OtpKey::load(args.otp_key_file.as_deref()).await?;
+    Ok(match &config.user_store {
+        None => Users::new(TomlUserStore::load(args.users.clone(), otp_key).await?),
+        Some(UserStoreSettings::Sqlite(path)) => {
+            Users::new(SqliteUserStore::open(path.clone(), otp_key).await?)
+        }
+        Some(UserStoreSettings::Http(url)) => Users::new(HttpUserStore::new(url)?),
+    })
+}
+
+/// Requests a graceful shutdown when the server receives SIGINT (Ctrl-C) or SIGTERM.
+async fn shut_down_on_signal(server: WebsocketServer) {
+    let interrupt = async {
+        if let Err(e) = tokio::signal::ctrl_c().await {
+            tracing::warn!("Could not listen for SIGINT: {e}");
+            std::future::pending::<()>().await;
+        }
+    };
+    #[cfg(unix)]
+    let terminate = async {
+        use tokio::signal::unix::{SignalKind, signal};
+
+        match signal(SignalKind::terminate()) {
+            Ok(mut terminate) => {
+                terminate.recv().await;
+            }
+            Err(e) => {
+                tracing::warn!("Could not listen for SIGTERM: {e}");
+                std::future::pending::<()>().await;
+            }
+        }
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        () = interrupt => {}
+        () = terminate => {}
+    }
+    tracing::info!("Shutting down...");
+    server.request_shutdown();
+}
+
+/// Reloads the users file whenever the server receives SIGHUP.
+#[cfg(unix)]
+async fn reload_users_on_sighup(server: WebsocketServer) {
+    use tokio::signal::unix::{SignalKind, signal};
+
+    let mut hangups = match signal(SignalKind::hangup()) {
+        Ok(hangups) => hangups,
+        Err(e) => {
+            tracing::warn!("Could not listen for SIGHUP: {e}");
+            return;
+        }
+    };
+    while hangups.recv().await.is_some() {
+        match server.reload_users().await {
+            Ok(reloaded) => tracing::info!(
+                added = ?reloaded.added,
+                updated = ?reloaded.updated,
+                removed = ?reloaded.removed,
+                "Reloaded the users"
+            ),
+            Err(e) => tracing::error!("Could not reload the users: {e}"),
+        }
+    }
 }
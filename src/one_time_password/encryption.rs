@@ -0,0 +1,104 @@
+use std::path::Path;
+
+use chacha20poly1305::{
+    ChaCha20Poly1305, Key, KeyInit, Nonce,
+    aead::{Aead, Payload},
+};
+use data_encoding::BASE64;
+
+/// The environment variable which contains the key, unless a key file is used.
+pub const OTP_KEY_VARIABLE: &str = "P2WS_OTP_KEY";
+
+const NONCE_LENGTH: usize = 12;
+
+/// A key used to encrypt OTP secrets in the users file, so that a copy of the file alone can't be used to log in.
+/// Keys are stored as base64 encoded 32 byte keys.
+pub struct OtpKey(Key);
+
+impl OtpKey {
+    /// Loads the key from the key file if it is set, or from the `P2WS_OTP_KEY` environment variable.
+    /// Returns `None` if neither is set.
+    pub async fn load(key_file: Option<&Path>) -> Result<Option<Self>, String> {
+        let (key, source) = match key_file {
+            Some(key_file) => (
+                tokio::fs::read_to_string(key_file)
+                    .await
+                    .map_err(|e| format!("Could not read {}: {e}", key_file.display()))?,
+                key_file.display().to_string(),
+            ),
+            None => match std::env::var(OTP_KEY_VARIABLE) {
+                Ok(key) => (key, OTP_KEY_VARIABLE.to_owned()),
+                Err(_) => return Ok(None),
+            },
+        };
+        Self::from_base64(key.trim())
+            .map(Some)
+            .ok_or_else(|| format!("{source} must contain a base64 encoded 32 byte key"))
+    }
+
+    pub fn from_base64(key: &str) -> Option<Self> {
+        let key = BASE64.decode(key.as_bytes()).ok()?;
+        (key.len() == 32).then(|| Self(*Key::from_slice(&key)))
+    }
+
+    pub fn generate() -> Result<Self, getrandom::Error> {
+        let mut key = Key::default();
+        getrandom::fill(&mut key)?;
+        Ok(Self(key))
+    }
+
+    pub fn to_base64(&self) -> String {
+        BASE64.encode(&self.0)
+    }
+
+    /// Encrypts `plaintext` for the user, returning the base64 encoded nonce and ciphertext.
+    /// The username is authenticated as well, so an encrypted secret can't be moved to a different user.
+    pub fn encrypt(&self, username: &str, plaintext: &str) -> Result<String, getrandom::Error> {
+        let mut nonce = Nonce::default();
+        getrandom::fill(&mut nonce)?;
+        let ciphertext = ChaCha20Poly1305::new(&self.0)
+            .encrypt(
+                &nonce,
+                Payload {
+                    msg: plaintext.as_bytes(),
+                    aad: username.as_bytes(),
+                },
+            )
+            .expect("encrypting a short message can't fail");
+        let mut encrypted = nonce.to_vec();
+        encrypted.extend_from_slice(&ciphertext);
+        Ok(BASE64.encode(&encrypted))
+    }
+
+    /// Returns `None` if the secret was not encrypted with this key for this user.
+    pub fn decrypt(&self, username: &str, encrypted: &str) -> Option<String> {
+        let encrypted = BASE64.decode(encrypted.as_bytes()).ok()?;
+        if encrypted.len() < NONCE_LENGTH {
+            return None;
+        }
+        let (nonce, ciphertext) = encrypted.split_at(NONCE_LENGTH);
+        let plaintext = ChaCha20Poly1305::new(&self.0)
+            .decrypt(
+                Nonce::from_slice(nonce),
+                Payload {
+                    msg: ciphertext,
+                    aad: username.as_bytes(),
+                },
+            )
+            .ok()?;
+        String::from_utf8(plaintext).ok()
+    }
+}
+
+#[test]
+fn test_encryption() {
+    let key = OtpKey::generate().unwrap();
+    let encrypted = key.encrypt("alice", "Totp:JBSWY3DPEHPK3PXP").unwrap();
+    assert_eq!(
+        key.decrypt("alice", &encrypted).as_deref(),
+        Some("Totp:JBSWY3DPEHPK3PXP")
+    );
+    assert_eq!(key.decrypt("mallory", &encrypted), None);
+    let other_key = OtpKey::from_base64(&OtpKey::generate().unwrap().to_base64()).unwrap();
+    assert_eq!(other_key.decrypt("alice", &encrypted), None);
+}
@@ -1,22 +1,77 @@
+mod encryption;
+mod totp;
+
+use std::time::SystemTime;
+
+pub use encryption::{OTP_KEY_VARIABLE, OtpKey};
+pub use totp::Totp;
+
 /// Some way to generate an OTP.
 pub enum OneTimePasswordGenerator {
     Static(u32),
+    Totp(Totp),
+}
+
+/// What is remembered about used OTPs, so that the same OTP can't be used twice.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct OtpState {
+    /// The time step of the most recently used TOTP
+    pub last_used_step: Option<u64>,
 }
 
 impl OneTimePasswordGenerator {
-    /// Returns the current OTP (as a decimal number), or
-    /// `None` if and only if the current OTP has been returned before.
-    ///
-    /// This means that the same OTP will not be returned twice.
-    /// After calling this function, until the OTP algorithm generates a new OTP,
-    /// there will be no OTP.
+    /// If `one_time_password` is the current OTP and hasn't been used yet,
+    /// returns the state which marks it as used once it is passed to `set_state`.
     ///
-    /// However, this function may still return `Some(x)` and `Some(y)`
-    /// back to back where `x == y`, as the OTP algorithm may simply
-    /// generate the same OTP twice for two different times.
-    pub fn get_current_otp(&mut self) -> Option<u32> {
+    /// Static OTPs have no state, so they can be used any number of times.
+    pub fn verify(&self, one_time_password: u32) -> Option<OtpState> {
         match self {
-            Self::Static(pin) => Some(*pin),
+            Self::Static(pin) => (*pin == one_time_password).then_some(OtpState::default()),
+            Self::Totp(totp) => totp
+                .verify(one_time_password, SystemTime::now())
+                .map(|step| OtpState {
+                    last_used_step: Some(step),
+                }),
+        }
+    }
+
+    pub fn state(&self) -> OtpState {
+        match self {
+            Self::Static(_) => OtpState::default(),
+            Self::Totp(totp) => OtpState {
+                last_used_step: totp.last_used_step(),
+            },
+        }
+    }
+
+    pub fn set_state(&mut self, state: OtpState) {
+        if let Self::Totp(totp) = self {
+            totp.set_last_used_step(state.last_used_step);
+        }
+    }
+
+    /// The settings as `Static:<pin>` or `Totp:<base32 secret>`, which is what gets encrypted in the users file.
+    pub fn to_plaintext(&self) -> String {
+        match self {
+            Self::Static(pin) => format!("Static:{pin}"),
+            Self::Totp(totp) => format!("Totp:{}", totp.secret_base32()),
+        }
+    }
+
+    pub fn from_plaintext(plaintext: &str) -> Option<Self> {
+        match plaintext.split_once(':')? {
+            ("Static", pin) => Some(Self::Static(pin.parse().ok()?)),
+            ("Totp", secret) => Some(Self::Totp(Totp::from_base32(secret)?)),
+            _ => None,
+        }
+    }
+
+    /// Whether both generate the same OTPs, ignoring which OTPs have already been used.
+    pub fn has_same_settings(&self, other: &Self) -> bool {
+        match (self, other) {
+            (Self::Static(a), Self::Static(b)) => a == b,
+            (Self::Totp(a), Self::Totp(b)) => a.has_same_secret(b),
+            _ => false,
         }
     }
 }
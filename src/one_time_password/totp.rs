@@ -0,0 +1,124 @@
+use std::time::{Duration, SystemTime};
+
+use hmac::{Hmac, Mac};
+use sha1::Sha1;
+
+/// Time-based OTPs as described in RFC 6238 (HMAC-SHA1, 30 seconds, 6 digits),
+/// which are supported by common authenticator apps.
+pub struct Totp {
+    secret: Vec<u8>,
+    /// The time step of the most recently used OTP
+    last_used_step: Option<u64>,
+}
+
+const STEP: Duration = Duration::from_secs(30);
+const DIGITS: u32 = 6;
+/// The length of generated secrets in bytes, as recommended by RFC 4226
+const SECRET_LENGTH: usize = 20;
+
+impl Totp {
+    pub fn new(secret: Vec<u8>) -> Self {
+        Self {
+            secret,
+            last_used_step: None,
+        }
+    }
+
+    /// Parses a base32 secret as shown by authenticator apps, ignoring case, spaces and padding.
+    pub fn from_base32(secret: &str) -> Option<Self> {
+        let secret = secret
+            .chars()
+            .filter(|c| !c.is_whitespace() && *c != '=')
+            .collect::<String>()
+            .to_ascii_uppercase();
+        let secret = data_encoding::BASE32_NOPAD.decode(secret.as_bytes()).ok()?;
+        (!secret.is_empty()).then(|| Self::new(secret))
+    }
+
+    /// Generates a random secret.
+    pub fn generate() -> Result<Self, getrandom::Error> {
+        let mut secret = vec![0; SECRET_LENGTH];
+        getrandom::fill(&mut secret)?;
+        Ok(Self::new(secret))
+    }
+
+    pub fn secret_base32(&self) -> String {
+        data_encoding::BASE32_NOPAD.encode(&self.secret)
+    }
+
+    /// A URI which authenticator apps can import, usually by scanning it as a QR code.
+    pub fn otpauth_uri(&self, issuer: &str, username: &str) -> String {
+        let (issuer, username) = (percent_encode(issuer), percent_encode(username));
+        format!(
+            "otpauth://totp/{issuer}:{username}?secret={}&issuer={issuer}&algorithm=SHA1&digits={DIGITS}&period={}",
+            self.secret_base32(),
+            STEP.as_secs()
+        )
+    }
+
+    /// If `one_time_password` is the OTP for `now` and hasn't been used yet, returns its time step.
+    /// The OTP is only marked as used by `set_last_used_step`.
+    pub fn verify(&self, one_time_password: u32, now: SystemTime) -> Option<u64> {
+        let step = now
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs()
+            / STEP.as_secs();
+        (self.last_used_step != Some(step) && self.otp(step) == one_time_password).then_some(step)
+    }
+
+    pub fn last_used_step(&self) -> Option<u64> {
+        self.last_used_step
+    }
+
+    pub fn set_last_used_step(&mut self, step: Option<u64>) {
+        self.last_used_step = step;
+    }
+
+    pub fn has_same_secret(&self, other: &Self) -> bool {
+        self.secret == other.secret
+    }
+
+    /// The OTP for a time step, see RFC 4226 section 5.3
+    fn otp(&self, step: u64) -> u32 {
+        let mut mac =
+            Hmac::<Sha1>::new_from_slice(&self.secret).expect("HMAC accepts keys of any length");
+        mac.update(&step.to_be_bytes());
+        let hash = mac.finalize().into_bytes();
+        let offset = (hash[hash.len() - 1] & 0xF) as usize;
+        let code = u32::from_be_bytes(hash[offset..offset + 4].try_into().unwrap()) & 0x7FFF_FFFF;
+        code % 10u32.pow(DIGITS)
+    }
+}
+
+fn percent_encode(s: &str) -> String {
+    let mut encoded = String::new();
+    for byte in s.bytes() {
+        if byte.is_ascii_alphanumeric() || matches!(byte, b'-' | b'_' | b'.' | b'~') {
+            encoded.push(byte as char);
+        } else {
+            encoded.push_str(&format!("%{byte:02X}"));
+        }
+    }
+    encoded
+}
+
+#[test]
+fn test_totp() {
+    // test vectors from RFC 6238, truncated to 6 digits
+    let mut totp = Totp::new(b"12345678901234567890".to_vec());
+    assert_eq!(totp.otp(59 / 30), 287082);
+    assert_eq!(totp.otp(1111111109 / 30), 81804);
+    let now = SystemTime::UNIX_EPOCH + Duration::from_secs(1234567890);
+    // a wrong OTP doesn't use up the time step, so guessing can't lock the user out
+    assert_eq!(totp.verify(5925, now), None);
+    assert_eq!(totp.last_used_step(), None);
+    assert_eq!(totp.verify(5924, now), Some(1234567890 / 30));
+    // each OTP can only be used once
+    totp.set_last_used_step(Some(1234567890 / 30));
+    assert_eq!(totp.verify(5924, now), None);
+
+    let parsed = Totp::from_base32(&totp.secret_base32().to_lowercase()).unwrap();
+    assert!(parsed.has_same_secret(&totp));
+    assert!(Totp::from_base32("not base32!").is_none());
+}
@@ -29,6 +29,7 @@ impl P2Encodable for Color {
 }
 
 impl P2Decodable for Color {
+    #[allow(clippy::cast_abs_to_unsigned)]
     async fn read_p2encoded(
         connection: &mut (impl crate::server::P2Read + Unpin),
     ) -> tokio::io::Result<Option<Self>> {
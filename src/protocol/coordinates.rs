@@ -1,5 +1,5 @@
 use crate::{
-    data::Coordinate,
+    data::{Area, Coordinate},
     protocol::{P2Decodable, P2Encodable},
     server::P2Write,
 };
@@ -30,6 +30,17 @@ impl P2Decodable for Coordinate {
     }
 }
 
+impl P2Encodable for Area {
+    async fn write_p2encoded(
+        &self,
+        connection: &mut (impl P2Write + Unpin),
+    ) -> tokio::io::Result<()> {
+        self.top_left.write_p2encoded(connection).await?;
+        self.bottom_right.write_p2encoded(connection).await?;
+        Ok(())
+    }
+}
+
 pub struct CoordI8(pub i8);
 
 pub struct CoordI16(pub i16);
@@ -0,0 +1,69 @@
+use std::time::Duration;
+
+use crate::{
+    protocol::{P2Decodable, P2Encodable, coordinates::CoordI16},
+    server::P2Write,
+};
+
+/// The largest duration which can be encoded, durations above this are encoded as this value.
+const MAX_SECONDS: u64 = 32512;
+
+impl P2Encodable for Duration {
+    async fn write_p2encoded(
+        &self,
+        connection: &mut (impl P2Write + Unpin),
+    ) -> tokio::io::Result<()> {
+        // round up so that clients never wait for less time than they have to
+        let millis = self.as_nanos().div_ceil(1_000_000);
+        let (seconds, millis) = if millis / 1000 > MAX_SECONDS as u128 {
+            (MAX_SECONDS as i16, 999)
+        } else {
+            ((millis / 1000) as i16, (millis % 1000) as i16)
+        };
+        CoordI16(seconds).write_p2encoded(connection).await?;
+        CoordI16(millis).write_p2encoded(connection).await?;
+        Ok(())
+    }
+}
+
+impl P2Decodable for Duration {
+    async fn read_p2encoded(
+        connection: &mut (impl crate::server::P2Read + Unpin),
+    ) -> tokio::io::Result<Option<Self>> {
+        Ok(
+            match (
+                CoordI16::read_p2encoded(connection).await?,
+                CoordI16::read_p2encoded(connection).await?,
+            ) {
+                (Some(CoordI16(seconds)), Some(CoordI16(millis)))
+                    if seconds >= 0 && (0..1000).contains(&millis) =>
+                {
+                    Some(Duration::from_secs(seconds as u64) + Duration::from_millis(millis as u64))
+                }
+                _ => None,
+            },
+        )
+    }
+}
+
+#[tokio::test]
+async fn test_duration_encoding_and_decoding() {
+    let mut connection = super::enc_dec::TestLoopbackConnection::default();
+    for (duration, expected) in [
+        (Duration::ZERO, Duration::ZERO),
+        (Duration::from_millis(1500), Duration::from_millis(1500)),
+        (Duration::from_micros(100), Duration::from_millis(1)),
+        (Duration::from_secs(300), Duration::from_secs(300)),
+        (
+            Duration::from_secs(100000),
+            Duration::from_millis(MAX_SECONDS * 1000 + 999),
+        ),
+    ] {
+        duration.write_p2encoded(&mut connection).await.unwrap();
+        let decoded = Duration::read_p2encoded(&mut connection)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(decoded, expected);
+    }
+}
@@ -0,0 +1,54 @@
+/// Optional parts of the protocol which a client can enable after authenticating.
+/// The server will only send messages belonging to an extension once the client has enabled it.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Extension {
+    /// The server sends Cooldown messages after every Put
+    Cooldown = 0x01,
+    /// The server sends Ratelimited messages when it ignores messages because of the ratelimit
+    Ratelimited = 0x02,
+    /// The server sends Bounds messages when the extension is enabled and when the bounds change
+    Bounds = 0x03,
+    /// The server sends a Palette message when the extension is enabled
+    Palette = 0x04,
+    /// The server sends a PutRejected message when a Put did not change a pixel
+    Rejections = 0x05,
+    /// The server sends a SessionToken message when the extension is enabled
+    Sessions = 0x06,
+}
+
+impl Extension {
+    pub const ALL: [Self; 6] = [
+        Self::Cooldown,
+        Self::Ratelimited,
+        Self::Bounds,
+        Self::Palette,
+        Self::Rejections,
+        Self::Sessions,
+    ];
+
+    pub fn from_byte(byte: u8) -> Option<Self> {
+        match byte {
+            0x01 => Some(Self::Cooldown),
+            0x02 => Some(Self::Ratelimited),
+            0x03 => Some(Self::Bounds),
+            0x04 => Some(Self::Palette),
+            0x05 => Some(Self::Rejections),
+            0x06 => Some(Self::Sessions),
+            _ => None,
+        }
+    }
+}
+
+/// The set of extensions a client has enabled.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Extensions(u32);
+
+impl Extensions {
+    pub fn enable(&mut self, extension: Extension) {
+        self.0 |= 1 << extension as u8;
+    }
+
+    pub fn is_enabled(&self, extension: Extension) -> bool {
+        self.0 & (1 << extension as u8) != 0
+    }
+}
@@ -0,0 +1,99 @@
+use std::time::Duration;
+
+use crate::{
+    data::{Area, Color, Coordinate},
+    protocol::{P2Encodable, coordinates::CoordI16},
+    server::{P2Write, PutRejected},
+};
+
+/// Messages which the server sends to clients, except for Updates.
+pub enum ServerMessage {
+    Cooldown {
+        available_pixels: u32,
+        next_pixel_in: Duration,
+    },
+    Ratelimited {
+        retry_after: Duration,
+        dropped_messages: u32,
+    },
+    Bounds {
+        area: Area,
+    },
+    Palette {
+        colors: Vec<Color>,
+    },
+    /// The canvas which the client is currently using.
+    /// `name` must be 1 to 255 bytes long.
+    Canvas {
+        name: String,
+    },
+    PutRejected {
+        coord: Coordinate,
+        reason: PutRejected,
+    },
+    /// A token which the client can use to authenticate again without an OTP.
+    SessionToken {
+        token: Vec<u8>,
+    },
+}
+
+impl P2Encodable for ServerMessage {
+    async fn write_p2encoded(
+        &self,
+        connection: &mut (impl P2Write + Unpin),
+    ) -> tokio::io::Result<()> {
+        match self {
+            Self::Cooldown {
+                available_pixels,
+                next_pixel_in,
+            } => {
+                connection.write_all(&[0xFF, 0x80]).await?;
+                CoordI16((*available_pixels).min(32512) as i16)
+                    .write_p2encoded(connection)
+                    .await?;
+                next_pixel_in.write_p2encoded(connection).await?;
+            }
+            Self::Ratelimited {
+                retry_after,
+                dropped_messages,
+            } => {
+                connection.write_all(&[0xFF, 0x81]).await?;
+                retry_after.write_p2encoded(connection).await?;
+                CoordI16((*dropped_messages).min(32512) as i16)
+                    .write_p2encoded(connection)
+                    .await?;
+            }
+            Self::Bounds { area } => {
+                connection.write_all(&[0xFF, 0x82]).await?;
+                area.write_p2encoded(connection).await?;
+            }
+            Self::Palette { colors } => {
+                connection.write_all(&[0xFF, 0x83]).await?;
+                // there are only 32768 colors, but at most 32512 can be encoded
+                let colors = &colors[0..colors.len().min(32512)];
+                CoordI16(colors.len() as i16)
+                    .write_p2encoded(connection)
+                    .await?;
+                for color in colors {
+                    color.write_p2encoded(connection).await?;
+                }
+            }
+            Self::Canvas { name } => {
+                connection
+                    .write_all(&[0xFF, 0x84, name.len() as u8 - 1])
+                    .await?;
+                connection.write_all(name.as_bytes()).await?;
+            }
+            Self::PutRejected { coord, reason } => {
+                connection.write_all(&[0xFF, 0x85]).await?;
+                coord.write_p2encoded(connection).await?;
+                connection.write_all(&[*reason as u8]).await?;
+            }
+            Self::SessionToken { token } => {
+                connection.write_all(&[0xFF, 0x86]).await?;
+                connection.write_all(token).await?;
+            }
+        }
+        Ok(())
+    }
+}
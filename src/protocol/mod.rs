@@ -1,5 +1,12 @@
 mod color;
 mod coordinates;
+mod duration;
 mod enc_dec;
+mod extensions;
+mod messages;
 
+#[cfg(test)]
+pub use enc_dec::TestLoopbackConnection;
 pub use enc_dec::{P2Decodable, P2Encodable};
+pub use extensions::{Extension, Extensions};
+pub use messages::ServerMessage;
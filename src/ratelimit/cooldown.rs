@@ -0,0 +1,122 @@
+use std::{collections::HashMap, time::Duration};
+
+use tokio::time::Instant;
+
+use crate::users::UserId;
+
+/// Limits how many pixels each user can place, independent of how many messages they send.
+///
+/// Every user has a stock of pixels which refills by one pixel every `time_per_pixel`,
+/// until it contains `stock_size` pixels. Placing a pixel takes one pixel from the stock.
+#[derive(Clone, Copy, Debug)]
+pub struct PixelCooldownSettings {
+    time_per_pixel: Duration,
+    stock_size: u32,
+}
+
+impl PixelCooldownSettings {
+    pub fn new(time_per_pixel: Duration) -> Self {
+        Self {
+            time_per_pixel,
+            stock_size: 1,
+        }
+    }
+    /// Allow users to save up this many pixels while they are not placing any.
+    /// A value of `0` and a value of `1` do the same thing,
+    /// allowing one pixel every `time_per_pixel`.
+    pub fn allow_stock(mut self, stock_size: u32) -> Self {
+        self.stock_size = stock_size.max(1);
+        self
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct CooldownStatus {
+    /// How many pixels the user can place right now
+    pub available_pixels: u32,
+    /// How long it will take until `available_pixels` increases,
+    /// or zero if the stock is already full.
+    pub next_pixel_in: Duration,
+}
+
+/// The pixel stock of every user who has placed a pixel recently.
+#[derive(Default)]
+pub struct PixelCooldowns {
+    /// For each user, the point in time at which their stock will be full again.
+    /// Users whose stock is full may or may not have an entry.
+    refilled_at: HashMap<UserId, Instant>,
+}
+
+impl PixelCooldowns {
+    pub fn status(
+        &self,
+        settings: &PixelCooldownSettings,
+        user: &UserId,
+        now: Instant,
+    ) -> CooldownStatus {
+        let refilled_at = self.refilled_at.get(user).copied().unwrap_or(now);
+        if refilled_at <= now {
+            return CooldownStatus {
+                available_pixels: settings.stock_size,
+                next_pixel_in: Duration::ZERO,
+            };
+        }
+        let until_refilled = refilled_at - now;
+        let missing_pixels = until_refilled
+            .as_nanos()
+            .div_ceil(settings.time_per_pixel.as_nanos().max(1));
+        let missing_pixels = missing_pixels.min(settings.stock_size as u128) as u32;
+        CooldownStatus {
+            available_pixels: settings.stock_size - missing_pixels,
+            next_pixel_in: until_refilled
+                .saturating_sub(settings.time_per_pixel * (missing_pixels - 1)),
+        }
+    }
+
+    /// If the user has a pixel in their stock, takes it and returns `true`.
+    /// Otherwise, returns `false` without changing anything.
+    pub fn try_take_pixel(
+        &mut self,
+        settings: &PixelCooldownSettings,
+        user: &UserId,
+        now: Instant,
+    ) -> bool {
+        if self.status(settings, user, now).available_pixels == 0 {
+            return false;
+        }
+        let refilled_at = self.refilled_at.entry(user.clone()).or_insert(now);
+        *refilled_at = (*refilled_at).max(now) + settings.time_per_pixel;
+        true
+    }
+
+    /// Removes entries of users whose stock is full, as they are equivalent to having no entry.
+    pub fn forget_refilled(&mut self, now: Instant) {
+        self.refilled_at.retain(|_, refilled_at| *refilled_at > now);
+    }
+}
+
+#[test]
+fn test_pixel_cooldown() {
+    let settings = PixelCooldownSettings::new(Duration::from_secs(10)).allow_stock(3);
+    let user = UserId::new("user".to_owned());
+    let mut cooldowns = PixelCooldowns::default();
+    let start = Instant::now();
+    let status = |cooldowns: &PixelCooldowns, secs| {
+        let status = cooldowns.status(&settings, &user, start + Duration::from_secs(secs));
+        (status.available_pixels, status.next_pixel_in.as_secs())
+    };
+    assert_eq!(status(&cooldowns, 0), (3, 0));
+    for _ in 0..3 {
+        assert!(cooldowns.try_take_pixel(&settings, &user, start));
+    }
+    assert!(!cooldowns.try_take_pixel(&settings, &user, start));
+    assert_eq!(status(&cooldowns, 0), (0, 10));
+    assert_eq!(status(&cooldowns, 5), (0, 5));
+    assert_eq!(status(&cooldowns, 10), (1, 10));
+    assert_eq!(status(&cooldowns, 25), (2, 5));
+    assert_eq!(status(&cooldowns, 30), (3, 0));
+    let later = start + Duration::from_secs(15);
+    assert!(cooldowns.try_take_pixel(&settings, &user, later));
+    assert!(!cooldowns.try_take_pixel(&settings, &user, later));
+    assert_eq!(status(&cooldowns, 20), (1, 10));
+}
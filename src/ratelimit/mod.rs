@@ -1,7 +1,11 @@
+mod cooldown;
+
 use std::time::Duration;
 
 use tokio::time::Instant;
 
+pub use cooldown::{CooldownStatus, PixelCooldownSettings, PixelCooldowns};
+
 #[derive(Clone, Copy)]
 pub struct RatelimitSettings {
     time_per_message: Duration,
@@ -42,6 +46,31 @@ impl RatelimitSettings {
     }
 }
 
+/// Per-user changes to the server's default `RatelimitSettings`.
+/// Every field which is `None` keeps the value from the defaults.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct RatelimitOverride {
+    pub time_per_message: Option<Duration>,
+    pub burst_size: Option<u32>,
+    pub drop_instead_of_blocking: Option<bool>,
+}
+
+impl RatelimitSettings {
+    /// Returns these settings with all values set in `ratelimit_override` replaced.
+    pub fn with_override(mut self, ratelimit_override: &RatelimitOverride) -> Self {
+        if let Some(time_per_message) = ratelimit_override.time_per_message {
+            self.time_per_message = time_per_message;
+        }
+        if let Some(burst_size) = ratelimit_override.burst_size {
+            self = self.allow_bursts(burst_size);
+        }
+        if let Some(drop_instead_of_blocking) = ratelimit_override.drop_instead_of_blocking {
+            self.drop_instead_of_blocking = drop_instead_of_blocking;
+        }
+        self
+    }
+}
+
 pub struct Ratelimiter {
     /// NOTE: this can be a time in the future.
     /// Do not assume `Instant::now() >= last_message.unwrap()`.
@@ -49,6 +78,8 @@ pub struct Ratelimiter {
     time_per_message: Duration,
     burst_size: u32,
     drop_instead_of_blocking: bool,
+    /// Time spent waiting in `wait_if_necessary_on_recv` since `take_blocked_time` was last called
+    blocked_time: Duration,
 }
 
 impl RatelimitSettings {
@@ -58,6 +89,7 @@ impl RatelimitSettings {
             time_per_message: self.time_per_message,
             burst_size: self.burst_size,
             drop_instead_of_blocking: self.drop_instead_of_blocking,
+            blocked_time: Duration::ZERO,
         }
     }
 }
@@ -104,7 +136,9 @@ impl Ratelimiter {
                 (last_message + self.time_per_message)
                     .max(now - self.time_per_message * (self.burst_size - 1))
             } else {
-                tokio::time::sleep(last_message + self.time_per_message - now).await;
+                let wait = last_message + self.time_per_message - now;
+                tokio::time::sleep(wait).await;
+                self.blocked_time += wait;
                 last_message + self.time_per_message
             }
         } else {
@@ -117,16 +151,27 @@ impl Ratelimiter {
     pub fn is_waiting_necessary(&self, now: Instant) -> bool {
         // NOTE: must have the same logic as `wait_if_necessary_on_recv`
         if let Some(last_message) = self.last_message {
-            if now >= last_message + self.time_per_message {
-                false
-            } else {
-                true
-            }
+            now < last_message + self.time_per_message
         } else {
             false
         }
     }
 
+    /// How long it will take until `is_waiting_necessary` returns `false`.
+    pub fn time_until_next_message(&self, now: Instant) -> Duration {
+        // NOTE: must have the same logic as `is_waiting_necessary`
+        if let Some(last_message) = self.last_message {
+            (last_message + self.time_per_message).saturating_duration_since(now)
+        } else {
+            Duration::ZERO
+        }
+    }
+
+    /// Returns how long `wait_if_necessary_on_recv` has waited since the last call, for statistics.
+    pub fn take_blocked_time(&mut self) -> Duration {
+        std::mem::take(&mut self.blocked_time)
+    }
+
     /// This will never block, but it will always reset the ratelimit
     /// so that the next call to `wait_if_necessary_on_recv` will return
     /// after `time_per_message` has passed since `dont_wait_on_recv` was called.
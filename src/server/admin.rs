@@ -0,0 +1,359 @@
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    time::{Duration, SystemTime},
+};
+
+use crate::{
+    canvas::Palette,
+    data::{Area, Color, Coordinate},
+    history::EditFilter,
+    image::{Dithering, RgbaImage, quantize},
+    server::{CanvasId, P2Write, Server, connection_data::ActiveConnectionData},
+    users::{ReloadedUsers, UserId},
+};
+
+/// A connected client, as shown to administrators.
+pub struct ConnectionInfo {
+    pub user: UserId,
+    pub canvas: CanvasId,
+    pub subscribed_area: Option<Area>,
+    /// The time since the client last sent a message
+    pub idle: Duration,
+}
+
+/// Operations for the server's administrators.
+/// All changes are attributed to `UserId::admin()` in the history.
+impl<W: P2Write + Unpin> Server<W> {
+    /// Reverts all edits which `user` made between `from` and `to`, optionally only within `area`.
+    ///
+    /// Each pixel which the user painted in that time, and which hasn't been painted by anyone else since,
+    /// is restored to the most recent color set by someone else, or to the canvas' background
+    /// if nobody else has ever painted it. Returns the number of restored pixels,
+    /// or an error if the canvas has no history.
+    pub async fn revert_user(
+        &self,
+        canvas: CanvasId,
+        user: &UserId,
+        from: SystemTime,
+        to: SystemTime,
+        area: Option<Area>,
+    ) -> tokio::io::Result<usize> {
+        // the history is read twice instead of being loaded into memory
+        let mut affected_pixels = BTreeSet::<Coordinate>::new();
+        self.for_each_edit(
+            canvas,
+            &EditFilter {
+                area,
+                from: Some(from),
+                to: Some(to),
+                user: Some(user.clone()),
+            },
+            |edit| {
+                affected_pixels.insert(edit.coord);
+            },
+        )
+        .await?;
+        // for each affected pixel: who made the most recent edit,
+        // and the most recent color set by someone else
+        let mut pixels = BTreeMap::<Coordinate, (bool, Option<Color>)>::new();
+        let filter = EditFilter {
+            area,
+            ..Default::default()
+        };
+        self.for_each_edit(canvas, &filter, |edit| {
+            if affected_pixels.contains(&edit.coord) {
+                let (last_edit_in_window, restore_to) = pixels.entry(edit.coord).or_default();
+                if edit.user == *user {
+                    *last_edit_in_window = from <= edit.time && edit.time <= to;
+                } else {
+                    *last_edit_in_window = false;
+                    *restore_to = Some(edit.new_color);
+                }
+            }
+        })
+        .await?;
+
+        let background = self.canvas(canvas).settings.background;
+        let admin = UserId::admin();
+        let mut restored = 0;
+        for (coord, (last_edit_in_window, restore_to)) in pixels {
+            if last_edit_in_window {
+                self.set_pixel(canvas, &admin, coord, restore_to.unwrap_or(background))
+                    .await;
+                restored += 1;
+            }
+        }
+        Ok(restored)
+    }
+
+    /// All connected clients, sorted by user
+    pub async fn connections(&self) -> Vec<ConnectionInfo> {
+        let active_connections = self.active_connections.lock().await;
+        let mut connections = Vec::with_capacity(active_connections.len());
+        for (user, connection) in active_connections.iter() {
+            let connection = connection.lock().await;
+            connections.push(ConnectionInfo {
+                user: user.clone(),
+                canvas: connection.canvas,
+                subscribed_area: connection.subscribed_area,
+                idle: connection.last_action.elapsed(),
+            });
+        }
+        drop(active_connections);
+        connections.sort_by(|a, b| a.user.cmp(&b.user));
+        connections
+    }
+
+    /// The smallest area containing every pixel which has been painted, `None` if no pixel has been painted.
+    pub async fn painted_area(&self, canvas: CanvasId) -> Option<Area> {
+        let pixels = self.canvas(canvas).pixels.lock().await;
+        let mut coords = pixels.keys();
+        let first = *coords.next()?;
+        let (mut top_left, mut bottom_right) = (first, first);
+        for coord in coords {
+            top_left.x = top_left.x.min(coord.x);
+            top_left.y = top_left.y.min(coord.y);
+            bottom_right.x = bottom_right.x.max(coord.x);
+            bottom_right.y = bottom_right.y.max(coord.y);
+        }
+        Area::try_new(top_left, bottom_right)
+    }
+
+    /// Reads the users file again, and disconnects users who were removed from it.
+    pub async fn reload_users(&self) -> Result<ReloadedUsers, String> {
+        let reloaded = self.users.reload().await?;
+        for user in &reloaded.removed {
+            self.revoke_sessions(user).await;
+            self.kick(user).await;
+        }
+        Ok(reloaded)
+    }
+
+    /// Removes the user from the user store and disconnects them.
+    /// Returns `Ok(false)` if the user didn't exist.
+    pub async fn remove_user(&self, user: &UserId) -> Result<bool, String> {
+        let removed = self.users.remove(user).await?;
+        self.revoke_sessions(user).await;
+        self.kick(user).await;
+        Ok(removed)
+    }
+
+    /// Makes the user's session tokens invalid, so they have to authenticate with an OTP again.
+    pub async fn revoke_sessions(&self, user: &UserId) {
+        if let Some(sessions) = self.sessions() {
+            sessions.revoke(user).await;
+        }
+    }
+
+    /// Disconnects the user by sending a Disconnect Request and closing the connection.
+    /// Returns `false` if the user wasn't connected.
+    pub async fn kick(&self, user: &UserId) -> bool {
+        let Some(connection) = self.active_connections.lock().await.remove(user) else {
+            return false;
+        };
+        disconnect(&mut *connection.lock().await).await;
+        true
+    }
+
+    /// Sends the pending Updates, then disconnects every client like `kick`.
+    pub async fn disconnect_all(&self) {
+        for (id, canvas) in self.canvases.iter().enumerate() {
+            Self::transmit_modified_pixels(
+                canvas,
+                CanvasId(id),
+                &self.active_connections,
+                &self.stats,
+            )
+            .await;
+        }
+        let connections = std::mem::take(&mut *self.active_connections.lock().await);
+        for connection in connections.into_values() {
+            disconnect(&mut *connection.lock().await).await;
+        }
+    }
+
+    /// Ignores the user's Puts for `duration`, or until they are unmuted if it is `None`.
+    pub async fn mute(&self, user: &UserId, duration: Option<Duration>) {
+        self.users
+            .mute(
+                user.clone(),
+                duration.map(|duration| SystemTime::now() + duration),
+            )
+            .await;
+    }
+
+    /// Returns `false` if the user wasn't muted.
+    pub async fn unmute(&self, user: &UserId) -> bool {
+        self.users.unmute(user).await
+    }
+
+    /// Disconnects the user and prevents them from authenticating for `duration`, or forever if it is `None`.
+    pub async fn ban(&self, user: &UserId, duration: Option<Duration>) -> tokio::io::Result<()> {
+        self.users
+            .ban(
+                user.clone(),
+                duration.map(|duration| SystemTime::now() + duration),
+            )
+            .await?;
+        self.revoke_sessions(user).await;
+        self.kick(user).await;
+        Ok(())
+    }
+
+    /// Returns `false` if the user wasn't banned.
+    pub async fn unban(&self, user: &UserId) -> tokio::io::Result<bool> {
+        self.users.unban(user).await
+    }
+
+    /// Paints `image` onto the canvas with its top left corner at `top_left`.
+    ///
+    /// The image is quantized to the given palette, or to the canvas' palette if there is none.
+    /// Each pixel is placed with `put` as the admin user, which has no cooldown and may paint protected areas.
+    /// Transparent pixels, pixels which already have the right color,
+    /// and pixels which `put` rejects, like those outside of the canvas' current bounds
+    /// or with a color the canvas' palette doesn't allow, are skipped.
+    /// Returns the number of changed pixels.
+    pub async fn import_image(
+        &self,
+        canvas: CanvasId,
+        image: &RgbaImage,
+        top_left: Coordinate,
+        palette: Option<&Palette>,
+        dithering: Dithering,
+    ) -> usize {
+        let colors = quantize(image, palette.or(self.palette(canvas)), dithering);
+        let admin = UserId::admin();
+        let mut changed = 0;
+        for (i, color) in colors.into_iter().enumerate() {
+            let Some(color) = color else { continue };
+            let (x, y) = (i as u32 % image.width, i as u32 / image.width);
+            let (Ok(x), Ok(y)) = (
+                i16::try_from(top_left.x as i64 + x as i64),
+                i16::try_from(top_left.y as i64 + y as i64),
+            ) else {
+                continue;
+            };
+            let coord = Coordinate { x, y };
+            if self.pixel(canvas, coord).await == Some(color) {
+                continue;
+            }
+            if self.put(canvas, &admin, coord, color).await.is_ok() {
+                changed += 1;
+            }
+        }
+        changed
+    }
+}
+
+pub(super) async fn disconnect<W: P2Write + Unpin>(connection: &mut ActiveConnectionData<W>) {
+    connection.replaced = true;
+    if connection.write.write_all(&[0xFF, 0x00]).await.is_ok() {
+        connection.write.flush().await.ok();
+    }
+    connection.write.close().await.ok();
+}
+
+#[tokio::test]
+async fn test_revert_user() {
+    let directory = std::env::temp_dir().join(format!("p2ws-revert-test-{}", std::process::id()));
+    let mut config = crate::config::Config::default();
+    config.history.as_mut().unwrap().directory = directory.clone();
+    let users = crate::users::Users::from_toml("").unwrap();
+    let server = Server::<Vec<u8>>::new(&config, users).await.unwrap();
+    let canvas = CanvasId::DEFAULT;
+    let (alice, griefer) = (
+        UserId::new("alice".to_owned()),
+        UserId::new("griefer".to_owned()),
+    );
+    let color = |r| Color { r, g: 0, b: 0 };
+    let coord = |x| Coordinate { x, y: 0 };
+
+    server.set_pixel(canvas, &alice, coord(0), color(1)).await;
+    server.set_pixel(canvas, &alice, coord(1), color(1)).await;
+    // edits are stored with millisecond precision
+    tokio::time::sleep(Duration::from_millis(5)).await;
+    let from = SystemTime::now();
+    tokio::time::sleep(Duration::from_millis(5)).await;
+    server.set_pixel(canvas, &griefer, coord(0), color(9)).await;
+    server.set_pixel(canvas, &griefer, coord(1), color(9)).await;
+    server.set_pixel(canvas, &griefer, coord(2), color(9)).await;
+    server.set_pixel(canvas, &griefer, coord(3), color(9)).await;
+    // painted over by someone else, so it should not be reverted
+    server.set_pixel(canvas, &alice, coord(1), color(2)).await;
+    tokio::time::sleep(Duration::from_millis(5)).await;
+    let to = SystemTime::now();
+
+    let restored = server
+        .revert_user(
+            canvas,
+            &griefer,
+            from,
+            to,
+            Area::try_new(coord(0), coord(2)),
+        )
+        .await
+        .unwrap();
+    assert_eq!(restored, 2);
+    assert_eq!(server.pixel(canvas, coord(0)).await, Some(color(1)));
+    assert_eq!(server.pixel(canvas, coord(1)).await, Some(color(2)));
+    let background = config.canvases[0].1.background;
+    assert_eq!(server.pixel(canvas, coord(2)).await, Some(background));
+    // outside of the area
+    assert_eq!(server.pixel(canvas, coord(3)).await, Some(color(9)));
+
+    std::fs::remove_dir_all(&directory).unwrap();
+
+    config.history = None;
+    let users = crate::users::Users::from_toml("").unwrap();
+    let server = Server::<Vec<u8>>::new(&config, users).await.unwrap();
+    let result = server.revert_user(canvas, &griefer, from, to, None).await;
+    assert_eq!(result.unwrap_err().kind(), std::io::ErrorKind::Unsupported);
+}
+
+#[tokio::test]
+async fn test_import_image() {
+    use crate::ratelimit::PixelCooldownSettings;
+
+    let mut config = crate::config::Config {
+        history: None,
+        ..Default::default()
+    };
+    let (red, blue, black) = (
+        Color { r: 31, g: 0, b: 0 },
+        Color { r: 0, g: 0, b: 31 },
+        Color { r: 0, g: 0, b: 0 },
+    );
+    let settings = &mut config.canvases[0].1;
+    settings.palette = Some(Palette::new(vec![red, black]));
+    // only one pixel per minute, which doesn't apply to imports
+    settings.pixel_cooldown = Some(PixelCooldownSettings::new(Duration::from_secs(60)));
+    let users = crate::users::Users::from_toml("").unwrap();
+    let server = Server::<Vec<u8>>::new(&config, users).await.unwrap();
+    let canvas = CanvasId::DEFAULT;
+    let image = RgbaImage {
+        width: 4,
+        height: 1,
+        pixels: vec![
+            [255, 0, 0, 255],
+            [255, 0, 0, 0],
+            [255, 0, 0, 255],
+            [0, 0, 255, 255],
+        ],
+    };
+    let coord = |x| Coordinate { x, y: 0 };
+
+    // blue is in the given palette, but the canvas' palette rejects it
+    let palette = Palette::new(vec![red, blue]);
+    let import = async || {
+        server
+            .import_image(canvas, &image, coord(0), Some(&palette), Dithering::None)
+            .await
+    };
+    assert_eq!(import().await, 2);
+    assert_eq!(server.pixel(canvas, coord(0)).await, Some(red));
+    assert_eq!(server.pixel(canvas, coord(1)).await, None);
+    assert_eq!(server.pixel(canvas, coord(2)).await, Some(red));
+    assert_eq!(server.pixel(canvas, coord(3)).await, None);
+    // pixels which already have the right color are skipped
+    assert_eq!(import().await, 0);
+}
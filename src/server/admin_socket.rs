@@ -0,0 +1,460 @@
+use std::{
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime},
+};
+
+use tokio::{
+    io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
+    net::{UnixListener, UnixStream},
+};
+
+use crate::{
+    cli::{MAX_IMAGE_PIXELS, parse_area, parse_palette, parse_time, scaled_size},
+    data::Coordinate,
+    image::{Dithering, RgbaImage},
+    one_time_password::OneTimePasswordGenerator,
+    ratelimit::RatelimitOverride,
+    server::{CanvasId, WebsocketServer},
+    users::{Role, UserId},
+};
+
+const HELP: &str = "\
+list                                     connected clients: user, canvas, subscribed area, seconds since the last message
+users                                    all users and their roles
+add-user <user> <pin> [role]             adds a user with a static OTP until the server restarts or reloads the users file
+remove-user <user>                       removes a user until the server restarts and disconnects them
+reload-users                             reads the users file again and disconnects removed users
+ratelimit <user> <messages/s> [burst] [drop|block]
+                                         overrides the user's ratelimit from their next connection or canvas change
+ratelimit <user> default                 removes the user's ratelimit overrides
+kick <user>
+mute <user> [seconds]
+unmute <user>
+ban <user> [seconds]                     bans are saved in the bans file
+unban <user>
+revert <canvas> <user> <from> <to> [area]
+                                         reverts the user's edits between two unix timestamps
+import <canvas> <file> <x,y> [none|ordered|floyd-steinberg] [palette]
+                                         paints a PNG or PPM image with its top left corner at x,y,
+                                         using only the palette's colors, like 31,0,0/0,0,0
+snapshot <canvas> <file> [area]          saves an area (by default every painted pixel) as a PNG image
+shutdown
+help
+
+<canvas> is a canvas' name, or - for the default canvas. Areas are left,top,right,bottom.";
+
+/// Accepts connections on a Unix socket, through which administrators can control the running server.
+///
+/// The protocol is line-based: each line is a command with whitespace-separated arguments,
+/// and the server answers with any number of lines followed by `ok` or `error: <message>`.
+pub async fn serve_admin_socket(server: WebsocketServer, path: PathBuf) -> std::io::Result<()> {
+    // remove the socket of a previous run, which would prevent binding
+    if tokio::fs::symlink_metadata(&path)
+        .await
+        .is_ok_and(|metadata| std::os::unix::fs::FileTypeExt::is_socket(&metadata.file_type()))
+    {
+        tokio::fs::remove_file(&path).await?;
+    }
+    let listener = bind_private(&path).await?;
+    loop {
+        let (connection, _) = listener.accept().await?;
+        tokio::task::spawn(handle_admin_connection(server.clone(), connection));
+    }
+}
+
+/// Binds the socket so that only the user running the server can connect.
+///
+/// The socket is created in a new directory which only that user can access, made private,
+/// and only then moved to `path`, so it is never reachable with the default permissions.
+async fn bind_private(path: &Path) -> std::io::Result<UnixListener> {
+    let parent = match path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => Path::new("."),
+    };
+    let directory = parent.join(format!(".p2ws-admin-{}", std::process::id()));
+    tokio::fs::DirBuilder::new()
+        .mode(0o700)
+        .create(&directory)
+        .await?;
+    let temporary_path = directory.join("socket");
+    let bind = async {
+        let listener = UnixListener::bind(&temporary_path)?;
+        tokio::fs::set_permissions(
+            &temporary_path,
+            std::os::unix::fs::PermissionsExt::from_mode(0o600),
+        )
+        .await?;
+        // unlike binding, renaming would replace an existing file
+        if tokio::fs::symlink_metadata(path).await.is_ok() {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::AlreadyExists,
+                format!("{} already exists", path.display()),
+            ));
+        }
+        tokio::fs::rename(&temporary_path, path).await?;
+        Ok(listener)
+    };
+    let result = bind.await;
+    tokio::fs::remove_file(&temporary_path).await.ok();
+    tokio::fs::remove_dir(&directory).await?;
+    result
+}
+
+async fn handle_admin_connection(server: WebsocketServer, connection: UnixStream) {
+    let (read, mut write) = connection.into_split();
+    let mut lines = BufReader::new(read).lines();
+    while let Ok(Some(line)) = lines.next_line().await {
+        let args = line.split_whitespace().collect::<Vec<_>>();
+        if args.is_empty() {
+            continue;
+        }
+        let response = match run_command(&server, &args).await {
+            Ok(output) => output + "ok\n",
+            Err(e) => format!("error: {e}\n"),
+        };
+        if write.write_all(response.as_bytes()).await.is_err() {
+            return;
+        }
+        if args[0] == "shutdown" {
+            server.request_shutdown();
+            return;
+        }
+    }
+}
+
+/// Returns the lines to send before `ok`.
+async fn run_command(server: &WebsocketServer, args: &[&str]) -> Result<String, String> {
+    let user = |i: usize| -> Result<UserId, String> {
+        args.get(i)
+            .map(|user| UserId::new((*user).to_owned()))
+            .ok_or_else(|| "missing user".to_owned())
+    };
+    let canvas = |i: usize| -> Result<CanvasId, String> {
+        match args.get(i) {
+            Some(&"-") => Ok(CanvasId::DEFAULT),
+            Some(name) => server
+                .canvas_by_name(name)
+                .ok_or_else(|| format!("there is no canvas named {name:?}")),
+            None => Err("missing canvas".to_owned()),
+        }
+    };
+    let seconds = |i: usize| -> Result<Option<Duration>, String> {
+        args.get(i)
+            .map(|seconds| {
+                seconds
+                    .parse::<f64>()
+                    .ok()
+                    .filter(|seconds| *seconds > 0.0)
+                    .and_then(|seconds| Duration::try_from_secs_f64(seconds).ok())
+                    // mutes and bans end at now + the duration
+                    .filter(|duration| SystemTime::now().checked_add(*duration).is_some())
+                    .ok_or_else(|| format!("invalid number of seconds {seconds:?}"))
+            })
+            .transpose()
+    };
+    let mut output = String::new();
+    match args[0] {
+        "help" => output = format!("{HELP}\n"),
+        "list" => {
+            for connection in server.connections().await {
+                let area = match connection.subscribed_area {
+                    Some(area) => format!(
+                        "{},{},{},{}",
+                        area.left(),
+                        area.top(),
+                        area.right(),
+                        area.bottom()
+                    ),
+                    None => "-".to_owned(),
+                };
+                output += &format!(
+                    "{} {} {area} {:.1}\n",
+                    connection.user.username(),
+                    server.canvas_name(connection.canvas),
+                    connection.idle.as_secs_f64()
+                );
+            }
+        }
+        "users" => {
+            for (user, role) in server.users().list().await? {
+                output += &format!("{} {}\n", user.username(), role.name());
+            }
+        }
+        "add-user" => {
+            let user = user(1)?;
+            if !UserId::is_valid_username(user.username()) {
+                return Err(
+                    "usernames must be 1 to 256 bytes long and can't start with `@`".to_owned(),
+                );
+            }
+            let pin = args
+                .get(2)
+                .and_then(|pin| pin.parse::<u32>().ok())
+                .ok_or("missing or invalid pin")?;
+            let role = match args.get(3) {
+                Some(name) => {
+                    Role::from_name(name).ok_or_else(|| format!("unknown role {name:?}"))?
+                }
+                None => Role::default(),
+            };
+            if !server
+                .users()
+                .add(user, OneTimePasswordGenerator::Static(pin), role)
+                .await?
+            {
+                return Err("the user already exists".to_owned());
+            }
+        }
+        "remove-user" => {
+            if !server.remove_user(&user(1)?).await? {
+                return Err("no such user".to_owned());
+            }
+        }
+        "reload-users" => {
+            let reloaded = server.reload_users().await?;
+            for (change, users) in [
+                ("added", reloaded.added),
+                ("updated", reloaded.updated),
+                ("removed", reloaded.removed),
+            ] {
+                for user in users {
+                    output += &format!("{change} {}\n", user.username());
+                }
+            }
+        }
+        "ratelimit" => {
+            let user = user(1)?;
+            let ratelimit = match args.get(2) {
+                Some(&"default") => RatelimitOverride::default(),
+                Some(messages_per_second) => {
+                    let time_per_message = messages_per_second
+                        .parse::<f64>()
+                        .ok()
+                        .filter(|n| n.is_finite() && *n > 0.0)
+                        .ok_or("messages per second must be a positive number")
+                        .and_then(|n| {
+                            Duration::try_from_secs_f64(1.0 / n)
+                                .map_err(|_| "messages per second is too small")
+                        })?;
+                    RatelimitOverride {
+                        time_per_message: Some(time_per_message),
+                        burst_size: args
+                            .get(3)
+                            .map(|burst| burst.parse::<u32>().map_err(|e| e.to_string()))
+                            .transpose()?,
+                        drop_instead_of_blocking: match args.get(4) {
+                            Some(&"drop") => Some(true),
+                            Some(&"block") => Some(false),
+                            Some(other) => {
+                                return Err(format!("expected drop or block, not {other:?}"));
+                            }
+                            None => None,
+                        },
+                    }
+                }
+                None => return Err("missing ratelimit".to_owned()),
+            };
+            if !server.users().set_ratelimit(&user, ratelimit).await? {
+                return Err("no such user".to_owned());
+            }
+        }
+        "kick" => {
+            if !server.kick(&user(1)?).await {
+                return Err("the user is not connected".to_owned());
+            }
+        }
+        "mute" => server.mute(&user(1)?, seconds(2)?).await,
+        "unmute" => {
+            if !server.unmute(&user(1)?).await {
+                return Err("the user is not muted".to_owned());
+            }
+        }
+        "ban" => server
+            .ban(&user(1)?, seconds(2)?)
+            .await
+            .map_err(|e| format!("could not save the bans: {e}"))?,
+        "unban" => {
+            if !server
+                .unban(&user(1)?)
+                .await
+                .map_err(|e| format!("could not save the bans: {e}"))?
+            {
+                return Err("the user is not banned".to_owned());
+            }
+        }
+        "revert" => {
+            let (canvas, user) = (canvas(1)?, user(2)?);
+            let from = parse_time(args.get(3).ok_or("missing start time")?)?;
+            let to = parse_time(args.get(4).ok_or("missing end time")?)?;
+            let area = args.get(5).map(|area| parse_area(area)).transpose()?;
+            let restored = server
+                .revert_user(canvas, &user, from, to, area)
+                .await
+                .map_err(|e| match e.kind() {
+                    std::io::ErrorKind::Unsupported => e.to_string(),
+                    _ => format!("could not read the history: {e}"),
+                })?;
+            output = format!("restored {restored} pixels\n");
+        }
+        "import" => {
+            let canvas = canvas(1)?;
+            let file = args.get(2).ok_or("missing file")?;
+            let (x, y) = args
+                .get(3)
+                .and_then(|position| position.split_once(','))
+                .and_then(|(x, y)| Some((x.parse().ok()?, y.parse().ok()?)))
+                .ok_or("missing or invalid position, expected x,y")?;
+            let dithering = match args.get(4) {
+                None | Some(&"none") => Dithering::None,
+                Some(&"ordered") => Dithering::Ordered,
+                Some(&"floyd-steinberg") => Dithering::FloydSteinberg,
+                Some(other) => return Err(format!("unknown dithering {other:?}")),
+            };
+            let palette = args
+                .get(5)
+                .map(|palette| parse_palette(palette))
+                .transpose()?;
+            let bytes = tokio::fs::read(file)
+                .await
+                .map_err(|e| format!("could not read {file}: {e}"))?;
+            let image = RgbaImage::decode(&bytes)?;
+            let changed = server
+                .import_image(
+                    canvas,
+                    &image,
+                    Coordinate { x, y },
+                    palette.as_ref(),
+                    dithering,
+                )
+                .await;
+            output = format!("changed {changed} pixels\n");
+        }
+        "snapshot" => {
+            let canvas = canvas(1)?;
+            let file = Path::new(args.get(2).ok_or("missing file")?);
+            let area = match args.get(3) {
+                Some(area) => parse_area(area)?,
+                None => server
+                    .painted_area(canvas)
+                    .await
+                    .ok_or("no pixels have been painted")?,
+            };
+            scaled_size(area, 1).map_err(|_| {
+                format!(
+                    "the area is too large, snapshots can have at most {MAX_IMAGE_PIXELS} pixels"
+                )
+            })?;
+            let image = server.render(canvas, area, None).await;
+            let bytes = image
+                .encode_png()
+                .map_err(|e| format!("could not encode the image: {e}"))?;
+            tokio::fs::write(file, bytes)
+                .await
+                .map_err(|e| format!("could not write {}: {e}", file.display()))?;
+        }
+        "shutdown" => {}
+        other => return Err(format!("unknown command {other:?}, try help")),
+    }
+    Ok(output)
+}
+
+#[tokio::test]
+async fn test_admin_commands() {
+    use crate::{config::Config, users::Users};
+
+    let config = Config {
+        history: None,
+        ..Default::default()
+    };
+    let server = WebsocketServer::new(&config, Users::from_toml("").unwrap())
+        .await
+        .unwrap();
+    let run = async |line: &str| {
+        let args = line.split_whitespace().collect::<Vec<_>>();
+        run_command(&server, &args).await
+    };
+
+    assert!(run("help").await.unwrap().starts_with("list "));
+    assert!(
+        run("frobnicate")
+            .await
+            .unwrap_err()
+            .contains("unknown command")
+    );
+    assert_eq!(run("kick").await.unwrap_err(), "missing user");
+    assert!(run("add-user @admin 1").await.is_err());
+    let long_name = "a".repeat(257);
+    assert!(run(&format!("add-user {long_name} 1")).await.is_err());
+    assert_eq!(
+        run("add-user alice").await.unwrap_err(),
+        "missing or invalid pin"
+    );
+    assert!(run("add-user alice 1 emperor").await.is_err());
+    assert_eq!(run("add-user alice 1 moderator").await, Ok(String::new()));
+    assert!(run("add-user alice 1").await.is_err());
+    assert_eq!(run("users").await.unwrap(), "alice moderator\n");
+    assert!(run("ratelimit alice 0").await.is_err());
+    assert_eq!(
+        run("ratelimit alice 1e-20").await.unwrap_err(),
+        "messages per second is too small"
+    );
+    assert!(run("mute alice 1e300").await.is_err());
+    assert!(run("ratelimit alice 10 5 sometimes").await.is_err());
+    assert_eq!(run("ratelimit alice 10 5 drop").await, Ok(String::new()));
+    assert_eq!(
+        run("ratelimit bob default").await.unwrap_err(),
+        "no such user"
+    );
+    assert!(run("mute alice -1").await.is_err());
+    assert_eq!(
+        run("kick alice").await.unwrap_err(),
+        "the user is not connected"
+    );
+    assert!(
+        run("revert nowhere alice 0 1")
+            .await
+            .unwrap_err()
+            .contains("no canvas named")
+    );
+    assert_eq!(
+        run("revert - alice 0 1").await.unwrap_err(),
+        "history is disabled for this canvas"
+    );
+    assert!(run("import - image.png 1").await.is_err());
+    assert!(run("import - image.png 0,0 none 32,0,0").await.is_err());
+    assert_eq!(
+        run("snapshot - image.png").await.unwrap_err(),
+        "no pixels have been painted"
+    );
+    assert!(
+        run("snapshot - image.png -32768,-32768,32767,32767")
+            .await
+            .unwrap_err()
+            .starts_with("the area is too large")
+    );
+    assert_eq!(run("remove-user alice").await, Ok(String::new()));
+    assert_eq!(run("remove-user alice").await.unwrap_err(), "no such user");
+
+    // the socket can only be used by the user running the server
+    let directory = std::env::temp_dir().join(format!("p2ws-admin-test-{}", std::process::id()));
+    std::fs::create_dir_all(&directory).unwrap();
+    let path = directory.join("admin.sock");
+    tokio::task::spawn(serve_admin_socket(server.clone(), path.clone()));
+    let mut connection = loop {
+        if let Ok(connection) = UnixStream::connect(&path).await {
+            break connection;
+        }
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    };
+    let mode =
+        std::os::unix::fs::PermissionsExt::mode(&std::fs::metadata(&path).unwrap().permissions());
+    assert_eq!(mode & 0o777, 0o600);
+    connection.write_all(b"users\n").await.unwrap();
+    let mut response = String::new();
+    BufReader::new(connection)
+        .read_line(&mut response)
+        .await
+        .unwrap();
+    assert_eq!(response, "ok\n");
+    std::fs::remove_dir_all(&directory).unwrap();
+}
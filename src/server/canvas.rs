@@ -0,0 +1,100 @@
+use std::collections::{BTreeMap, HashMap};
+
+use tokio::{
+    sync::{MappedMutexGuard, Mutex, MutexGuard, mpsc},
+    task::JoinHandle,
+};
+
+use crate::{
+    canvas::CanvasSettings,
+    data::{Color, Coordinate},
+    history::{Edit, HistoryLog},
+    ratelimit::PixelCooldowns,
+    server::tiles::{TileCache, TileIndex},
+};
+
+/// Identifies one of the server's canvases.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct CanvasId(pub(super) usize);
+
+impl CanvasId {
+    /// The canvas used by clients which haven't selected a canvas.
+    pub const DEFAULT: Self = Self(0);
+}
+
+/// A canvas and the state the server keeps for it.
+pub struct Canvas {
+    pub name: String,
+    pub settings: CanvasSettings,
+    /// The color of every pixel which has been painted.
+    /// NOTE: Queue edits in `queued_edits` and lock `tile_index` while holding this lock,
+    /// so that changes are applied in the same order.
+    pub pixels: Mutex<HashMap<Coordinate, Color>>,
+    /// What the tiles show, updated whenever a pixel changes.
+    pub tile_index: Mutex<TileIndex>,
+    /// NOTE: Only one tile of the canvas is rendered at a time, while this is locked.
+    /// Lock this before locking `tile_index`.
+    pub tiles: Mutex<TileCache>,
+    /// Edits are appended to the history after `pixels` is unlocked, in the order in which they are queued.
+    /// Set if the history is enabled.
+    pub queued_edits: Option<mpsc::UnboundedSender<Edit>>,
+    /// Use `lock_history` to append the queued edits first.
+    history: Option<Mutex<QueuedHistory>>,
+    /// Only used if `settings.pixel_cooldown` is set.
+    /// Not part of the connection data so that reconnecting doesn't refill a user's stock.
+    pub pixel_cooldowns: Mutex<PixelCooldowns>,
+    /// Recently modified pixels
+    pub modified_pixels: Mutex<BTreeMap<Coordinate, Color>>,
+    /// used to batch updates together so that more groups can be built, also appends the queued edits to the history
+    pub update_task: Mutex<Option<JoinHandle<()>>>,
+}
+
+impl Canvas {
+    pub fn new(
+        name: String,
+        settings: CanvasSettings,
+        pixels: HashMap<Coordinate, Color>,
+        history: Option<HistoryLog>,
+    ) -> Self {
+        let (queued_edits, history) = match history {
+            Some(log) => {
+                let (sender, queued) = mpsc::unbounded_channel();
+                (
+                    Some(sender),
+                    Some(Mutex::new(QueuedHistory { log, queued })),
+                )
+            }
+            None => (None, None),
+        };
+        Self {
+            name,
+            settings,
+            tile_index: Mutex::new(TileIndex::new(&pixels)),
+            pixels: Mutex::new(pixels),
+            tiles: Default::default(),
+            queued_edits,
+            history,
+            pixel_cooldowns: Default::default(),
+            modified_pixels: Default::default(),
+            update_task: Default::default(),
+        }
+    }
+
+    /// Appends the queued edits to the history and returns it, `None` if the history is disabled.
+    /// Edits which can't be appended are logged and dropped.
+    pub async fn lock_history(&self) -> Option<MappedMutexGuard<'_, HistoryLog>> {
+        let mut history = self.history.as_ref()?.lock().await;
+        while let Ok(edit) = history.queued.try_recv() {
+            if let Err(e) = history.log.append(&edit).await {
+                tracing::error!(canvas = self.name, "Could not write to the history: {e}");
+            }
+        }
+        Some(MutexGuard::map(history, |history| &mut history.log))
+    }
+}
+
+/// A history log and the edits which haven't been appended to it yet
+struct QueuedHistory {
+    log: HistoryLog,
+    queued: mpsc::UnboundedReceiver<Edit>,
+}
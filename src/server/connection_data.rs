@@ -1,10 +1,16 @@
 use tokio::time::Instant;
 
-use crate::{data::Area, server::P2Write};
+use crate::{
+    data::Area,
+    protocol::Extensions,
+    server::{CanvasId, P2Write},
+};
 
 pub struct ActiveConnectionData<W: P2Write + Unpin> {
     pub replaced: bool,
+    pub canvas: CanvasId,
     pub subscribed_area: Option<Area>,
+    pub extensions: Extensions,
     pub write: W,
     pub last_action: Instant,
 }
@@ -12,7 +18,9 @@ impl<W: P2Write + Unpin> ActiveConnectionData<W> {
     pub fn new(write: W) -> Self {
         Self {
             replaced: false,
+            canvas: CanvasId::DEFAULT,
             subscribed_area: None,
+            extensions: Extensions::default(),
             write,
             last_action: Instant::now(),
         }
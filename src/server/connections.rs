@@ -1,4 +1,4 @@
-use std::{collections::VecDeque, convert::Infallible, sync::Arc};
+use std::{collections::VecDeque, sync::Arc, time::Duration};
 
 use futures_util::{
     SinkExt, StreamExt,
@@ -6,67 +6,180 @@ use futures_util::{
 };
 use tokio::{
     net::{TcpListener, TcpStream, ToSocketAddrs},
-    sync::Mutex,
+    sync::{Mutex, Semaphore},
+    task::JoinSet,
 };
 use tokio_tungstenite::WebSocketStream;
+use tracing::Instrument;
 
-use crate::{
-    server::{
-        P2Read, P2Write, Server,
-        connection_data::ActiveConnectionData,
-        handle_connection::{Disconnected, handle_connection},
-    },
-    users::Users,
+use crate::server::{
+    P2Read, P2Write, Server, ServerStats,
+    connection_data::ActiveConnectionData,
+    handle_connection::{Disconnected, HandleConnectionError, handle_connection},
+    http::{handle_http_request, is_plain_http_request, reject_http_request},
 };
 
 #[derive(Debug)]
 pub enum AcceptConnectionsError {
-    CouldNotAcceptMoreConnections(tokio::io::Error),
-    CouldNotAcceptAnyConnections(tokio::io::Error),
+    CouldNotBind(tokio::io::Error),
+    /// The listener can't accept any more connections
+    ListenerFailed(tokio::io::Error),
 }
 
 pub type WebsocketServer = Server<WritableWebsocketStream>;
 
+/// How long connections may take to close after a shutdown has been requested, before they are aborted
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(5);
+/// The longest time to wait before accepting again after `accept` failed
+const MAX_ACCEPT_BACKOFF: Duration = Duration::from_secs(1);
+/// How many rejected connections may be answered at the same time.
+/// More are closed immediately, so that a flood of connections can't use up the file descriptors.
+const MAX_PENDING_REJECTIONS: usize = 64;
+
 impl WebsocketServer {
+    /// Accepts connections until a shutdown is requested.
+    /// Then all clients are disconnected, including those which are still authenticating,
+    /// and their connections get `SHUTDOWN_TIMEOUT` to close.
+    /// Connections which are still open after that, like HTTP requests, are aborted.
     pub async fn accept_connections(
         self,
         bind_addr: impl ToSocketAddrs,
-        users: Users,
-    ) -> Result<Infallible, AcceptConnectionsError> {
-        let socket = TcpListener::bind(bind_addr).await.unwrap();
-        let mut accepted_connections_counter: u128 = 0;
+    ) -> Result<(), AcceptConnectionsError> {
+        let socket = TcpListener::bind(bind_addr)
+            .await
+            .map_err(AcceptConnectionsError::CouldNotBind)?;
+        for canvas in self.canvas_ids() {
+            tokio::task::spawn(self.clone().announce_bounds_changes(canvas));
+        }
+        let mut connections = JoinSet::new();
+        let mut backoff = Duration::ZERO;
+        let mut connection_id: u64 = 0;
+        let rejections = Arc::new(Semaphore::new(MAX_PENDING_REJECTIONS));
         loop {
-            match socket.accept().await {
-                Ok((connection, _)) => {
-                    accepted_connections_counter = accepted_connections_counter.saturating_add(1);
-                    let users = users.clone();
+            let accepted = tokio::select! {
+                accepted = socket.accept() => accepted,
+                Some(_) = connections.join_next() => continue,
+                () = self.shutdown_requested() => break,
+            };
+            match accepted {
+                Ok((connection, peer)) => {
+                    backoff = Duration::ZERO;
+                    connection_id += 1;
+                    // closed connections count until they have been removed
+                    while connections.try_join_next().is_some() {}
+                    if self
+                        .max_connections
+                        .is_some_and(|max_connections| connections.len() >= max_connections)
+                    {
+                        ServerStats::count(&self.stats.rejected_connections, 1);
+                        tracing::warn!(
+                            connection = connection_id,
+                            %peer,
+                            "Too many open connections, rejecting"
+                        );
+                        if let Ok(permit) = Arc::clone(&rejections).try_acquire_owned() {
+                            tokio::task::spawn(async move {
+                                reject_http_request(connection).await;
+                                drop(permit);
+                            });
+                        }
+                        continue;
+                    }
                     let server = self.clone();
-                    tokio::task::spawn(handle_tcp_connection(connection, users, server));
+                    let span = tracing::info_span!(
+                        "connection",
+                        id = connection_id,
+                        %peer,
+                        user = tracing::field::Empty
+                    );
+                    connections.spawn(handle_tcp_connection(connection, server).instrument(span));
+                }
+                Err(e) if is_fatal_accept_error(&e) => {
+                    return Err(AcceptConnectionsError::ListenerFailed(e));
                 }
                 Err(e) => {
-                    return if accepted_connections_counter == 0 {
-                        Err(AcceptConnectionsError::CouldNotAcceptAnyConnections(e))
-                    } else {
-                        Err(AcceptConnectionsError::CouldNotAcceptMoreConnections(e))
-                    };
+                    ServerStats::count(&self.stats.accept_errors, 1);
+                    tracing::debug!("Could not accept a connection: {e}");
+                    // errors like EMFILE persist until some connections are closed, so wait before trying again
+                    if !is_connection_error(&e) {
+                        backoff = next_accept_backoff(backoff);
+                        tracing::warn!(
+                            "Could not accept a connection, trying again in {backoff:?}: {e}"
+                        );
+                        tokio::select! {
+                            () = tokio::time::sleep(backoff) => {}
+                            () = self.shutdown_requested() => break,
+                        }
+                    }
                 }
             }
         }
+
+        drop(socket);
+        self.disconnect_all().await;
+        let all_closed = async { while connections.join_next().await.is_some() {} };
+        if tokio::time::timeout(SHUTDOWN_TIMEOUT, all_closed)
+            .await
+            .is_err()
+        {
+            tracing::warn!(
+                "Aborting {} connections which did not close in time.",
+                connections.len()
+            );
+        }
+        Ok(())
     }
 }
 
-async fn handle_tcp_connection(connection: TcpStream, users: Users, server: WebsocketServer) {
-    if let Ok(connection) = tokio_tungstenite::accept_async(connection).await {
-        let (write, read) = connection.split();
-        let (read, write) = (
-            ReadableWebsocketStream(read, Default::default(), None),
-            Arc::new(Mutex::new(ActiveConnectionData::new(
-                WritableWebsocketStream(write, Default::default()),
-            ))),
-        );
-        match handle_connection(users, server, read, write).await {
-            Ok(Disconnected) => {}
-            Err(_e) => {}
+/// Doubles the time to wait after each consecutive error, starting at 10 ms, up to `MAX_ACCEPT_BACKOFF`.
+fn next_accept_backoff(backoff: Duration) -> Duration {
+    (backoff * 2).clamp(Duration::from_millis(10), MAX_ACCEPT_BACKOFF)
+}
+
+/// Errors which only affect the connection which was being accepted
+fn is_connection_error(e: &tokio::io::Error) -> bool {
+    use tokio::io::ErrorKind;
+    matches!(
+        e.kind(),
+        ErrorKind::ConnectionRefused
+            | ErrorKind::ConnectionAborted
+            | ErrorKind::ConnectionReset
+            | ErrorKind::Interrupted
+    )
+}
+
+/// Errors after which the listener can't be used anymore.
+/// Everything else, including running out of file descriptors or memory, may go away after a while.
+fn is_fatal_accept_error(e: &tokio::io::Error) -> bool {
+    use tokio::io::ErrorKind;
+    matches!(e.kind(), ErrorKind::InvalidInput | ErrorKind::Unsupported)
+}
+
+async fn handle_tcp_connection(connection: TcpStream, server: WebsocketServer) {
+    if is_plain_http_request(&connection).await {
+        return handle_http_request(connection, server).await;
+    }
+    let connection = match tokio_tungstenite::accept_async(connection).await {
+        Ok(connection) => connection,
+        Err(e) => {
+            tracing::debug!("WebSocket handshake failed: {e}");
+            return;
+        }
+    };
+    let (write, read) = connection.split();
+    let (read, write) = (
+        ReadableWebsocketStream(read, Default::default(), None),
+        Arc::new(Mutex::new(ActiveConnectionData::new(
+            WritableWebsocketStream(write, Default::default()),
+        ))),
+    );
+    let stats = Arc::clone(&server.stats);
+    match handle_connection(server, read, write).await {
+        Ok(Disconnected) => tracing::info!("Disconnected on request"),
+        Err(HandleConnectionError::IoError(e)) => tracing::info!("Disconnected: {e}"),
+        Err(HandleConnectionError::AuthenticationError(e)) => {
+            ServerStats::count(stats.auth_failures(&e), 1);
+            tracing::info!(reason = ?e, "Authentication failed")
         }
     }
 }
@@ -82,6 +195,7 @@ pub struct WritableWebsocketStream(
 );
 
 impl P2Read for ReadableWebsocketStream {
+    #[allow(clippy::len_zero)]
     async fn read_exact(&mut self, mut buf: &mut [u8]) -> tokio::io::Result<()> {
         if !self.1.is_empty() {
             // take as many bytes as possible from `self.1` and put them into `buf` immediately
@@ -125,6 +239,13 @@ impl P2Read for ReadableWebsocketStream {
     }
 }
 
+impl WritableWebsocketStream {
+    /// Bytes which have been written but not flushed yet
+    pub fn queued_bytes(&self) -> usize {
+        self.1.len()
+    }
+}
+
 impl P2Write for WritableWebsocketStream {
     async fn write_all(&mut self, buf: &[u8]) -> tokio::io::Result<()> {
         self.1.extend(buf);
@@ -135,6 +256,7 @@ impl P2Write for WritableWebsocketStream {
         }
     }
 
+    #[allow(clippy::redundant_closure)]
     async fn flush(&mut self) -> tokio::io::Result<()> {
         self.0
             .send(tokio_tungstenite::tungstenite::Message::Binary(
@@ -145,7 +267,147 @@ impl P2Write for WritableWebsocketStream {
         self.0.flush().await.map_err(|e| std::io::Error::other(e))
     }
 
+    #[allow(clippy::redundant_closure)]
     async fn close(&mut self) -> tokio::io::Result<()> {
         self.0.close().await.map_err(|e| std::io::Error::other(e))
     }
 }
+
+#[tokio::test]
+async fn test_connection_limit() {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    use crate::{config::Config, users::Users};
+
+    let config = Config {
+        history: None,
+        max_connections: Some(1),
+        ..Default::default()
+    };
+    let server = WebsocketServer::new(&config, Users::from_toml("").unwrap())
+        .await
+        .unwrap();
+    let address = TcpListener::bind("127.0.0.1:0")
+        .await
+        .unwrap()
+        .local_addr()
+        .unwrap();
+    tokio::task::spawn(server.clone().accept_connections(address));
+    let request = async |path: &str| {
+        let mut connection = TcpStream::connect(address).await.unwrap();
+        connection
+            .write_all(format!("GET {path} HTTP/1.1\r\n\r\n").as_bytes())
+            .await
+            .unwrap();
+        let mut response = String::new();
+        connection.read_to_string(&mut response).await.unwrap();
+        response
+    };
+
+    // an idle connection, which hasn't sent a request yet
+    let mut idle = loop {
+        if let Ok(connection) = TcpStream::connect(address).await {
+            break connection;
+        }
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    };
+    // wait until the idle connection has been accepted
+    while !request("/metrics")
+        .await
+        .starts_with("HTTP/1.1 503 Service Unavailable\r\n")
+    {
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+    let rejected = request("/metrics").await;
+    assert!(rejected.contains("\r\nRetry-After: 5\r\n"));
+    assert!(ServerStats::get(&server.stats.rejected_connections) >= 2);
+
+    idle.write_all(b"GET /nothing HTTP/1.1\r\n\r\n")
+        .await
+        .unwrap();
+    idle.read_to_end(&mut Vec::new()).await.unwrap();
+    drop(idle);
+    let mut response = request("/metrics").await;
+    for _ in 0..100 {
+        if response.starts_with("HTTP/1.1 200 OK\r\n") {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        response = request("/metrics").await;
+    }
+    assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+    server.request_shutdown();
+
+    let mut backoff = Duration::ZERO;
+    let mut backoffs = Vec::new();
+    for _ in 0..10 {
+        backoff = next_accept_backoff(backoff);
+        backoffs.push(backoff.as_millis());
+    }
+    assert_eq!(backoffs, [10, 20, 40, 80, 160, 320, 640, 1000, 1000, 1000]);
+    let emfile = std::io::Error::from_raw_os_error(24);
+    assert!(!is_connection_error(&emfile) && !is_fatal_accept_error(&emfile));
+    assert!(is_connection_error(
+        &std::io::ErrorKind::ConnectionAborted.into()
+    ));
+}
+
+#[tokio::test]
+async fn test_shutdown() {
+    use tokio_tungstenite::tungstenite::Message;
+
+    use crate::{config::Config, users::Users};
+
+    let config = Config {
+        history: None,
+        ..Default::default()
+    };
+    let users = Users::from_toml("painter = { otp.Static = 1 }").unwrap();
+    let server = WebsocketServer::new(&config, users).await.unwrap();
+    let address = TcpListener::bind("127.0.0.1:0")
+        .await
+        .unwrap()
+        .local_addr()
+        .unwrap();
+    let accepting = tokio::task::spawn(server.clone().accept_connections(address));
+    let mut painter = loop {
+        if let Ok((client, _)) = tokio_tungstenite::connect_async(format!("ws://{address}")).await {
+            break client;
+        }
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    };
+    let mut authentication = vec![0xFF, 0xA0, 6];
+    authentication.extend_from_slice(b"painter");
+    authentication.extend_from_slice(&[0x00, 0x00, 0x00, 0x01]);
+    painter
+        .send(Message::Binary(authentication.into()))
+        .await
+        .unwrap();
+    let (mut pending, _) = tokio_tungstenite::connect_async(format!("ws://{address}"))
+        .await
+        .unwrap();
+    while server.connections().await.is_empty()
+        || ServerStats::get(&server.stats.pending_authentications) < 1
+    {
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+
+    server.request_shutdown();
+    for client in [&mut painter, &mut pending] {
+        let mut disconnect_request = None;
+        while let Some(Ok(message)) = client.next().await {
+            if let Message::Binary(message) = message {
+                disconnect_request = Some(message);
+            }
+        }
+        assert_eq!(disconnect_request.as_deref(), Some(&[0xFF, 0x00][..]));
+    }
+    tokio::time::timeout(SHUTDOWN_TIMEOUT, accepting)
+        .await
+        .expect("the server didn't shut down in time")
+        .unwrap()
+        .unwrap();
+    assert!(server.connections().await.is_empty());
+    assert_eq!(ServerStats::get(&server.stats.pending_authentications), 0);
+    assert!(TcpStream::connect(address).await.is_err());
+}
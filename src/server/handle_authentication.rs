@@ -1,26 +1,88 @@
 use crate::{
-    server::P2Read,
+    server::{
+        P2Read,
+        sessions::{SIGNATURE_LENGTH, SessionToken, Sessions, decode_hex},
+    },
     users::{UserId, Users},
 };
 
+#[derive(Debug)]
 pub enum AuthenticationError {
     UsernameNotUtf8,
     NoSuchUser(String),
     InvalidOneTimePassword,
+    /// The user is banned until the given time, or forever if it is `None`.
+    Banned {
+        until: Option<std::time::SystemTime>,
+    },
+    SpectatorsNotAllowed,
+    TooManySpectators,
+    /// The session token is invalid, has expired or has been revoked, or session tokens are disabled.
+    InvalidSessionToken,
+    /// The user store could not be read or written.
+    UserStore(String),
+}
+
+/// What a client has asked to connect as.
+pub enum Login {
+    User(UserId),
+    /// An anonymous, read-only connection
+    Spectator,
 }
 
 pub async fn handle_authentication(
     users: Users,
+    sessions: Option<&Sessions>,
     connection: &mut (impl P2Read + Unpin),
-) -> tokio::io::Result<Result<UserId, AuthenticationError>> {
-    let mut buf_len = [0u8; 3];
+) -> tokio::io::Result<Result<Login, AuthenticationError>> {
+    let mut buf_message_type = [0u8; 2];
+    connection.read_exact(&mut buf_message_type).await?;
+    if buf_message_type[1] == 0xA2 {
+        // Message: Spectate
+        return Ok(Ok(Login::Spectator));
+    }
+    if buf_message_type[1] == 0xA1 {
+        // Message: Resume
+        // the token is in hexadecimal, and starts with the length of the username
+        let mut buf_len = [0u8; 2];
+        connection.read_exact(&mut buf_len).await?;
+        let Some(&[len]) = decode_hex(&buf_len).as_deref() else {
+            return Ok(Err(AuthenticationError::InvalidSessionToken));
+        };
+        let username_len = len as usize + 1;
+        let mut buf_message = vec![0u8; (username_len + 8 + SIGNATURE_LENGTH) * 2];
+        connection.read_exact(&mut buf_message).await?;
+        let Some(mut buf_message) = decode_hex(&buf_message) else {
+            return Ok(Err(AuthenticationError::InvalidSessionToken));
+        };
+        let signature = buf_message.split_off(username_len + 8);
+        let issued_at = buf_message.split_off(username_len);
+        let token = SessionToken {
+            username: buf_message,
+            issued_at: u64::from_be_bytes(issued_at.try_into().unwrap()),
+            signature: signature.try_into().unwrap(),
+        };
+        let Some(user) = (match sessions {
+            Some(sessions) => sessions.verify(&token).await,
+            None => None,
+        }) else {
+            return Ok(Err(AuthenticationError::InvalidSessionToken));
+        };
+        return Ok(users.resume_session(user).await.map(Login::User));
+    }
+    let mut buf_len = [0u8];
     connection.read_exact(&mut buf_len).await?;
-    let username_len = buf_len[2] as usize + 1;
+    let username_len = buf_len[0] as usize + 1;
     let mut buf_message = vec![0u8; username_len + 4];
     connection.read_exact(&mut buf_message).await?;
-    Ok(handle_authentication_message(users, username_len, buf_message).await)
+    Ok(
+        handle_authentication_message(users, username_len, buf_message)
+            .await
+            .map(Login::User),
+    )
 }
 
+#[allow(clippy::identity_op)]
 async fn handle_authentication_message(
     users: Users,
     username_len: usize,
@@ -47,9 +109,46 @@ fn byte_to_digits(byte: u8) -> u32 {
 }
 
 #[test]
+#[allow(clippy::zero_prefixed_literal)]
 fn test_byte_to_digits() {
     assert_eq!(byte_to_digits(0x04), 04);
     assert_eq!(byte_to_digits(0x70), 70);
     assert_eq!(byte_to_digits(0x89), 89);
     assert_eq!(byte_to_digits(0xC3), 93);
 }
+
+#[tokio::test]
+async fn test_resume() {
+    use crate::{
+        protocol::TestLoopbackConnection,
+        server::{P2Write, sessions::SessionSettings},
+    };
+
+    let users = Users::from_toml("alice.otp.Static = 1").unwrap();
+    let sessions = Sessions::new(&SessionSettings {
+        lifetime: std::time::Duration::from_secs(60),
+    })
+    .unwrap();
+    let alice = UserId::new("alice".to_owned());
+    let resume = async |token: &[u8]| {
+        let mut connection = TestLoopbackConnection::default();
+        connection.write_all(&[0xFF, 0xA1]).await.unwrap();
+        connection.write_all(token).await.unwrap();
+        handle_authentication(users.clone(), Some(&sessions), &mut connection)
+            .await
+            .unwrap()
+    };
+
+    let token = sessions.issue(&alice).unwrap();
+    assert!(matches!(resume(&token).await, Ok(Login::User(user)) if user == alice));
+    let mut tampered = token.clone();
+    *tampered.last_mut().unwrap() = b'x';
+    assert!(matches!(
+        resume(&tampered).await,
+        Err(AuthenticationError::InvalidSessionToken)
+    ));
+    assert!(matches!(
+        resume(&token[..token.len() - 2]).await,
+        Err(AuthenticationError::InvalidSessionToken)
+    ));
+}
@@ -2,15 +2,13 @@ use std::sync::Arc;
 
 use tokio::sync::Mutex;
 
-use crate::{
-    server::{
-        P2Write, WebsocketServer,
-        connection_data::ActiveConnectionData,
-        connections::{ReadableWebsocketStream, WritableWebsocketStream},
-        handle_authentication::{AuthenticationError, handle_authentication},
-        handle_received_messages::handle_received_messages,
-    },
-    users::Users,
+use crate::server::{
+    P2Write, ServerStats, WebsocketServer,
+    admin::disconnect,
+    connection_data::ActiveConnectionData,
+    connections::{ReadableWebsocketStream, WritableWebsocketStream},
+    handle_authentication::{AuthenticationError, Login, handle_authentication},
+    handle_received_messages::handle_received_messages,
 };
 
 pub struct Disconnected;
@@ -20,18 +18,37 @@ pub enum HandleConnectionError {
 }
 
 pub async fn handle_connection(
-    users: Users,
     server: WebsocketServer,
     mut read: ReadableWebsocketStream,
     active_connection_data: Arc<Mutex<ActiveConnectionData<WritableWebsocketStream>>>,
 ) -> Result<Disconnected, HandleConnectionError> {
-    match handle_authentication(users, &mut read).await {
-        Ok(Ok(user)) => {
+    ServerStats::count(&server.stats.pending_authentications, 1);
+    let login = tokio::select! {
+        login = handle_authentication(server.users().clone(), server.sessions(), &mut read) => Some(login),
+        () = server.shutdown_requested() => None,
+    };
+    server
+        .stats
+        .pending_authentications
+        .fetch_sub(1, std::sync::atomic::Ordering::Relaxed);
+    let Some(login) = login else {
+        // clients which haven't authenticated yet are disconnected like the others during a shutdown
+        disconnect(&mut *active_connection_data.lock().await).await;
+        return Ok(Disconnected);
+    };
+    match login {
+        Ok(Ok(Login::User(user))) if server.is_guest(&user) => {
+            tracing::Span::current().record("user", user.username());
+            handle_spectator(server, read, active_connection_data).await
+        }
+        Ok(Ok(Login::User(user))) => {
+            tracing::Span::current().record("user", user.username());
             let mut cons_lock = server.active_connections.lock().await;
             if let Some(previous_connection) =
                 cons_lock.insert(user.clone(), Arc::clone(&active_connection_data))
             {
                 drop(cons_lock);
+                tracing::info!("Replacing the user's previous connection");
                 let mut previous_connection = previous_connection.lock().await;
                 previous_connection.replaced = true;
                 previous_connection.write.close().await.ok();
@@ -39,14 +56,57 @@ pub async fn handle_connection(
                 drop(cons_lock);
             }
 
-            eprintln!("User {user:?} has joined.");
-            handle_received_messages(server, user, active_connection_data, &mut read).await
+            tracing::info!("Authenticated");
+            handle_received_messages(server, user, false, active_connection_data, &mut read).await
+        }
+        Ok(Ok(Login::Spectator)) => {
+            if server
+                .spectator_settings()
+                .is_some_and(|settings| settings.allow_anonymous)
+            {
+                handle_spectator(server, read, active_connection_data).await
+            } else {
+                Err(HandleConnectionError::AuthenticationError(
+                    AuthenticationError::SpectatorsNotAllowed,
+                ))
+            }
         }
         Ok(Err(e)) => Err(HandleConnectionError::AuthenticationError(e)),
         Err(e) => Err(HandleConnectionError::IoError(e)),
     }
 }
 
+/// Spectators get a new user id for each connection, so they never replace each other.
+async fn handle_spectator(
+    server: WebsocketServer,
+    mut read: ReadableWebsocketStream,
+    active_connection_data: Arc<Mutex<ActiveConnectionData<WritableWebsocketStream>>>,
+) -> Result<Disconnected, HandleConnectionError> {
+    let Some(slot) = server.try_add_spectator() else {
+        return Err(HandleConnectionError::AuthenticationError(
+            AuthenticationError::TooManySpectators,
+        ));
+    };
+    let id = slot.id.clone();
+    tracing::info!(spectator = id.username(), "Spectating");
+    server
+        .active_connections
+        .lock()
+        .await
+        .insert(id.clone(), active_connection_data.clone());
+    let result = handle_received_messages(
+        server.clone(),
+        id.clone(),
+        true,
+        active_connection_data,
+        &mut read,
+    )
+    .await;
+    server.active_connections.lock().await.remove(&id);
+    drop(slot);
+    result
+}
+
 impl From<tokio::io::Error> for HandleConnectionError {
     fn from(value: tokio::io::Error) -> Self {
         Self::IoError(value)
@@ -5,9 +5,10 @@ use tokio::{sync::Mutex, time::Instant};
 
 use crate::{
     data::{Area, Color, Coordinate},
-    protocol::P2Decodable,
+    protocol::{Extension, P2Decodable, P2Encodable, ServerMessage},
+    ratelimit::{RatelimitSettings, Ratelimiter},
     server::{
-        P2Read, P2Write, WebsocketServer,
+        CanvasId, P2Read, P2Write, PutRejected, ServerStats, WebsocketServer,
         connection_data::ActiveConnectionData,
         connections::{ReadableWebsocketStream, WritableWebsocketStream},
         handle_connection::{Disconnected, HandleConnectionError},
@@ -18,12 +19,22 @@ use crate::{
 pub async fn handle_received_messages(
     server: WebsocketServer,
     user: UserId,
+    // spectators can't Put
+    spectator: bool,
     active_connection_data: Arc<Mutex<ActiveConnectionData<WritableWebsocketStream>>>,
     connection: &mut ReadableWebsocketStream,
 ) -> Result<Disconnected, HandleConnectionError> {
-    let mut ratelimit = server.ratelimit.ratelimiter();
+    let mut canvas = CanvasId::DEFAULT;
+    let mut ratelimit = ratelimit_settings(&server, &user, spectator, canvas)
+        .await
+        .ratelimiter();
     let mut valid = true;
+    // messages dropped because of the ratelimit which the client hasn't been told about yet
+    let mut dropped_messages: u32 = 0;
+    // don't notify the client about dropped messages again until it was allowed to send messages again
+    let mut next_ratelimit_notice = Instant::now();
     'receive_a_message: loop {
+        count_blocked_time(&server, &mut ratelimit);
         if let Some(ping) = connection.2.take() {
             active_connection_data
                 .lock()
@@ -35,7 +46,31 @@ pub async fn handle_received_messages(
                 .ok();
         }
         let mut first = [0u8];
-        connection.read_exact(&mut first).await?;
+        // once the client may send again, report the dropped messages even if it has stopped sending
+        tokio::select! {
+            result = connection.read_exact(&mut first) => result?,
+            () = tokio::time::sleep_until(next_ratelimit_notice), if dropped_messages > 0 => {
+                let dropped_messages = std::mem::take(&mut dropped_messages);
+                if active_connection_data
+                    .lock()
+                    .await
+                    .extensions
+                    .is_enabled(Extension::Ratelimited)
+                {
+                    let retry_after = ratelimit.time_until_next_message(Instant::now());
+                    send_message(
+                        &active_connection_data,
+                        ServerMessage::Ratelimited {
+                            retry_after,
+                            dropped_messages,
+                        },
+                    )
+                    .await;
+                    ServerStats::count(&server.stats.ratelimit_notices_sent, 1);
+                }
+                continue 'receive_a_message;
+            }
+        }
         match first[0] {
             0x00 if valid => {
                 // Message: Disconnect Request
@@ -45,6 +80,7 @@ pub async fn handle_received_messages(
                 drop(lock);
                 let mut cons_lock = server.active_connections.lock().await;
                 // if the connection hasn't been replaced yet, remove it from the server state
+                #[allow(clippy::collapsible_if)]
                 if let Some(con) = cons_lock.remove(&user) {
                     if !con.lock().await.replaced {
                         cons_lock.insert(user.clone(), con);
@@ -53,10 +89,33 @@ pub async fn handle_received_messages(
                 drop(cons_lock);
                 break 'receive_a_message Ok(Disconnected);
             }
-            0xD0 if valid => {
+            0xD0 if valid && !spectator => {
                 // Message: Put
                 if ratelimit.should_drop_message().await {
                     valid = false;
+                    ServerStats::count(&server.stats.ratelimit_dropped_messages, 1);
+                    dropped_messages = dropped_messages.saturating_add(1);
+                    tracing::debug!(dropped_messages, "Dropped a Put because of the ratelimit");
+                    let now = Instant::now();
+                    if now >= next_ratelimit_notice
+                        && active_connection_data
+                            .lock()
+                            .await
+                            .extensions
+                            .is_enabled(Extension::Ratelimited)
+                    {
+                        let retry_after = ratelimit.time_until_next_message(now);
+                        next_ratelimit_notice = now + retry_after;
+                        send_message(
+                            &active_connection_data,
+                            ServerMessage::Ratelimited {
+                                retry_after,
+                                dropped_messages: std::mem::take(&mut dropped_messages),
+                            },
+                        )
+                        .await;
+                        ServerStats::count(&server.stats.ratelimit_notices_sent, 1);
+                    }
                     continue 'receive_a_message;
                 }
                 let coord = if let Some(v) = Coordinate::read_p2encoded(connection).await? {
@@ -71,7 +130,28 @@ pub async fn handle_received_messages(
                     valid = false;
                     continue 'receive_a_message;
                 };
-                server.put(coord, color).await;
+                let result = server.put(canvas, &user, coord, color).await;
+                let extensions = active_connection_data.lock().await.extensions;
+                if let Err(reason) = result
+                    && reason != PutRejected::Muted
+                    && extensions.is_enabled(Extension::Rejections)
+                {
+                    send_message(
+                        &active_connection_data,
+                        ServerMessage::PutRejected { coord, reason },
+                    )
+                    .await;
+                }
+                if extensions.is_enabled(Extension::Cooldown) {
+                    send_extension_state(
+                        &server,
+                        canvas,
+                        &user,
+                        &active_connection_data,
+                        Extension::Cooldown,
+                    )
+                    .await;
+                }
             }
             0xAF if valid => {
                 // Message: Sub
@@ -90,8 +170,69 @@ pub async fn handle_received_messages(
                 if lock.replaced {
                     break 'receive_a_message Ok(Disconnected);
                 }
-                lock.subscribed_area = Area::try_new(top_left, bottom_right);
+                lock.subscribed_area = Area::try_new(top_left, bottom_right)
+                    .and_then(|area| area.intersection(server.current_bounds(canvas)));
+                lock.has_acted();
+            }
+            0xAE if valid => {
+                // Message: Enable Extension
+                let mut extension = [0u8];
+                connection.read_exact(&mut extension).await?;
+                let Some(extension) = Extension::from_byte(extension[0]) else {
+                    valid = false;
+                    continue 'receive_a_message;
+                };
+                let mut lock = active_connection_data.lock().await;
+                if lock.replaced {
+                    break 'receive_a_message Ok(Disconnected);
+                }
+                lock.extensions.enable(extension);
                 lock.has_acted();
+                drop(lock);
+                send_extension_state(&server, canvas, &user, &active_connection_data, extension)
+                    .await;
+            }
+            0xAC if valid => {
+                // Message: Select Canvas
+                let mut name_len = [0u8];
+                connection.read_exact(&mut name_len).await?;
+                let mut name = vec![0u8; name_len[0] as usize + 1];
+                connection.read_exact(&mut name).await?;
+                ratelimit.wait_if_necessary_on_recv(Instant::now()).await;
+                if let Some(selected) = String::from_utf8(name)
+                    .ok()
+                    .and_then(|name| server.canvas_by_name(&name))
+                    && selected != canvas
+                {
+                    canvas = selected;
+                    count_blocked_time(&server, &mut ratelimit);
+                    ratelimit = ratelimit_settings(&server, &user, spectator, canvas)
+                        .await
+                        .ratelimiter();
+                    let mut lock = active_connection_data.lock().await;
+                    if lock.replaced {
+                        break 'receive_a_message Ok(Disconnected);
+                    }
+                    lock.canvas = canvas;
+                    lock.subscribed_area = None;
+                    lock.has_acted();
+                    let extensions = lock.extensions;
+                    drop(lock);
+                    for extension in Extension::ALL {
+                        if extensions.is_enabled(extension) {
+                            send_extension_state(
+                                &server,
+                                canvas,
+                                &user,
+                                &active_connection_data,
+                                extension,
+                            )
+                            .await;
+                        }
+                    }
+                }
+                let name = server.canvas_name(canvas).to_owned();
+                send_message(&active_connection_data, ServerMessage::Canvas { name }).await;
             }
             0xFF => {
                 valid = true;
@@ -106,3 +247,223 @@ pub async fn handle_received_messages(
         }
     }
 }
+
+fn count_blocked_time(server: &WebsocketServer, ratelimit: &mut Ratelimiter) {
+    let blocked_time = ratelimit.take_blocked_time();
+    if !blocked_time.is_zero() {
+        ServerStats::count(
+            &server.stats.ratelimit_blocked_micros,
+            blocked_time.as_micros().try_into().unwrap_or(u64::MAX),
+        );
+    }
+}
+
+async fn ratelimit_settings(
+    server: &WebsocketServer,
+    user: &UserId,
+    spectator: bool,
+    canvas: CanvasId,
+) -> RatelimitSettings {
+    match server.spectator_settings() {
+        Some(settings) if spectator => settings.ratelimit,
+        _ => {
+            server
+                .users()
+                .ratelimit(user, server.ratelimit(canvas))
+                .await
+        }
+    }
+}
+
+/// Sends the messages which a client expects after enabling the extension, if there are any.
+async fn send_extension_state(
+    server: &WebsocketServer,
+    canvas: CanvasId,
+    user: &UserId,
+    active_connection_data: &Mutex<ActiveConnectionData<WritableWebsocketStream>>,
+    extension: Extension,
+) {
+    let message = match extension {
+        Extension::Cooldown => {
+            server
+                .cooldown_status(canvas, user)
+                .await
+                .map(|status| ServerMessage::Cooldown {
+                    available_pixels: status.available_pixels,
+                    next_pixel_in: status.next_pixel_in,
+                })
+        }
+        Extension::Ratelimited | Extension::Rejections => None,
+        Extension::Bounds => Some(ServerMessage::Bounds {
+            area: server.current_bounds(canvas),
+        }),
+        Extension::Palette => server
+            .palette(canvas)
+            .map(|palette| ServerMessage::Palette {
+                colors: palette.colors().to_vec(),
+            }),
+        Extension::Sessions => server
+            .sessions()
+            .and_then(|sessions| sessions.issue(user))
+            .map(|token| ServerMessage::SessionToken { token }),
+    };
+    if let Some(message) = message {
+        send_message(active_connection_data, message).await;
+    }
+}
+
+async fn send_message(
+    active_connection_data: &Mutex<ActiveConnectionData<WritableWebsocketStream>>,
+    message: ServerMessage,
+) {
+    let mut lock = active_connection_data.lock().await;
+    if lock.replaced {
+        return;
+    }
+    if message.write_p2encoded(&mut lock.write).await.is_err() || lock.write.flush().await.is_err()
+    {
+        lock.replaced = true;
+    }
+}
+
+#[tokio::test]
+async fn test_ratelimited_notices() {
+    use std::time::Duration;
+
+    use futures_util::StreamExt;
+    use tokio_tungstenite::tungstenite::Message;
+
+    use crate::{
+        canvas::CanvasSettings,
+        config::{Config, DEFAULT_CANVAS_NAME},
+        protocol::TestLoopbackConnection,
+        users::Users,
+    };
+
+    let time_per_message = Duration::from_millis(200);
+    let config = Config {
+        history: None,
+        canvases: vec![(
+            DEFAULT_CANVAS_NAME.to_owned(),
+            CanvasSettings {
+                ratelimit: RatelimitSettings::new(time_per_message).drop_instead_of_blocking(),
+                ..Default::default()
+            },
+        )],
+        ..Default::default()
+    };
+    let users =
+        Users::from_toml("painter = { otp.Static = 1 }\nother = { otp.Static = 1 }").unwrap();
+    let server = WebsocketServer::new(&config, users).await.unwrap();
+    let address = tokio::net::TcpListener::bind("127.0.0.1:0")
+        .await
+        .unwrap()
+        .local_addr()
+        .unwrap();
+    tokio::task::spawn(server.clone().accept_connections(address));
+    let connect = async |username: &str| {
+        let mut client = loop {
+            if let Ok((client, _)) =
+                tokio_tungstenite::connect_async(format!("ws://{address}")).await
+            {
+                break client;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        };
+        let mut authentication = vec![0xFF, 0xA0, username.len() as u8 - 1];
+        authentication.extend_from_slice(username.as_bytes());
+        authentication.extend_from_slice(&[0x00, 0x00, 0x00, 0x01]);
+        client
+            .send(Message::Binary(authentication.into()))
+            .await
+            .unwrap();
+        client
+    };
+    // the time after which messages are accepted again and the number of dropped messages
+    let parse_notice = async |message: &[u8]| {
+        let notice = message.strip_prefix(&[0xFF, 0x81])?;
+        let mut connection = TestLoopbackConnection::default();
+        connection.write_all(notice).await.unwrap();
+        let retry_after = Duration::read_p2encoded(&mut connection)
+            .await
+            .unwrap()
+            .unwrap();
+        let mut dropped_messages = [0u8; 2];
+        connection.read_exact(&mut dropped_messages).await.unwrap();
+        Some((retry_after, dropped_messages))
+    };
+    // Sends `messages` followed by `puts` Puts in a single WebSocket message,
+    // then selects the default canvas and returns the Ratelimited notices received until the answer.
+    let send_puts = async |client: &mut tokio_tungstenite::WebSocketStream<_>,
+                           mut messages: Vec<u8>,
+                           puts: i16| {
+        for x in 0..puts {
+            messages.extend_from_slice(&[0xFF, 0xD0]);
+            Coordinate { x, y: 0 }
+                .write_p2encoded(&mut messages)
+                .await
+                .unwrap();
+            Color { r: 1, g: 2, b: 3 }
+                .write_p2encoded(&mut messages)
+                .await
+                .unwrap();
+        }
+        messages.extend_from_slice(b"\xFF\xAC\x06default");
+        client.send(Message::Binary(messages.into())).await.unwrap();
+        let mut notices = Vec::new();
+        while let Some(Ok(message)) = client.next().await {
+            let message = message.into_data();
+            if let Some(notice) = parse_notice(&message).await {
+                notices.push(notice);
+            } else if message.starts_with(&[0xFF, 0x84]) {
+                return notices;
+            }
+        }
+        panic!("the connection was closed");
+    };
+
+    // clients which haven't enabled the extension aren't notified
+    let mut other = connect("other").await;
+    assert!(send_puts(&mut other, Vec::new(), 2).await.is_empty());
+    assert_eq!(
+        ServerStats::get(&server.stats.ratelimit_dropped_messages),
+        1
+    );
+
+    let mut painter = connect("painter").await;
+    let notices = send_puts(&mut painter, vec![0xFF, 0xAE, 0x02], 3).await;
+    // only the first dropped Put is reported until the client may send again
+    assert_eq!(notices.len(), 1);
+    let (retry_after, dropped_messages) = notices[0];
+    assert!(!retry_after.is_zero() && retry_after <= time_per_message);
+    assert_eq!(dropped_messages, [0x00, 0x01]);
+    assert_eq!(
+        ServerStats::get(&server.stats.ratelimit_dropped_messages),
+        3
+    );
+
+    // the Put which wasn't reported yet is reported once the client may send again,
+    // which is right after the answer because selecting the canvas waited for the ratelimit
+    let message = tokio::time::timeout(time_per_message * 2, painter.next())
+        .await
+        .unwrap()
+        .unwrap()
+        .unwrap()
+        .into_data();
+    let (retry_after, dropped_messages) = parse_notice(&message).await.unwrap();
+    assert!(retry_after <= time_per_message);
+    assert_eq!(dropped_messages, [0x00, 0x01]);
+    assert_eq!(ServerStats::get(&server.stats.ratelimit_notices_sent), 2);
+
+    tokio::time::sleep(time_per_message).await;
+
+    let notices = send_puts(&mut painter, Vec::new(), 2).await;
+    assert_eq!(notices.len(), 1);
+    assert_eq!(notices[0].1, [0x00, 0x01]);
+    assert_eq!(
+        ServerStats::get(&server.stats.ratelimit_dropped_messages),
+        4
+    );
+    assert_eq!(ServerStats::get(&server.stats.ratelimit_notices_sent), 3);
+    server.request_shutdown();
+}
@@ -0,0 +1,334 @@
+use std::time::Duration;
+
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpStream,
+};
+
+use crate::server::{CanvasId, WebsocketServer};
+
+/// The width and height of a tile in pixels
+pub const TILE_SIZE: u32 = 256;
+/// At this zoom level, one pixel of a tile is one pixel of the canvas.
+pub const MAX_ZOOM: u8 = 8;
+/// The coordinate of the top left pixel of the only tile at zoom level 0
+pub const TILE_ORIGIN: i32 = i16::MIN as i32;
+/// The maximum size of a request's head
+const MAX_REQUEST_SIZE: usize = 8 * 1024;
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+/// Rejected connections are closed sooner, so that they don't hold on to file descriptors.
+const REJECTED_REQUEST_TIMEOUT: Duration = Duration::from_secs(1);
+/// How long rejected clients are asked to wait before connecting again
+const RETRY_AFTER: Duration = Duration::from_secs(5);
+
+/// A square part of the canvas, like the tiles of web maps.
+/// At zoom level `z`, the canvas is split into `2^z * 2^z` tiles.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct Tile {
+    pub z: u8,
+    pub x: u32,
+    pub y: u32,
+}
+
+impl Tile {
+    /// Parses `{z}/{x}/{y}.png`.
+    fn from_path(path: &str) -> Option<Self> {
+        let mut parts = path.strip_suffix(".png")?.split('/');
+        let (z, x, y) = (parts.next()?, parts.next()?, parts.next()?);
+        if parts.next().is_some() {
+            return None;
+        }
+        let tile = Self {
+            z: z.parse().ok()?,
+            x: x.parse().ok()?,
+            y: y.parse().ok()?,
+        };
+        (tile.z <= MAX_ZOOM && tile.x < 1 << tile.z && tile.y < 1 << tile.z).then_some(tile)
+    }
+
+    /// log2 of the number of canvas pixels per tile pixel in each direction
+    pub fn scale_shift(self) -> u8 {
+        MAX_ZOOM - self.z
+    }
+
+    /// The coordinates of the canvas pixel at the tile's top left corner,
+    /// which are not always valid `Coordinate`s.
+    pub fn top_left(self) -> (i32, i32) {
+        let size = (TILE_SIZE as i32) << self.scale_shift();
+        (
+            TILE_ORIGIN + self.x as i32 * size,
+            TILE_ORIGIN + self.y as i32 * size,
+        )
+    }
+}
+
+/// Checks whether a new connection sent a plain HTTP request rather than a WebSocket upgrade request.
+/// Does not consume any data, so the connection can still be passed on to the WebSocket handshake.
+pub async fn is_plain_http_request(connection: &TcpStream) -> bool {
+    let mut buf = vec![0; MAX_REQUEST_SIZE];
+    let mut previous_len = 0;
+    let result = tokio::time::timeout(REQUEST_TIMEOUT, async {
+        loop {
+            let len = connection.peek(&mut buf).await.ok()?;
+            if len == 0 {
+                return None;
+            }
+            let mut headers = [httparse::EMPTY_HEADER; 64];
+            let mut request = httparse::Request::new(&mut headers);
+            match request.parse(&buf[..len]) {
+                Ok(httparse::Status::Complete(_)) => {
+                    return Some(!request.headers.iter().any(|header| {
+                        header.name.eq_ignore_ascii_case("upgrade")
+                            && String::from_utf8_lossy(header.value)
+                                .to_ascii_lowercase()
+                                .contains("websocket")
+                    }));
+                }
+                Ok(httparse::Status::Partial) if len < buf.len() => {}
+                _ => return None,
+            }
+            // peeking again returns the same data until more data has arrived
+            if len == previous_len {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+            previous_len = len;
+        }
+    })
+    .await;
+    // let the WebSocket handshake deal with invalid requests
+    result.ok().flatten().unwrap_or(false)
+}
+
+/// Answers a single HTTP request and closes the connection.
+///
+/// `GET /tiles/{z}/{x}/{y}.png` returns a tile of the default canvas as a PNG image,
+/// `?canvas=<name>` selects another canvas.
+/// `GET /metrics` returns the server's statistics for Prometheus.
+pub async fn handle_http_request(connection: TcpStream, server: WebsocketServer) {
+    answer_http_request(connection, REQUEST_TIMEOUT, Some(&server)).await;
+}
+
+/// Answers any request, including WebSocket upgrade requests, with `503 Service Unavailable`.
+pub async fn reject_http_request(connection: TcpStream) {
+    answer_http_request(connection, REJECTED_REQUEST_TIMEOUT, None).await;
+}
+
+/// Rejects the request if there is no `server`.
+async fn answer_http_request(
+    mut connection: TcpStream,
+    timeout: Duration,
+    server: Option<&WebsocketServer>,
+) {
+    let mut buf = vec![0; MAX_REQUEST_SIZE];
+    let mut len = 0;
+    let response = tokio::time::timeout(timeout, async {
+        loop {
+            let read = connection.read(&mut buf[len..]).await.ok()?;
+            if read == 0 {
+                return None;
+            }
+            len += read;
+            let mut headers = [httparse::EMPTY_HEADER; 64];
+            let mut request = httparse::Request::new(&mut headers);
+            match request.parse(&buf[..len]) {
+                Ok(httparse::Status::Complete(_)) => {
+                    return Some(match server {
+                        Some(server) => respond(server, &request).await,
+                        None => {
+                            let mut response = Response::status(503, "Service Unavailable");
+                            response
+                                .headers
+                                .push(("Retry-After", RETRY_AFTER.as_secs().to_string()));
+                            response
+                        }
+                    });
+                }
+                Ok(httparse::Status::Partial) if len < buf.len() => {}
+                _ => return Some(Response::status(400, "Bad Request")),
+            }
+        }
+    })
+    .await;
+    if let Ok(Some(response)) = response {
+        tracing::debug!(status = response.status, "Answered an HTTP request");
+        connection.write_all(&response.encode()).await.ok();
+        connection.shutdown().await.ok();
+    }
+}
+
+struct Response {
+    status: u16,
+    reason: &'static str,
+    headers: Vec<(&'static str, String)>,
+    body: Vec<u8>,
+    /// `false` for responses to HEAD requests
+    send_body: bool,
+}
+
+impl Response {
+    fn status(status: u16, reason: &'static str) -> Self {
+        Self {
+            status,
+            reason,
+            headers: vec![("Content-Type", "text/plain".to_owned())],
+            body: format!("{status} {reason}\n").into_bytes(),
+            send_body: true,
+        }
+    }
+
+    fn encode(&self) -> Vec<u8> {
+        let mut out = format!("HTTP/1.1 {} {}\r\n", self.status, self.reason);
+        for (name, value) in &self.headers {
+            out.push_str(&format!("{name}: {value}\r\n"));
+        }
+        out.push_str(&format!(
+            "Content-Length: {}\r\nConnection: close\r\n\r\n",
+            self.body.len()
+        ));
+        let mut out = out.into_bytes();
+        if self.send_body {
+            out.extend_from_slice(&self.body);
+        }
+        out
+    }
+}
+
+async fn respond(server: &WebsocketServer, request: &httparse::Request<'_, '_>) -> Response {
+    let send_body = match request.method {
+        Some("GET") => true,
+        Some("HEAD") => false,
+        _ => {
+            let mut response = Response::status(405, "Method Not Allowed");
+            response.headers.push(("Allow", "GET, HEAD".to_owned()));
+            return response;
+        }
+    };
+    let (path, query) = request
+        .path
+        .unwrap_or_default()
+        .split_once('?')
+        .unwrap_or((request.path.unwrap_or_default(), ""));
+    let if_none_match = request
+        .headers
+        .iter()
+        .find(|header| header.name.eq_ignore_ascii_case("if-none-match"))
+        .map(|header| String::from_utf8_lossy(header.value).into_owned());
+
+    let mut response = if let Some(tile) = path.strip_prefix("/tiles/") {
+        let canvas = match query_parameter(query, "canvas") {
+            Some(name) => server.canvas_by_name(&name),
+            None => Some(CanvasId::DEFAULT),
+        };
+        match (canvas, Tile::from_path(tile)) {
+            (Some(canvas), Some(tile)) => tile_response(server, canvas, tile, if_none_match).await,
+            _ => Response::status(404, "Not Found"),
+        }
+    } else if path == "/metrics" {
+        Response {
+            status: 200,
+            reason: "OK",
+            headers: vec![(
+                "Content-Type",
+                "text/plain; version=0.0.4; charset=utf-8".to_owned(),
+            )],
+            body: server.metrics().await.into_bytes(),
+            send_body: true,
+        }
+    } else {
+        Response::status(404, "Not Found")
+    };
+    response.send_body = send_body;
+    response
+}
+
+async fn tile_response(
+    server: &WebsocketServer,
+    canvas: CanvasId,
+    tile: Tile,
+    if_none_match: Option<String>,
+) -> Response {
+    // the generation restarts at 0 when the server restarts, so the start time is part of the ETag
+    let etag = |generation| format!("\"{}-{generation}\"", server.started_at());
+    let current_etag = etag(server.tile_generation(canvas, tile).await);
+    let mut response = if if_none_match.is_some_and(|tags| {
+        tags.split(',')
+            .any(|tag| tag.trim() == current_etag || tag.trim() == "*")
+    }) {
+        Response {
+            status: 304,
+            reason: "Not Modified",
+            headers: vec![("ETag", current_etag)],
+            body: Vec::new(),
+            send_body: true,
+        }
+    } else {
+        // the canvas may have changed in the meantime, so use the generation of the rendered image
+        let Ok((png, generation)) = server.tile_png(canvas, tile).await else {
+            return Response::status(500, "Internal Server Error");
+        };
+        Response {
+            status: 200,
+            reason: "OK",
+            headers: vec![
+                ("Content-Type", "image/png".to_owned()),
+                ("ETag", etag(generation)),
+            ],
+            body: png.to_vec(),
+            send_body: true,
+        }
+    };
+    response.headers.extend([
+        ("Cache-Control", "no-cache".to_owned()),
+        ("Access-Control-Allow-Origin", "*".to_owned()),
+    ]);
+    response
+}
+
+/// The percent-decoded value of the first parameter called `name` in the query string.
+fn query_parameter(query: &str, name: &str) -> Option<String> {
+    let value = query
+        .split('&')
+        .find_map(|parameter| parameter.strip_prefix(name)?.strip_prefix('='))?;
+    let mut bytes = Vec::with_capacity(value.len());
+    let mut value = value.bytes();
+    while let Some(byte) = value.next() {
+        bytes.push(match byte {
+            b'+' => b' ',
+            b'%' => {
+                let hex = [value.next()?, value.next()?];
+                u8::from_str_radix(std::str::from_utf8(&hex).ok()?, 16).ok()?
+            }
+            byte => byte,
+        });
+    }
+    String::from_utf8(bytes).ok()
+}
+
+#[test]
+fn test_parse_tile_requests() {
+    assert_eq!(
+        Tile::from_path("3/7/0.png"),
+        Some(Tile { z: 3, x: 7, y: 0 })
+    );
+    assert_eq!(Tile::from_path("3/8/0.png"), None);
+    assert_eq!(Tile::from_path("9/0/0.png"), None);
+    assert_eq!(Tile::from_path("1/0/0/0.png"), None);
+    assert_eq!(Tile::from_path("1/0/0"), None);
+    assert_eq!(Tile { z: 0, x: 0, y: 0 }.top_left(), (-32768, -32768));
+    assert_eq!(
+        Tile {
+            z: 8,
+            x: 128,
+            y: 129
+        }
+        .top_left(),
+        (0, 256)
+    );
+
+    assert_eq!(
+        query_parameter("x=1&canvas=event%202025", "canvas").as_deref(),
+        Some("event 2025")
+    );
+    assert_eq!(query_parameter("canvases=a", "canvas"), None);
+    assert_eq!(query_parameter("canvas=%ZZ", "canvas"), None);
+}
@@ -0,0 +1,304 @@
+use std::{fmt::Write, sync::atomic::AtomicU64};
+
+use crate::server::{PutRejected, ServerStats, WebsocketServer, stats::Histogram};
+
+impl WebsocketServer {
+    /// Renders the server's statistics in the Prometheus text exposition format.
+    pub async fn metrics(&self) -> String {
+        let stats = &self.stats;
+        let mut metrics = Metrics(String::new());
+
+        let active_connections = self.active_connections.lock().await;
+        let connected_clients = active_connections.len();
+        let (mut queued_bytes, mut sending_connections) = (0, 0);
+        for connection in active_connections.values() {
+            // a connection is locked while a message is being sent to it, so don't wait for slow clients
+            match connection.try_lock() {
+                Ok(connection) => queued_bytes += connection.write.queued_bytes(),
+                Err(_) => sending_connections += 1,
+            }
+        }
+        drop(active_connections);
+
+        metrics.gauge(
+            "p2ws_connected_clients",
+            "Authenticated clients, including spectators",
+            connected_clients as u64,
+        );
+        metrics.gauge(
+            "p2ws_connected_spectators",
+            "Connected spectators",
+            self.spectator_count() as u64,
+        );
+        metrics.gauge(
+            "p2ws_pending_authentications",
+            "WebSocket connections which haven't authenticated yet",
+            ServerStats::get(&stats.pending_authentications),
+        );
+        metrics.gauge(
+            "p2ws_outbound_queued_bytes",
+            "Bytes waiting to be sent to clients",
+            queued_bytes as u64,
+        );
+        metrics.gauge(
+            "p2ws_outbound_sending_connections",
+            "Connections which are currently being sent a message",
+            sending_connections,
+        );
+
+        metrics.counter(
+            "p2ws_puts_accepted_total",
+            "Puts which changed a pixel",
+            &stats.accepted_puts,
+        );
+        metrics.header(
+            "p2ws_puts_rejected_total",
+            "counter",
+            "Puts which did not change a pixel",
+        );
+        for (reason, name) in [
+            (PutRejected::OutOfBounds, "out_of_bounds"),
+            (PutRejected::NotInPalette, "not_in_palette"),
+            (PutRejected::Cooldown, "cooldown"),
+            (PutRejected::NotAllowed, "not_allowed"),
+            (PutRejected::Protected, "protected"),
+            (PutRejected::Muted, "muted"),
+        ] {
+            metrics.sample(
+                "p2ws_puts_rejected_total",
+                &format!("reason=\"{name}\""),
+                ServerStats::get(stats.rejected_puts(reason)),
+            );
+        }
+        metrics.counter(
+            "p2ws_ratelimit_dropped_messages_total",
+            "Messages which were ignored because the client exceeded its ratelimit",
+            &stats.ratelimit_dropped_messages,
+        );
+        metrics.counter(
+            "p2ws_ratelimit_notices_sent_total",
+            "Ratelimited messages sent to clients",
+            &stats.ratelimit_notices_sent,
+        );
+        metrics.header(
+            "p2ws_ratelimit_blocked_seconds_total",
+            "counter",
+            "Time spent delaying messages of clients whose ratelimit blocks instead of dropping",
+        );
+        metrics.sample(
+            "p2ws_ratelimit_blocked_seconds_total",
+            "",
+            micros_as_seconds(ServerStats::get(&stats.ratelimit_blocked_micros)),
+        );
+
+        metrics.header(
+            "p2ws_auth_failures_total",
+            "counter",
+            "Failed authentications",
+        );
+        for (reason, counter) in [
+            ("username_not_utf8", &stats.auth_failures_username_not_utf8),
+            ("no_such_user", &stats.auth_failures_no_such_user),
+            (
+                "invalid_one_time_password",
+                &stats.auth_failures_invalid_one_time_password,
+            ),
+            ("banned", &stats.auth_failures_banned),
+            (
+                "spectators_not_allowed",
+                &stats.auth_failures_spectators_not_allowed,
+            ),
+            (
+                "too_many_spectators",
+                &stats.auth_failures_too_many_spectators,
+            ),
+            (
+                "invalid_session_token",
+                &stats.auth_failures_invalid_session_token,
+            ),
+            ("user_store", &stats.auth_failures_user_store),
+        ] {
+            metrics.sample(
+                "p2ws_auth_failures_total",
+                &format!("reason=\"{reason}\""),
+                ServerStats::get(counter),
+            );
+        }
+
+        metrics.counter(
+            "p2ws_updates_sent_total",
+            "Update messages sent to clients",
+            &stats.updates_sent,
+        );
+        metrics.counter(
+            "p2ws_update_bytes_sent_total",
+            "Bytes of Update messages sent to clients",
+            &stats.update_bytes_sent,
+        );
+        metrics.histogram(
+            "p2ws_update_duration_seconds",
+            "How long it took to send the Updates for a batch of modified pixels",
+            &stats.update_duration_micros,
+            micros_as_seconds,
+        );
+        metrics.histogram(
+            "p2ws_update_batch_pixels",
+            "Modified pixels per batch of Updates",
+            &stats.update_batch_pixels,
+            |pixels| pixels.to_string(),
+        );
+
+        metrics.counter(
+            "p2ws_rejected_connections_total",
+            "Connections rejected because the server had too many connections",
+            &stats.rejected_connections,
+        );
+        metrics.counter(
+            "p2ws_accept_errors_total",
+            "Failed attempts to accept a connection",
+            &stats.accept_errors,
+        );
+        metrics.0
+    }
+}
+
+struct Metrics(String);
+
+impl Metrics {
+    fn header(&mut self, name: &str, kind: &str, help: &str) {
+        writeln!(self.0, "# HELP {name} {help}\n# TYPE {name} {kind}").unwrap();
+    }
+
+    fn sample(&mut self, name: &str, labels: &str, value: impl std::fmt::Display) {
+        if labels.is_empty() {
+            writeln!(self.0, "{name} {value}").unwrap();
+        } else {
+            writeln!(self.0, "{name}{{{labels}}} {value}").unwrap();
+        }
+    }
+
+    fn gauge(&mut self, name: &str, help: &str, value: u64) {
+        self.header(name, "gauge", help);
+        self.sample(name, "", value);
+    }
+
+    fn counter(&mut self, name: &str, help: &str, counter: &AtomicU64) {
+        self.header(name, "counter", help);
+        self.sample(name, "", ServerStats::get(counter));
+    }
+
+    /// `format` converts the histogram's values to the unit of the metric.
+    fn histogram(
+        &mut self,
+        name: &str,
+        help: &str,
+        histogram: &Histogram,
+        format: impl Fn(u64) -> String,
+    ) {
+        self.header(name, "histogram", help);
+        let mut cumulative = 0;
+        for (bucket, count) in histogram.buckets().into_iter().enumerate() {
+            cumulative += count;
+            let upper_bound = format(Histogram::upper_bound(bucket));
+            self.sample(
+                &format!("{name}_bucket"),
+                &format!("le=\"{upper_bound}\""),
+                cumulative,
+            );
+        }
+        let count = histogram.count();
+        self.sample(&format!("{name}_bucket"), "le=\"+Inf\"", count);
+        self.sample(&format!("{name}_sum"), "", format(histogram.sum()));
+        self.sample(&format!("{name}_count"), "", count);
+    }
+}
+
+fn micros_as_seconds(micros: u64) -> String {
+    (micros as f64 / 1_000_000.0).to_string()
+}
+
+#[tokio::test]
+async fn test_metrics_endpoint() {
+    use futures_util::{SinkExt, StreamExt};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    use crate::{
+        config::Config,
+        data::{Color, Coordinate},
+        server::CanvasId,
+        users::{UserId, Users},
+    };
+
+    let config = Config {
+        history: None,
+        ..Default::default()
+    };
+    let users = Users::from_toml("painter = { otp.Static = 1 }").unwrap();
+    let server = WebsocketServer::new(&config, users).await.unwrap();
+    let address = tokio::net::TcpListener::bind("127.0.0.1:0")
+        .await
+        .unwrap()
+        .local_addr()
+        .unwrap();
+    tokio::task::spawn(server.clone().accept_connections(address));
+    let scrape = async || {
+        for _ in 0..100 {
+            if let Ok(mut connection) = tokio::net::TcpStream::connect(address).await {
+                connection
+                    .write_all(b"GET /metrics HTTP/1.1\r\nHost: localhost\r\n\r\n")
+                    .await
+                    .unwrap();
+                let mut response = String::new();
+                connection.read_to_string(&mut response).await.unwrap();
+                return response;
+            }
+            tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+        }
+        panic!("the server didn't start");
+    };
+
+    let metrics = scrape().await;
+    assert!(metrics.starts_with("HTTP/1.1 200 OK\r\n"));
+    assert!(metrics.contains("\np2ws_connected_clients 0\n"));
+    assert!(metrics.contains("\np2ws_update_duration_seconds_bucket{le=\"+Inf\"} 0\n"));
+
+    server
+        .put(
+            CanvasId::DEFAULT,
+            &UserId::new("painter".to_owned()),
+            Coordinate { x: 0, y: 0 },
+            Color { r: 1, g: 2, b: 3 },
+        )
+        .await
+        .unwrap();
+    // authenticate with the wrong OTP, after which the server closes the connection
+    let (mut client, _) = tokio_tungstenite::connect_async(format!("ws://{address}"))
+        .await
+        .unwrap();
+    let mut authentication = vec![0xFF, 0xA0, 6];
+    authentication.extend_from_slice(b"painter");
+    authentication.extend_from_slice(&[0x00, 0x00, 0x00, 0x02]);
+    client
+        .send(tokio_tungstenite::tungstenite::Message::Binary(
+            authentication.into(),
+        ))
+        .await
+        .unwrap();
+    while let Some(Ok(_)) = client.next().await {}
+
+    let mut metrics = scrape().await;
+    for _ in 0..100 {
+        if metrics.contains("\np2ws_auth_failures_total{reason=\"invalid_one_time_password\"} 1\n")
+        {
+            break;
+        }
+        tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+        metrics = scrape().await;
+    }
+    assert!(
+        metrics.contains("\np2ws_auth_failures_total{reason=\"invalid_one_time_password\"} 1\n")
+    );
+    assert!(metrics.contains("\np2ws_puts_accepted_total 1\n"));
+    assert!(metrics.contains("\np2ws_pending_authentications 0\n"));
+    server.request_shutdown();
+}
@@ -1,77 +1,479 @@
+mod admin;
+#[cfg(unix)]
+mod admin_socket;
+mod canvas;
 mod connection_data;
 mod connection_traits;
 mod connections;
 mod handle_authentication;
 mod handle_connection;
 mod handle_received_messages;
+mod http;
+mod metrics;
+mod sessions;
+mod spectators;
+mod stats;
+mod tiles;
 
+#[cfg(unix)]
+pub use admin_socket::serve_admin_socket;
+pub use canvas::CanvasId;
 pub use connection_traits::*;
 pub use connections::WebsocketServer;
 
-use tokio::{sync::Mutex, task::JoinHandle};
+use tokio::{
+    sync::{Mutex, watch},
+    time::Instant,
+};
 
 use std::{
     collections::{BTreeMap, HashMap},
     sync::Arc,
-    time::Duration,
+    time::{Duration, SystemTime},
 };
 
 use crate::{
+    canvas::Palette,
+    config::Config,
     data::{Area, Color, Coordinate},
-    protocol::P2Encodable,
-    ratelimit::RatelimitSettings,
-    server::connection_data::ActiveConnectionData,
-    users::UserId,
+    history::{Edit, EditFilter, HistoryLog, for_each_edit},
+    image::RgbImage,
+    protocol::{Extension, P2Encodable, ServerMessage},
+    ratelimit::{CooldownStatus, RatelimitSettings},
+    server::{
+        canvas::Canvas,
+        connection_data::ActiveConnectionData,
+        http::{TILE_SIZE, Tile},
+        sessions::Sessions,
+        spectators::Spectators,
+    },
+    users::{Role, UserId, Users},
 };
 
 pub use handle_authentication::AuthenticationError;
+pub use sessions::SessionSettings;
+pub use spectators::SpectatorSettings;
+pub use stats::ServerStats;
 
 const DELAY_BETWEEN_UPDATES: Duration = Duration::from_millis(10);
 
 /// Shared state, can be shared using `.clone()`.
 pub struct Server<W: P2Write + Unpin> {
-    ratelimit: RatelimitSettings,
+    /// Indexed by `CanvasId`, the first canvas is the default canvas.
+    canvases: Arc<Vec<Canvas>>,
     /// NOTE: You may not wait for a lock on this Mutex while holding a lock to a Mutex
     /// which is (or was) contained in the HashMap, as this may result in a deadlock.
     /// Always lock this Mutex before you lock an inner Mutex, if you have to hold two locks at the same time.
+    #[allow(clippy::type_complexity)]
     active_connections: Arc<Mutex<HashMap<UserId, Arc<Mutex<ActiveConnectionData<W>>>>>>,
-    /// Recently modified pixels
-    modified_pixels: Arc<Mutex<BTreeMap<Coordinate, Color>>>,
-    /// used to batch updates together so that more groups can be built
-    update_task: Arc<Mutex<Option<JoinHandle<()>>>>,
+    pub stats: Arc<ServerStats>,
+    users: Users,
+    started_at: SystemTime,
+    /// `None` if spectators are disabled
+    spectator_settings: Option<Arc<SpectatorSettings>>,
+    spectators: Arc<Spectators>,
+    /// `None` if session tokens are disabled
+    sessions: Option<Arc<Sessions>>,
+    /// Further connections are rejected while this many are open, unless it is `None`.
+    max_connections: Option<usize>,
+    /// Set to `true` when the server should stop accepting connections
+    shutdown: Arc<watch::Sender<bool>>,
+}
+
+/// The reason why a Put did not change a pixel.
+/// The values are used in PutRejected messages.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PutRejected {
+    /// The pixel is outside of the canvas' current bounds
+    OutOfBounds = 0x01,
+    /// The color is not in the canvas' palette
+    NotInPalette = 0x02,
+    /// The user has no pixels left and has to wait for the cooldown
+    Cooldown = 0x03,
+    /// The user's role doesn't allow painting
+    NotAllowed = 0x04,
+    /// The pixel is in a protected area which the user may not paint
+    Protected = 0x05,
+    /// The user has been muted. Muted users are not told that their Puts are ignored.
+    Muted = 0x06,
 }
 
 impl<W: P2Write + Unpin> Server<W> {
-    pub fn new(ratelimit: RatelimitSettings) -> Self {
-        Self {
-            ratelimit,
+    /// Restores the canvases from their history, if the history is enabled.
+    pub async fn new(config: &Config, users: Users) -> tokio::io::Result<Self> {
+        let mut canvases = Vec::with_capacity(config.canvases.len());
+        for (name, settings) in &config.canvases {
+            let mut pixels = HashMap::new();
+            let history = match &config.history {
+                Some(history_settings) => {
+                    let directory = history_settings.canvas_directory(name);
+                    for_each_edit(&directory, &EditFilter::default(), |edit| {
+                        pixels.insert(edit.coord, edit.new_color);
+                    })
+                    .await?;
+                    Some(HistoryLog::open(directory, history_settings.rotation).await?)
+                }
+                None => None,
+            };
+            canvases.push(Canvas::new(name.clone(), settings.clone(), pixels, history));
+        }
+        Ok(Self {
+            canvases: Arc::new(canvases),
             active_connections: Default::default(),
-            modified_pixels: Arc::new(Mutex::new(BTreeMap::new())),
-            update_task: Arc::new(Mutex::new(None)),
+            stats: Default::default(),
+            users,
+            started_at: SystemTime::now(),
+            spectator_settings: config.spectators.clone().map(Arc::new),
+            spectators: Default::default(),
+            sessions: match &config.sessions {
+                Some(settings) => Some(Arc::new(
+                    Sessions::new(settings).map_err(tokio::io::Error::other)?,
+                )),
+                None => None,
+            },
+            max_connections: config.max_connections,
+            shutdown: Arc::new(watch::channel(false).0),
+        })
+    }
+
+    pub fn users(&self) -> &Users {
+        &self.users
+    }
+
+    /// `None` if session tokens are disabled
+    pub fn sessions(&self) -> Option<&Sessions> {
+        self.sessions.as_deref()
+    }
+
+    fn canvas(&self, canvas: CanvasId) -> &Canvas {
+        &self.canvases[canvas.0]
+    }
+
+    pub fn canvas_ids(&self) -> impl Iterator<Item = CanvasId> + use<W> {
+        (0..self.canvases.len()).map(CanvasId)
+    }
+
+    pub fn canvas_by_name(&self, name: &str) -> Option<CanvasId> {
+        self.canvases
+            .iter()
+            .position(|canvas| canvas.name == name)
+            .map(CanvasId)
+    }
+
+    pub fn canvas_name(&self, canvas: CanvasId) -> &str {
+        &self.canvas(canvas).name
+    }
+
+    /// The default ratelimit for clients using this canvas.
+    pub fn ratelimit(&self, canvas: CanvasId) -> RatelimitSettings {
+        self.canvas(canvas).settings.ratelimit
+    }
+
+    /// Changes the pixel if the user is allowed to, and counts the rejection otherwise.
+    pub async fn put(
+        &self,
+        canvas: CanvasId,
+        user: &UserId,
+        coord: Coordinate,
+        color: Color,
+    ) -> Result<(), PutRejected> {
+        let result = self.try_put(canvas, user, coord, color).await;
+        match result {
+            Ok(()) => ServerStats::count(&self.stats.accepted_puts, 1),
+            Err(reason) => ServerStats::count(self.stats.rejected_puts(reason), 1),
+        }
+        result
+    }
+
+    async fn try_put(
+        &self,
+        canvas: CanvasId,
+        user: &UserId,
+        coord: Coordinate,
+        color: Color,
+    ) -> Result<(), PutRejected> {
+        let settings = &self.canvas(canvas).settings;
+        if !settings.bounds.contains(coord, SystemTime::now()) {
+            return Err(PutRejected::OutOfBounds);
+        }
+        let role = self.users.role(user).await;
+        if role < Role::Painter {
+            return Err(PutRejected::NotAllowed);
+        }
+        if self.users.is_muted(user).await {
+            return Err(PutRejected::Muted);
+        }
+        if !settings
+            .protected
+            .iter()
+            .all(|protected| protected.allows(coord, user, role))
+        {
+            return Err(PutRejected::Protected);
+        }
+        let color = match &settings.palette {
+            Some(palette) => palette.apply(color).ok_or(PutRejected::NotInPalette)?,
+            None => color,
+        };
+        // the admin user places many pixels at once, for example when importing images
+        if let Some(pixel_cooldown) = &settings.pixel_cooldown
+            && *user != UserId::admin()
+        {
+            let now = Instant::now();
+            let mut pixel_cooldowns = self.canvas(canvas).pixel_cooldowns.lock().await;
+            if !pixel_cooldowns.try_take_pixel(pixel_cooldown, user, now) {
+                return Err(PutRejected::Cooldown);
+            }
+            pixel_cooldowns.forget_refilled(now);
         }
+        self.set_pixel(canvas, user, coord, color).await;
+        Ok(())
+    }
+
+    /// The current color of the pixel, or `None` if it has never been painted.
+    pub async fn pixel(&self, canvas: CanvasId, coord: Coordinate) -> Option<Color> {
+        self.canvas(canvas).pixels.lock().await.get(&coord).copied()
+    }
+
+    /// Renders the area of the canvas, where pixels which have never been painted are `background`,
+    /// or the canvas' background if that is `None`.
+    pub async fn render(
+        &self,
+        canvas: CanvasId,
+        area: Area,
+        background: Option<Color>,
+    ) -> RgbImage {
+        let canvas = self.canvas(canvas);
+        let background = background.unwrap_or(canvas.settings.background);
+        RgbImage::render(area, background, canvas.pixels.lock().await.iter())
+    }
+
+    /// Returns a tile of the canvas as a PNG image, and the tile's generation at the time it was rendered.
+    /// Tiles are cached until one of their pixels changes.
+    pub async fn tile_png(
+        &self,
+        canvas: CanvasId,
+        tile: Tile,
+    ) -> Result<(Arc<Vec<u8>>, u64), png::EncodingError> {
+        let canvas = self.canvas(canvas);
+        let mut tiles = canvas.tiles.lock().await;
+        let index = canvas.tile_index.lock().await;
+        let generation = index.generation(tile);
+        if let Some(png) = tiles.get(tile, generation) {
+            return Ok((png, generation));
+        }
+        let pixels = index.pixels(tile);
+        drop(index);
+        let background = canvas.settings.background;
+        let png = tokio::task::spawn_blocking(move || {
+            let mut image = RgbImage::new(TILE_SIZE, TILE_SIZE, background);
+            for (x, y, color) in pixels {
+                image.set(x, y, color);
+            }
+            image.encode_png()
+        })
+        .await
+        .expect("rendering a tile doesn't panic")?;
+        let png = Arc::new(png);
+        tiles.insert(
+            tile,
+            generation,
+            Arc::clone(&png),
+            &*canvas.tile_index.lock().await,
+        );
+        Ok((png, generation))
+    }
+
+    /// A number which changes whenever the tile changes.
+    pub async fn tile_generation(&self, canvas: CanvasId, tile: Tile) -> u64 {
+        self.canvas(canvas).tile_index.lock().await.generation(tile)
     }
 
-    pub async fn put(&self, coord: Coordinate, color: Color) {
-        self.modified_pixels.lock().await.insert(coord, color);
-        let mut update_task = self.update_task.lock().await;
-        let modified_pixels = Arc::clone(&self.modified_pixels);
+    /// When the server was started, in milliseconds since the unix epoch.
+    pub fn started_at(&self) -> u128 {
+        self.started_at
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis()
+    }
+
+    /// Calls `f` with each edit in the canvas' history which matches the filter, oldest first.
+    /// No edits are appended to the history in the meantime.
+    /// Fails with `ErrorKind::Unsupported` if the history is disabled.
+    pub async fn for_each_edit(
+        &self,
+        canvas: CanvasId,
+        filter: &EditFilter,
+        f: impl FnMut(Edit),
+    ) -> tokio::io::Result<()> {
+        let Some(mut history) = self.canvas(canvas).lock_history().await else {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::Unsupported,
+                "history is disabled for this canvas",
+            ));
+        };
+        history.flush().await?;
+        for_each_edit(history.directory(), filter, f).await
+    }
+
+    /// Makes `accept_connections` disconnect all clients and return.
+    pub fn request_shutdown(&self) {
+        self.shutdown.send_replace(true);
+    }
+
+    /// Waits until `request_shutdown` has been called.
+    pub async fn shutdown_requested(&self) {
+        self.shutdown
+            .subscribe()
+            .wait_for(|shutdown| *shutdown)
+            .await
+            .ok();
+    }
+
+    /// Writes all buffered edits to the history files.
+    pub async fn flush_history(&self) -> tokio::io::Result<()> {
+        for canvas in self.canvases.iter() {
+            if let Some(mut history) = canvas.lock_history().await {
+                history.flush().await?;
+            }
+        }
+        Ok(())
+    }
+
+    /// The area in which pixels can currently be placed.
+    pub fn current_bounds(&self, canvas: CanvasId) -> Area {
+        self.canvas(canvas)
+            .settings
+            .bounds
+            .current(SystemTime::now())
+    }
+
+    pub fn palette(&self, canvas: CanvasId) -> Option<&Palette> {
+        self.canvas(canvas).settings.palette.as_ref()
+    }
+
+    /// Sends a Bounds message to all clients which use the canvas and have enabled the extension
+    /// whenever the canvas' bounds change. Never returns.
+    pub async fn announce_bounds_changes(self, canvas: CanvasId) {
+        loop {
+            let Some(next_change) = self
+                .canvas(canvas)
+                .settings
+                .bounds
+                .next_change(SystemTime::now())
+            else {
+                return std::future::pending().await;
+            };
+            // SystemTime may jump, so sleep in short intervals to not miss a change by much
+            let until_next_change = next_change
+                .duration_since(SystemTime::now())
+                .unwrap_or(Duration::ZERO)
+                .min(Duration::from_secs(60));
+            tokio::time::sleep(until_next_change).await;
+            if SystemTime::now() >= next_change {
+                let area = self.current_bounds(canvas);
+                self.broadcast(canvas, Extension::Bounds, ServerMessage::Bounds { area })
+                    .await;
+            }
+        }
+    }
+
+    /// Sends the message to every connected client which uses the canvas and has enabled the extension.
+    pub async fn broadcast(&self, canvas: CanvasId, extension: Extension, message: ServerMessage) {
+        let mut encoded = Vec::new();
+        message.write_p2encoded(&mut encoded).await.unwrap();
+        let active_connections = self.active_connections.lock().await;
+        for (_, connection) in active_connections.iter() {
+            let mut connection = connection.lock().await;
+            if !connection.replaced
+                && connection.canvas == canvas
+                && connection.extensions.is_enabled(extension)
+                && (connection.write.write_all(&encoded).await.is_err()
+                    || connection.write.flush().await.is_err())
+            {
+                connection.replaced = true;
+            }
+        }
+    }
+
+    /// The user's current pixel stock, if pixel cooldowns are enabled on the canvas.
+    pub async fn cooldown_status(&self, canvas: CanvasId, user: &UserId) -> Option<CooldownStatus> {
+        let canvas = self.canvas(canvas);
+        let pixel_cooldown = canvas.settings.pixel_cooldown.as_ref()?;
+        Some(
+            canvas
+                .pixel_cooldowns
+                .lock()
+                .await
+                .status(pixel_cooldown, user, Instant::now()),
+        )
+    }
+
+    /// Changes the pixel, records the change in the history, and sends an Update to subscribed clients soon,
+    /// bypassing all checks done in `put`.
+    pub async fn set_pixel(
+        &self,
+        canvas: CanvasId,
+        user: &UserId,
+        coord: Coordinate,
+        color: Color,
+    ) {
+        let canvas_data = self.canvas(canvas);
+        let mut pixels = canvas_data.pixels.lock().await;
+        let old_color = pixels.insert(coord, color);
+        canvas_data.tile_index.lock().await.insert(coord, color);
+        if let Some(queued_edits) = &canvas_data.queued_edits {
+            // appended by the update task, so that the files aren't written while `pixels` is locked
+            queued_edits
+                .send(Edit {
+                    time: SystemTime::now(),
+                    user: user.clone(),
+                    coord,
+                    old_color,
+                    new_color: color,
+                })
+                .ok();
+        }
+        drop(pixels);
+        canvas_data
+            .modified_pixels
+            .lock()
+            .await
+            .insert(coord, color);
+        let mut update_task = canvas_data.update_task.lock().await;
+        let canvases = Arc::clone(&self.canvases);
         let active_connections = Arc::clone(&self.active_connections);
+        let stats = Arc::clone(&self.stats);
         if update_task.as_ref().is_none_or(|task| task.is_finished()) {
             *update_task = Some(tokio::task::spawn(async move {
                 tokio::time::sleep(DELAY_BETWEEN_UPDATES).await;
-                Self::transmit_modified_pixels(&*modified_pixels, &*active_connections).await;
+                let canvas_data = &canvases[canvas.0];
+                Self::transmit_modified_pixels(canvas_data, canvas, &active_connections, &stats)
+                    .await;
+                if let Some(mut history) = canvas_data.lock_history().await
+                    && let Err(e) = history.flush().await
+                {
+                    tracing::error!(
+                        canvas = canvas_data.name,
+                        "Could not write to the history: {e}"
+                    );
+                }
             }));
         }
     }
 
+    #[allow(clippy::type_complexity)]
     async fn transmit_modified_pixels(
-        modified_pixels: &Mutex<BTreeMap<Coordinate, Color>>,
+        canvas: &Canvas,
+        canvas_id: CanvasId,
         active_connections: &Mutex<HashMap<UserId, Arc<Mutex<ActiveConnectionData<W>>>>>,
+        stats: &ServerStats,
     ) {
-        let mut modified_pixels = modified_pixels.lock().await;
+        let started = Instant::now();
+        let mut modified_pixels = canvas.modified_pixels.lock().await;
         if modified_pixels.is_empty() {
             return;
         }
+        let pixel_count = modified_pixels.len();
 
         // find connected groups of pixels, but not necessarily rectangles
         let mut groups = Vec::<BTreeMap<Coordinate, Color>>::new();
@@ -155,10 +557,11 @@ impl<W: P2Write + Unpin> Server<W> {
                 message,
             ));
         }
+        let mut recipients = 0;
         let active_connections = active_connections.lock().await;
-        for (_, connection) in active_connections.iter() {
+        for (user, connection) in active_connections.iter() {
             let mut connection = connection.lock().await;
-            if !connection.replaced {
+            if !connection.replaced && connection.canvas == canvas_id {
                 let mut sent_any = false;
                 for (area, message) in messages.iter() {
                     if connection
@@ -166,29 +569,132 @@ impl<W: P2Write + Unpin> Server<W> {
                         .is_some_and(|subscribed_area| area.intersects(subscribed_area))
                     {
                         sent_any = true;
-                        if connection.write.write_all(&message).await.is_err() {
+                        ServerStats::count(&stats.updates_sent, 1);
+                        ServerStats::count(&stats.update_bytes_sent, message.len() as u64);
+                        if let Err(e) = connection.write.write_all(message).await {
+                            tracing::debug!(
+                                user = user.username(),
+                                "Could not send an Update: {e}"
+                            );
                             connection.replaced = true;
                         }
                     }
                 }
                 if sent_any {
-                    if connection.write.flush().await.is_err() {
+                    recipients += 1;
+                    if let Err(e) = connection.write.flush().await {
+                        tracing::debug!(user = user.username(), "Could not send Updates: {e}");
                         connection.replaced = true;
                     }
                 }
             }
         }
         drop(active_connections);
+        stats
+            .update_duration_micros
+            .observe_duration(started.elapsed());
+        stats.update_batch_pixels.observe(pixel_count as u64);
+        tracing::debug!(
+            canvas = canvas.name,
+            pixels = pixel_count,
+            messages = messages.len(),
+            recipients,
+            elapsed = ?started.elapsed(),
+            "Sent Updates"
+        );
     }
 }
 
 impl<W: P2Write + Unpin> Clone for Server<W> {
     fn clone(&self) -> Self {
         Self {
-            ratelimit: self.ratelimit,
+            canvases: Arc::clone(&self.canvases),
             active_connections: Arc::clone(&self.active_connections),
-            modified_pixels: Arc::clone(&self.modified_pixels),
-            update_task: Arc::clone(&self.update_task),
+            stats: Arc::clone(&self.stats),
+            users: self.users.clone(),
+            started_at: self.started_at,
+            spectator_settings: self.spectator_settings.clone(),
+            spectators: Arc::clone(&self.spectators),
+            sessions: self.sessions.clone(),
+            max_connections: self.max_connections,
+            shutdown: Arc::clone(&self.shutdown),
         }
     }
 }
+
+#[tokio::test]
+async fn test_put_permissions() {
+    use crate::canvas::ProtectedArea;
+
+    let mut config = Config {
+        history: None,
+        ..Default::default()
+    };
+    config.canvases[0].1.protected.push(ProtectedArea {
+        area: Area::try_new(Coordinate { x: 0, y: 0 }, Coordinate { x: 9, y: 9 }).unwrap(),
+        min_role: Role::Moderator,
+        users: vec![UserId::new("artist".to_owned())],
+    });
+    let users = Users::from_toml(
+        r#"
+        painter = { otp.Static = 1 }
+        artist = { otp.Static = 1 }
+        viewer = { otp.Static = 1, role = "viewer" }
+        moderator = { otp.Static = 1, role = "moderator" }
+        "#,
+    )
+    .unwrap();
+    let server = Server::<Vec<u8>>::new(&config, users).await.unwrap();
+    let put = async |user: &str, x| {
+        server
+            .put(
+                CanvasId::DEFAULT,
+                &UserId::new(user.to_owned()),
+                Coordinate { x, y: 0 },
+                Color { r: 0, g: 0, b: 0 },
+            )
+            .await
+    };
+
+    assert_eq!(put("painter", 10).await, Ok(()));
+    assert_eq!(put("painter", 0).await, Err(PutRejected::Protected));
+    assert_eq!(put("artist", 0).await, Ok(()));
+    assert_eq!(put("moderator", 0).await, Ok(()));
+    assert_eq!(put("viewer", 10).await, Err(PutRejected::NotAllowed));
+    server.mute(&UserId::new("painter".to_owned()), None).await;
+    assert_eq!(put("painter", 10).await, Err(PutRejected::Muted));
+    assert_eq!(ServerStats::get(&server.stats.rejected_puts_protected), 1);
+    assert_eq!(ServerStats::get(&server.stats.rejected_puts_not_allowed), 1);
+}
+
+#[tokio::test]
+async fn test_tile_cache() {
+    let config = Config {
+        history: None,
+        ..Default::default()
+    };
+    let server = Server::<Vec<u8>>::new(&config, Users::from_toml("").unwrap())
+        .await
+        .unwrap();
+    let user = UserId::new("painter".to_owned());
+    let (red, blue) = (Color { r: 31, g: 0, b: 0 }, Color { r: 0, g: 0, b: 31 });
+    let tile = Tile { z: 7, x: 64, y: 64 };
+    server
+        .set_pixel(CanvasId::DEFAULT, &user, Coordinate { x: 0, y: 0 }, red)
+        .await;
+    let (png, generation) = server.tile_png(CanvasId::DEFAULT, tile).await.unwrap();
+    let (cached, _) = server.tile_png(CanvasId::DEFAULT, tile).await.unwrap();
+    assert!(Arc::ptr_eq(&png, &cached));
+    server
+        .set_pixel(CanvasId::DEFAULT, &user, Coordinate { x: 0, y: 0 }, blue)
+        .await;
+    let (changed, new_generation) = server.tile_png(CanvasId::DEFAULT, tile).await.unwrap();
+    assert_ne!(generation, new_generation);
+    assert_ne!(png, changed);
+    // pixels outside of the tile don't change it
+    server
+        .set_pixel(CanvasId::DEFAULT, &user, Coordinate { x: 1000, y: 0 }, red)
+        .await;
+    let (unchanged, _) = server.tile_png(CanvasId::DEFAULT, tile).await.unwrap();
+    assert!(Arc::ptr_eq(&changed, &unchanged));
+}
@@ -0,0 +1,190 @@
+use std::{
+    collections::HashMap,
+    time::{Duration, SystemTime},
+};
+
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use tokio::sync::Mutex;
+
+use crate::users::UserId;
+
+/// The length of a session token's signature in bytes
+pub const SIGNATURE_LENGTH: usize = 32;
+
+/// Lets clients authenticate again with a session token (`0xFF A1`) instead of an OTP,
+/// for example after a flaky connection has been closed.
+#[derive(Clone, Debug, PartialEq)]
+pub struct SessionSettings {
+    /// How long a token can be used after it was issued
+    pub lifetime: Duration,
+}
+
+/// Issues and verifies session tokens.
+///
+/// The signing key is random and, like revocations, only kept in memory,
+/// so every token becomes invalid when the server restarts.
+pub struct Sessions {
+    key: [u8; 32],
+    lifetime: Duration,
+    /// Tokens issued to a user at or before this time (in milliseconds since the Unix epoch) are invalid.
+    revoked: Mutex<HashMap<UserId, u64>>,
+}
+
+/// The parts of a token as sent in a Resume message
+pub struct SessionToken {
+    pub username: Vec<u8>,
+    /// In milliseconds since the Unix epoch
+    pub issued_at: u64,
+    pub signature: [u8; SIGNATURE_LENGTH],
+}
+
+impl Sessions {
+    pub fn new(settings: &SessionSettings) -> Result<Self, getrandom::Error> {
+        let mut key = [0; 32];
+        getrandom::fill(&mut key)?;
+        Ok(Self {
+            key,
+            lifetime: settings.lifetime,
+            revoked: Default::default(),
+        })
+    }
+
+    /// Returns a token for the user, encoded like a Resume message without the message type:
+    /// the username as in an Authentication message, the time when it was issued as 8 bytes, and a signature,
+    /// all in hexadecimal, so that the token never contains `0xFF`.
+    /// Returns `None` for users who can't authenticate normally, like spectators.
+    pub fn issue(&self, user: &UserId) -> Option<Vec<u8>> {
+        if !UserId::is_valid_username(user.username()) {
+            return None;
+        }
+        let username = user.username().as_bytes();
+        let mut token = vec![(username.len() - 1) as u8];
+        token.extend_from_slice(username);
+        token.extend_from_slice(&now().to_be_bytes());
+        let signature = self.mac(&token).finalize().into_bytes();
+        token.extend_from_slice(&signature);
+        Some(encode_hex(&token))
+    }
+
+    /// Returns the user if the token was issued by this server, hasn't expired, and hasn't been revoked.
+    /// The caller still has to check that the user exists and isn't banned.
+    pub async fn verify(&self, token: &SessionToken) -> Option<UserId> {
+        let mut signed = vec![(token.username.len().checked_sub(1)?) as u8];
+        signed.extend_from_slice(&token.username);
+        signed.extend_from_slice(&token.issued_at.to_be_bytes());
+        self.mac(&signed).verify_slice(&token.signature).ok()?;
+        let lifetime = u64::try_from(self.lifetime.as_millis()).unwrap_or(u64::MAX);
+        if token.issued_at.saturating_add(lifetime) < now() {
+            return None;
+        }
+        let user = UserId::new(String::from_utf8(token.username.clone()).ok()?);
+        if self
+            .revoked
+            .lock()
+            .await
+            .get(&user)
+            .is_some_and(|revoked_at| token.issued_at <= *revoked_at)
+        {
+            return None;
+        }
+        Some(user)
+    }
+
+    /// Invalidates every token which has been issued to the user so far.
+    pub async fn revoke(&self, user: &UserId) {
+        self.revoked.lock().await.insert(user.clone(), now());
+    }
+
+    fn mac(&self, message: &[u8]) -> Hmac<Sha256> {
+        let mut mac =
+            Hmac::<Sha256>::new_from_slice(&self.key).expect("HMAC accepts keys of any length");
+        mac.update(message);
+        mac
+    }
+}
+
+fn encode_hex(bytes: &[u8]) -> Vec<u8> {
+    const DIGITS: &[u8; 16] = b"0123456789abcdef";
+    bytes
+        .iter()
+        .flat_map(|byte| [DIGITS[(byte >> 4) as usize], DIGITS[(byte & 0xF) as usize]])
+        .collect()
+}
+
+/// Decodes a part of a token. Returns `None` if `hex` isn't hexadecimal.
+pub fn decode_hex(hex: &[u8]) -> Option<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) {
+        return None;
+    }
+    hex.chunks_exact(2)
+        .map(|pair| {
+            let digit = |digit: u8| (digit as char).to_digit(16);
+            Some((digit(pair[0])? * 16 + digit(pair[1])?) as u8)
+        })
+        .collect()
+}
+
+/// In milliseconds, so that tokens issued right after a revocation are valid
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis() as u64
+}
+
+#[tokio::test]
+async fn test_session_tokens() {
+    let sessions = Sessions::new(&SessionSettings {
+        lifetime: Duration::from_secs(60),
+    })
+    .unwrap();
+    let user = UserId::new("alice".to_owned());
+    let parse = |token: &[u8]| {
+        let token = decode_hex(token).unwrap();
+        SessionToken {
+            username: token[1..token.len() - 8 - SIGNATURE_LENGTH].to_vec(),
+            issued_at: u64::from_be_bytes(
+                token[token.len() - 8 - SIGNATURE_LENGTH..token.len() - SIGNATURE_LENGTH]
+                    .try_into()
+                    .unwrap(),
+            ),
+            signature: token[token.len() - SIGNATURE_LENGTH..].try_into().unwrap(),
+        }
+    };
+
+    let token = parse(&sessions.issue(&user).unwrap());
+    assert_eq!(sessions.verify(&token).await, Some(user.clone()));
+    assert!(sessions.issue(&UserId::spectator(1)).is_none());
+    // the length of a 256 byte username and the signature would contain `0xFF` without the encoding
+    let long_name = UserId::new("a".repeat(256));
+    for _ in 0..100 {
+        let token = sessions.issue(&long_name).unwrap();
+        assert!(!token.contains(&0xFF));
+        assert!(token.starts_with(b"ff"));
+        assert_eq!(
+            sessions.verify(&parse(&token)).await,
+            Some(long_name.clone())
+        );
+    }
+    assert_eq!(decode_hex(b"00fF7a"), Some(vec![0x00, 0xFF, 0x7A]));
+    assert_eq!(decode_hex(b"0g"), None);
+    assert_eq!(decode_hex(b"0"), None);
+
+    let forged = SessionToken {
+        username: b"mallory".to_vec(),
+        ..parse(&sessions.issue(&user).unwrap())
+    };
+    assert_eq!(sessions.verify(&forged).await, None);
+    let expired = SessionToken {
+        issued_at: token.issued_at - 120_000,
+        ..parse(&sessions.issue(&user).unwrap())
+    };
+    assert_eq!(sessions.verify(&expired).await, None);
+
+    sessions.revoke(&user).await;
+    assert_eq!(sessions.verify(&token).await, None);
+    tokio::time::sleep(Duration::from_millis(2)).await;
+    let new_token = parse(&sessions.issue(&user).unwrap());
+    assert_eq!(sessions.verify(&new_token).await, Some(user));
+}
@@ -0,0 +1,196 @@
+use std::sync::{
+    Arc,
+    atomic::{AtomicU64, AtomicUsize, Ordering},
+};
+
+use crate::{
+    ratelimit::RatelimitSettings,
+    server::{P2Write, Server},
+    users::UserId,
+};
+
+/// Connections which can only Sub and receive Updates, for example to show the canvas on a website.
+#[derive(Clone)]
+pub struct SpectatorSettings {
+    /// Whether clients can become spectators without a user account (`0xFF A2`).
+    pub allow_anonymous: bool,
+    /// Users who authenticate normally but are always spectators.
+    /// Unlike other users, they can be connected multiple times at once.
+    pub guest_users: Vec<UserId>,
+    pub max_connections: usize,
+    /// Replaces the canvas' ratelimit for spectators.
+    pub ratelimit: RatelimitSettings,
+}
+
+/// Counts spectators, so that `SpectatorSettings::max_connections` can be enforced.
+#[derive(Default)]
+pub struct Spectators {
+    connected: AtomicUsize,
+    next_id: AtomicU64,
+}
+
+/// A spectator's place in the connection limit, which is freed when this is dropped.
+pub struct SpectatorSlot {
+    spectators: Arc<Spectators>,
+    /// A unique id, used as the spectator's user id while it is connected.
+    pub id: UserId,
+}
+
+impl Drop for SpectatorSlot {
+    fn drop(&mut self) {
+        self.spectators.connected.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+impl<W: P2Write + Unpin> Server<W> {
+    pub fn spectator_settings(&self) -> Option<&SpectatorSettings> {
+        self.spectator_settings.as_deref()
+    }
+
+    /// Whether the user is a guest user, who may only spectate.
+    pub fn is_guest(&self, user: &UserId) -> bool {
+        self.spectator_settings()
+            .is_some_and(|settings| settings.guest_users.contains(user))
+    }
+
+    /// The number of connected spectators, including guest users.
+    pub fn spectator_count(&self) -> usize {
+        self.spectators.connected.load(Ordering::Relaxed)
+    }
+
+    /// Returns `None` if spectators are disabled or if the maximum number of spectators are connected.
+    pub fn try_add_spectator(&self) -> Option<SpectatorSlot> {
+        let max_connections = self.spectator_settings()?.max_connections;
+        self.spectators
+            .connected
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |connected| {
+                (connected < max_connections).then_some(connected + 1)
+            })
+            .ok()?;
+        let id = self.spectators.next_id.fetch_add(1, Ordering::Relaxed);
+        Some(SpectatorSlot {
+            spectators: Arc::clone(&self.spectators),
+            id: UserId::spectator(id),
+        })
+    }
+}
+
+#[tokio::test]
+async fn test_spectators() {
+    use std::time::Duration;
+
+    use futures_util::{SinkExt, StreamExt};
+    use tokio_tungstenite::tungstenite::Message;
+
+    use crate::{
+        config::Config,
+        data::{Color, Coordinate},
+        protocol::P2Encodable,
+        server::{CanvasId, ServerStats, WebsocketServer},
+        users::Users,
+    };
+
+    let config = Config {
+        history: None,
+        spectators: Some(SpectatorSettings {
+            allow_anonymous: true,
+            guest_users: vec![UserId::new("guest".to_owned())],
+            max_connections: 2,
+            ratelimit: RatelimitSettings::new(Duration::from_millis(1)),
+        }),
+        ..Default::default()
+    };
+    let users =
+        Users::from_toml("painter = { otp.Static = 1 }\nguest = { otp.Static = 1 }").unwrap();
+    let server = WebsocketServer::new(&config, users).await.unwrap();
+    let address = tokio::net::TcpListener::bind("127.0.0.1:0")
+        .await
+        .unwrap()
+        .local_addr()
+        .unwrap();
+    tokio::task::spawn(server.clone().accept_connections(address));
+    let connect = async |login: Vec<u8>| {
+        let mut client = loop {
+            if let Ok((client, _)) =
+                tokio_tungstenite::connect_async(format!("ws://{address}")).await
+            {
+                break client;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        };
+        client.send(Message::Binary(login.into())).await.unwrap();
+        client
+    };
+    let login = |username: &str| {
+        let mut authentication = vec![0xFF, 0xA0, username.len() as u8 - 1];
+        authentication.extend_from_slice(username.as_bytes());
+        authentication.extend_from_slice(&[0x00, 0x00, 0x00, 0x01]);
+        authentication
+    };
+    let spectate = || vec![0xFF, 0xA2];
+    // Enables the Rejections extension and Puts at `x`, then selects the default canvas
+    // and waits for the answer, so that the Put has been handled when this returns.
+    // Returns whether the Put was rejected, or `None` if the connection was closed instead.
+    let put = async |client: &mut tokio_tungstenite::WebSocketStream<_>, x: i16| {
+        let mut messages = vec![0xFF, 0xAE, 0x05, 0xFF, 0xD0];
+        Coordinate { x, y: 0 }
+            .write_p2encoded(&mut messages)
+            .await
+            .unwrap();
+        Color { r: 1, g: 2, b: 3 }
+            .write_p2encoded(&mut messages)
+            .await
+            .unwrap();
+        messages.extend_from_slice(b"\xFF\xAC\x06default");
+        client.send(Message::Binary(messages.into())).await.ok()?;
+        let mut rejected = false;
+        while let Some(Ok(message)) = client.next().await {
+            let message = message.into_data();
+            rejected |= message.starts_with(&[0xFF, 0x85]);
+            if message.starts_with(&[0xFF, 0x84]) {
+                return Some(rejected);
+            }
+        }
+        None
+    };
+    let pixel = async |x: i16| {
+        server
+            .pixel(CanvasId::DEFAULT, Coordinate { x, y: 0 })
+            .await
+    };
+
+    let mut painter = connect(login("painter")).await;
+    assert_eq!(put(&mut painter, 0).await, Some(false));
+    assert_eq!(pixel(0).await, Some(Color { r: 1, g: 2, b: 3 }));
+
+    // neither anonymous spectators nor guest users can Put, and their Puts are ignored without a rejection
+    let mut anonymous = connect(spectate()).await;
+    assert_eq!(put(&mut anonymous, 1).await, Some(false));
+    assert_eq!(pixel(1).await, None);
+    let mut guest = connect(login("guest")).await;
+    assert_eq!(put(&mut guest, 2).await, Some(false));
+    assert_eq!(pixel(2).await, None);
+    assert_eq!(server.spectator_count(), 2);
+
+    // the spectator limit is reached
+    let mut rejected = connect(spectate()).await;
+    assert_eq!(put(&mut rejected, 3).await, None);
+    let mut rejected = connect(login("guest")).await;
+    assert_eq!(put(&mut rejected, 3).await, None);
+    while ServerStats::get(&server.stats.auth_failures_too_many_spectators) < 2 {
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+    assert_eq!(server.spectator_count(), 2);
+    assert_eq!(server.connections().await.len(), 3);
+
+    // a disconnected spectator frees its place
+    anonymous.close(None).await.unwrap();
+    while server.spectator_count() > 1 {
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+    let mut anonymous = connect(spectate()).await;
+    assert_eq!(put(&mut anonymous, 3).await, Some(false));
+    assert_eq!(pixel(3).await, None);
+    assert_eq!(server.spectator_count(), 2);
+    server.request_shutdown();
+}
@@ -0,0 +1,126 @@
+use std::{
+    sync::atomic::{AtomicU64, Ordering},
+    time::Duration,
+};
+
+use crate::server::{AuthenticationError, PutRejected};
+
+/// The number of buckets of a `Histogram`, not counting the implicit `+Inf` bucket
+pub const HISTOGRAM_BUCKETS: usize = 12;
+
+/// Counters describing what the server has done since it was started.
+#[derive(Debug, Default)]
+pub struct ServerStats {
+    /// Puts which changed a pixel
+    pub accepted_puts: AtomicU64,
+    /// Messages which were ignored because the client exceeded its ratelimit
+    pub ratelimit_dropped_messages: AtomicU64,
+    /// Time spent waiting before handling messages of clients whose ratelimit blocks instead of dropping
+    pub ratelimit_blocked_micros: AtomicU64,
+    /// Ratelimited messages which were sent to clients
+    pub ratelimit_notices_sent: AtomicU64,
+    /// Puts which did not change a pixel, by reason
+    pub rejected_puts_out_of_bounds: AtomicU64,
+    pub rejected_puts_not_in_palette: AtomicU64,
+    pub rejected_puts_cooldown: AtomicU64,
+    pub rejected_puts_not_allowed: AtomicU64,
+    pub rejected_puts_protected: AtomicU64,
+    pub rejected_puts_muted: AtomicU64,
+    /// Connections which were rejected because the server had too many connections
+    pub rejected_connections: AtomicU64,
+    /// Failed attempts to accept a connection
+    pub accept_errors: AtomicU64,
+    /// WebSocket connections which haven't finished authenticating yet
+    pub pending_authentications: AtomicU64,
+    /// Failed authentications, by reason
+    pub auth_failures_username_not_utf8: AtomicU64,
+    pub auth_failures_no_such_user: AtomicU64,
+    pub auth_failures_invalid_one_time_password: AtomicU64,
+    pub auth_failures_banned: AtomicU64,
+    pub auth_failures_spectators_not_allowed: AtomicU64,
+    pub auth_failures_too_many_spectators: AtomicU64,
+    pub auth_failures_invalid_session_token: AtomicU64,
+    pub auth_failures_user_store: AtomicU64,
+    /// Update messages sent to clients, and their size in bytes
+    pub updates_sent: AtomicU64,
+    pub update_bytes_sent: AtomicU64,
+    /// How long it took to send the Updates for a batch of modified pixels, in microseconds
+    pub update_duration_micros: Histogram,
+    /// How many pixels were modified in a batch
+    pub update_batch_pixels: Histogram,
+}
+
+/// Counts values in exponential buckets, which hold values up to `4^i` for `i` in `0..HISTOGRAM_BUCKETS`.
+#[derive(Debug, Default)]
+pub struct Histogram {
+    buckets: [AtomicU64; HISTOGRAM_BUCKETS],
+    count: AtomicU64,
+    sum: AtomicU64,
+}
+
+impl Histogram {
+    pub fn observe(&self, value: u64) {
+        if let Some(bucket) = (0..HISTOGRAM_BUCKETS).find(|i| value <= Self::upper_bound(*i)) {
+            self.buckets[bucket].fetch_add(1, Ordering::Relaxed);
+        }
+        self.count.fetch_add(1, Ordering::Relaxed);
+        self.sum.fetch_add(value, Ordering::Relaxed);
+    }
+
+    pub fn observe_duration(&self, duration: Duration) {
+        self.observe(duration.as_micros().try_into().unwrap_or(u64::MAX));
+    }
+
+    pub fn upper_bound(bucket: usize) -> u64 {
+        4u64.pow(bucket as u32)
+    }
+
+    /// The number of values in each bucket, excluding values which are larger than the last bucket's bound
+    pub fn buckets(&self) -> [u64; HISTOGRAM_BUCKETS] {
+        std::array::from_fn(|i| self.buckets[i].load(Ordering::Relaxed))
+    }
+
+    pub fn count(&self) -> u64 {
+        self.count.load(Ordering::Relaxed)
+    }
+
+    pub fn sum(&self) -> u64 {
+        self.sum.load(Ordering::Relaxed)
+    }
+}
+
+impl ServerStats {
+    pub fn count(counter: &AtomicU64, amount: u64) {
+        counter.fetch_add(amount, Ordering::Relaxed);
+    }
+
+    pub fn rejected_puts(&self, reason: PutRejected) -> &AtomicU64 {
+        match reason {
+            PutRejected::OutOfBounds => &self.rejected_puts_out_of_bounds,
+            PutRejected::NotInPalette => &self.rejected_puts_not_in_palette,
+            PutRejected::Cooldown => &self.rejected_puts_cooldown,
+            PutRejected::NotAllowed => &self.rejected_puts_not_allowed,
+            PutRejected::Protected => &self.rejected_puts_protected,
+            PutRejected::Muted => &self.rejected_puts_muted,
+        }
+    }
+
+    pub fn auth_failures(&self, error: &AuthenticationError) -> &AtomicU64 {
+        match error {
+            AuthenticationError::UsernameNotUtf8 => &self.auth_failures_username_not_utf8,
+            AuthenticationError::NoSuchUser(_) => &self.auth_failures_no_such_user,
+            AuthenticationError::InvalidOneTimePassword => {
+                &self.auth_failures_invalid_one_time_password
+            }
+            AuthenticationError::Banned { .. } => &self.auth_failures_banned,
+            AuthenticationError::SpectatorsNotAllowed => &self.auth_failures_spectators_not_allowed,
+            AuthenticationError::TooManySpectators => &self.auth_failures_too_many_spectators,
+            AuthenticationError::InvalidSessionToken => &self.auth_failures_invalid_session_token,
+            AuthenticationError::UserStore(_) => &self.auth_failures_user_store,
+        }
+    }
+
+    pub fn get(counter: &AtomicU64) -> u64 {
+        counter.load(Ordering::Relaxed)
+    }
+}
@@ -0,0 +1,177 @@
+use std::{
+    collections::{BTreeMap, HashMap, btree_map::Entry},
+    sync::Arc,
+};
+
+use crate::{
+    data::{Color, Coordinate},
+    server::http::{MAX_ZOOM, TILE_ORIGIN, TILE_SIZE, Tile},
+};
+
+/// Rendered tiles are dropped when there are more than this many, starting with outdated ones.
+const MAX_CACHED_TILES: usize = 1024;
+
+/// What the tiles of every zoom level show, updated with every changed pixel,
+/// so that rendering a tile only has to look at the pixels of that tile.
+pub struct TileIndex {
+    /// For each zoom level, the canvas pixel shown at each tile pixel, keyed by the tile pixel's
+    /// position at that zoom level (relative to `TILE_ORIGIN`, `x` first, so that columns can be queried as ranges).
+    /// Each pixel of a scaled down tile shows the topmost, then leftmost painted pixel it covers.
+    shown: Vec<BTreeMap<(i32, i32), (Coordinate, Color)>>,
+    /// The generation at which each tile last changed. Tiles which have never changed are at generation 0.
+    generations: HashMap<Tile, u64>,
+    /// Incremented whenever a pixel changes
+    generation: u64,
+}
+
+impl TileIndex {
+    pub fn new(pixels: &HashMap<Coordinate, Color>) -> Self {
+        let mut index = Self {
+            shown: vec![BTreeMap::new(); MAX_ZOOM as usize + 1],
+            generations: HashMap::new(),
+            generation: 0,
+        };
+        for (coord, color) in pixels {
+            index.insert(*coord, *color);
+        }
+        index
+    }
+
+    /// Updates the tiles which show the pixel.
+    pub fn insert(&mut self, coord: Coordinate, color: Color) {
+        self.generation += 1;
+        // if a tile pixel doesn't change, neither do the larger tile pixels which contain it
+        for z in (0..=MAX_ZOOM).rev() {
+            let shift = MAX_ZOOM - z;
+            let position = (
+                (coord.x as i32 - TILE_ORIGIN) >> shift,
+                (coord.y as i32 - TILE_ORIGIN) >> shift,
+            );
+            match self.shown[z as usize].entry(position) {
+                Entry::Vacant(entry) => {
+                    entry.insert((coord, color));
+                }
+                Entry::Occupied(mut entry) => {
+                    let (shown, shown_color) = *entry.get();
+                    if (shown == coord && shown_color != color)
+                        || (coord.y, coord.x) < (shown.y, shown.x)
+                    {
+                        entry.insert((coord, color));
+                    } else {
+                        break;
+                    }
+                }
+            }
+            let tile = Tile {
+                z,
+                x: position.0 as u32 / TILE_SIZE,
+                y: position.1 as u32 / TILE_SIZE,
+            };
+            self.generations.insert(tile, self.generation);
+        }
+    }
+
+    /// A number which changes whenever the tile changes.
+    pub fn generation(&self, tile: Tile) -> u64 {
+        self.generations.get(&tile).copied().unwrap_or(0)
+    }
+
+    /// The painted pixels of the tile, as `(x, y, color)` relative to its top left corner.
+    pub fn pixels(&self, tile: Tile) -> Vec<(u32, u32, Color)> {
+        let shown = &self.shown[tile.z as usize];
+        let (left, top) = ((tile.x * TILE_SIZE) as i32, (tile.y * TILE_SIZE) as i32);
+        let mut pixels = Vec::new();
+        for x in left..left + TILE_SIZE as i32 {
+            for ((x, y), (_, color)) in shown.range((x, top)..(x, top + TILE_SIZE as i32)) {
+                pixels.push(((x - left) as u32, (y - top) as u32, *color));
+            }
+        }
+        pixels
+    }
+}
+
+/// Encoded tiles, and the generation of the tile they were rendered at.
+#[derive(Default)]
+pub struct TileCache {
+    tiles: HashMap<Tile, (u64, Arc<Vec<u8>>)>,
+}
+
+impl TileCache {
+    /// Returns the tile if it was rendered at this generation.
+    pub fn get(&self, tile: Tile, generation: u64) -> Option<Arc<Vec<u8>>> {
+        self.tiles
+            .get(&tile)
+            .filter(|(rendered_at, _)| *rendered_at == generation)
+            .map(|(_, png)| Arc::clone(png))
+    }
+
+    pub fn insert(&mut self, tile: Tile, generation: u64, png: Arc<Vec<u8>>, index: &TileIndex) {
+        if self.tiles.len() >= MAX_CACHED_TILES {
+            self.tiles
+                .retain(|tile, (rendered_at, _)| *rendered_at == index.generation(*tile));
+            if self.tiles.len() >= MAX_CACHED_TILES {
+                self.tiles.clear();
+            }
+        }
+        self.tiles.insert(tile, (generation, png));
+    }
+}
+
+#[test]
+fn test_tile_index() {
+    let (red, blue) = (Color { r: 31, g: 0, b: 0 }, Color { r: 0, g: 0, b: 31 });
+    // at zoom level 7, these pixels are the same pixel of a tile
+    let tile = Tile { z: 7, x: 64, y: 64 };
+    let pixels = [
+        (Coordinate { x: 1, y: 1 }, blue),
+        (Coordinate { x: 0, y: 1 }, red),
+    ];
+    for order in [pixels, [pixels[1], pixels[0]]] {
+        let mut index = TileIndex::new(&HashMap::new());
+        for (coord, color) in order {
+            index.insert(coord, color);
+        }
+        assert_eq!(index.pixels(tile), [(0, 0, red)]);
+    }
+
+    let mut index = TileIndex::new(&pixels.into_iter().collect());
+    let other_tile = Tile { z: 7, x: 0, y: 0 };
+    let whole_canvas = Tile { z: 0, x: 0, y: 0 };
+    let (generation, other_generation, whole_canvas_generation) = (
+        index.generation(tile),
+        index.generation(other_tile),
+        index.generation(whole_canvas),
+    );
+    // hidden by the red pixel when zoomed out
+    index.insert(Coordinate { x: 1, y: 1 }, red);
+    assert_eq!(index.generation(tile), generation);
+    assert_eq!(
+        index.generation(Tile {
+            z: 8,
+            x: 128,
+            y: 128
+        }),
+        index.generation
+    );
+    index.insert(Coordinate { x: 0, y: 1 }, blue);
+    assert_ne!(index.generation(tile), generation);
+    assert_ne!(index.generation(whole_canvas), whole_canvas_generation);
+    assert_eq!(index.generation(other_tile), other_generation);
+    assert_eq!(index.pixels(tile), [(0, 0, blue)]);
+    assert_eq!(
+        index.pixels(whole_canvas),
+        [(128, 128, blue)],
+        "the canvas' center is in the middle of the only tile at zoom level 0"
+    );
+    index.insert(
+        Coordinate {
+            x: -32768,
+            y: 32767,
+        },
+        red,
+    );
+    assert_eq!(
+        index.pixels(whole_canvas),
+        [(0, 255, red), (128, 128, blue)]
+    );
+}
@@ -0,0 +1,131 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    path::PathBuf,
+    time::{Duration, SystemTime},
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::users::UserId;
+
+/// Users who may not authenticate, either until a certain time or forever.
+/// If a file is set, every change is saved to it, so that bans survive restarts.
+#[derive(Default)]
+pub struct Bans {
+    file: Option<PathBuf>,
+    /// `None` if the ban never expires
+    bans: HashMap<UserId, Option<SystemTime>>,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct DeBan {
+    /// unix timestamp in seconds, the ban never expires if this isn't set
+    until: Option<u64>,
+}
+
+impl Bans {
+    /// Loads the bans from the file, or starts without any bans if it doesn't exist yet.
+    pub async fn load(file: PathBuf) -> Result<Self, String> {
+        let bans = match tokio::fs::read_to_string(&file).await {
+            Ok(content) => toml::from_str::<HashMap<String, DeBan>>(&content)
+                .map_err(|e| format!("Invalid bans file {}: {e}", file.display()))?
+                .into_iter()
+                .map(|(user, ban)| {
+                    (
+                        UserId::new(user),
+                        ban.until
+                            .map(|until| SystemTime::UNIX_EPOCH + Duration::from_secs(until)),
+                    )
+                })
+                .collect(),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => HashMap::new(),
+            Err(e) => return Err(format!("Could not read {}: {e}", file.display())),
+        };
+        Ok(Self {
+            file: Some(file),
+            bans,
+        })
+    }
+
+    /// If the user is banned, returns when the ban expires (`None` if never).
+    pub fn ban_of(&self, user: &UserId, now: SystemTime) -> Option<Option<SystemTime>> {
+        self.bans
+            .get(user)
+            .copied()
+            .filter(|until| until.is_none_or(|until| until > now))
+    }
+
+    pub async fn ban(&mut self, user: UserId, until: Option<SystemTime>) -> std::io::Result<()> {
+        self.bans.insert(user, until);
+        self.save().await
+    }
+
+    /// Returns `false` if the user wasn't banned.
+    pub async fn unban(&mut self, user: &UserId) -> std::io::Result<bool> {
+        let was_banned = self.bans.remove(user).is_some();
+        if was_banned {
+            self.save().await?;
+        }
+        Ok(was_banned)
+    }
+
+    /// Removes expired bans and writes the remaining bans to the file.
+    async fn save(&mut self) -> std::io::Result<()> {
+        let now = SystemTime::now();
+        self.bans
+            .retain(|_, until| until.is_none_or(|until| until > now));
+        let Some(file) = &self.file else {
+            return Ok(());
+        };
+        let bans = self
+            .bans
+            .iter()
+            .map(|(user, until)| {
+                (
+                    user.username(),
+                    DeBan {
+                        until: until.map(|until| {
+                            // round up so that the ban doesn't end early
+                            let since_epoch = until
+                                .duration_since(SystemTime::UNIX_EPOCH)
+                                .unwrap_or_default();
+                            since_epoch.as_secs() + u64::from(since_epoch.subsec_nanos() > 0)
+                        }),
+                    },
+                )
+            })
+            .collect::<BTreeMap<_, _>>();
+        let content = toml::to_string(&bans).map_err(std::io::Error::other)?;
+        // write to a temporary file first, so that the bans file is never incomplete
+        let temporary_file = file.with_extension("tmp");
+        tokio::fs::write(&temporary_file, content).await?;
+        tokio::fs::rename(&temporary_file, file).await
+    }
+}
+
+#[tokio::test]
+async fn test_bans() {
+    let file = std::env::temp_dir().join(format!("p2ws-bans-test-{}.toml", std::process::id()));
+    let (alice, bob) = (
+        UserId::new("alice".to_owned()),
+        UserId::new("bob".to_owned()),
+    );
+    let now = SystemTime::now();
+    let mut bans = Bans::load(file.clone()).await.unwrap();
+    bans.ban(alice.clone(), None).await.unwrap();
+    bans.ban(bob.clone(), Some(now + Duration::from_secs(60)))
+        .await
+        .unwrap();
+
+    let mut bans = Bans::load(file.clone()).await.unwrap();
+    assert_eq!(bans.ban_of(&alice, now), Some(None));
+    assert!(bans.ban_of(&bob, now).is_some());
+    assert_eq!(bans.ban_of(&bob, now + Duration::from_secs(61)), None);
+    assert!(bans.unban(&alice).await.unwrap());
+    assert!(!bans.unban(&alice).await.unwrap());
+
+    let bans = Bans::load(file.clone()).await.unwrap();
+    assert_eq!(bans.ban_of(&alice, now), None);
+    tokio::fs::remove_file(&file).await.unwrap();
+}
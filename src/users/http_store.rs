@@ -0,0 +1,240 @@
+use std::time::Duration;
+
+use async_trait::async_trait;
+use serde::Deserialize;
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpStream,
+};
+
+use crate::{
+    one_time_password::OtpState,
+    server::AuthenticationError,
+    users::{
+        UserId, UserRecord, UserStore,
+        save_file::{DeRatelimit, parse_role},
+    },
+};
+
+/// How long the callback may take to respond
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Asks an HTTP server, like an existing account system, about users.
+///
+/// `POST <url>/verify` with `{"username": "alice", "one_time_password": 123456}` must respond with
+/// 200 if the OTP is correct, 403 if it isn't, and 404 if there is no such user.
+/// The callback is responsible for rejecting OTPs which have already been used.
+///
+/// `POST <url>/lookup` with `{"username": "alice"}` must respond with 404 if there is no such user,
+/// or with 200 and `{"role": "moderator", "ratelimit": {"messages_per_second": 100.0, "burst": 50, "drop": true}}`,
+/// where every field is optional.
+///
+/// Only plain `http://` URLs are supported, so the callback should run on the same machine.
+pub struct HttpUserStore {
+    /// `host:port`
+    address: String,
+    /// The path of the URL, without a trailing `/`
+    path: String,
+}
+
+impl HttpUserStore {
+    pub fn new(url: &str) -> Result<Self, String> {
+        let Some(url) = url.strip_prefix("http://") else {
+            return Err(format!("{url:?} is not an http:// URL"));
+        };
+        let (host, path) = url.split_at(url.find('/').unwrap_or(url.len()));
+        if host.is_empty() {
+            return Err(format!("http://{url} has no host"));
+        }
+        Ok(Self {
+            address: if host.contains(':') {
+                host.to_owned()
+            } else {
+                format!("{host}:80")
+            },
+            path: path.trim_end_matches('/').to_owned(),
+        })
+    }
+
+    /// Sends `body` as JSON to `<url>/<endpoint>`, and returns the status code and body of the response.
+    async fn post(
+        &self,
+        endpoint: &str,
+        body: serde_json::Value,
+    ) -> Result<(u16, Vec<u8>), String> {
+        let body = body.to_string();
+        let request = format!(
+            "POST {}/{endpoint} HTTP/1.1\r\nHost: {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+            self.path,
+            self.address,
+            body.len()
+        );
+        let exchange = async {
+            let mut stream = TcpStream::connect(&self.address).await?;
+            stream.write_all(request.as_bytes()).await?;
+            let mut response = Vec::new();
+            stream.read_to_end(&mut response).await?;
+            Ok::<_, std::io::Error>(response)
+        };
+        let response = tokio::time::timeout(TIMEOUT, exchange)
+            .await
+            .map_err(|_| format!("the callback at {} timed out", self.address))?
+            .map_err(|e| format!("could not reach the callback at {}: {e}", self.address))?;
+
+        let mut headers = [httparse::EMPTY_HEADER; 32];
+        let mut parsed = httparse::Response::new(&mut headers);
+        let httparse::Status::Complete(header_length) = parsed
+            .parse(&response)
+            .map_err(|e| format!("invalid response from the callback: {e}"))?
+        else {
+            return Err("incomplete response from the callback".to_owned());
+        };
+        let header = |name: &str| {
+            parsed
+                .headers
+                .iter()
+                .find(|header| header.name.eq_ignore_ascii_case(name))
+                .and_then(|header| std::str::from_utf8(header.value).ok())
+        };
+        if header("transfer-encoding").is_some_and(|encoding| encoding != "identity") {
+            return Err("the callback must not use chunked responses".to_owned());
+        }
+        let mut body = response[header_length..].to_vec();
+        if let Some(length) = header("content-length").and_then(|length| length.parse().ok()) {
+            body.truncate(length);
+        }
+        Ok((parsed.code.unwrap_or_default(), body))
+    }
+}
+
+#[async_trait]
+impl UserStore for HttpUserStore {
+    async fn verify_one_time_password(
+        &self,
+        user_id: &UserId,
+        one_time_password: u32,
+    ) -> Result<OtpState, AuthenticationError> {
+        let body = serde_json::json!({
+            "username": user_id.username(),
+            "one_time_password": one_time_password,
+        });
+        match self
+            .post("verify", body)
+            .await
+            .map_err(AuthenticationError::UserStore)?
+        {
+            (200, _) => Ok(OtpState::default()),
+            (403, _) => Err(AuthenticationError::InvalidOneTimePassword),
+            (404, _) => Err(AuthenticationError::NoSuchUser(
+                user_id.username().to_owned(),
+            )),
+            (status, _) => Err(AuthenticationError::UserStore(format!(
+                "the callback responded with status {status}"
+            ))),
+        }
+    }
+
+    async fn lookup(&self, user_id: &UserId) -> Result<Option<UserRecord>, String> {
+        #[derive(Deserialize)]
+        struct DeUser {
+            role: Option<String>,
+            #[serde(default)]
+            ratelimit: DeRatelimit,
+        }
+
+        let body = serde_json::json!({ "username": user_id.username() });
+        match self.post("lookup", body).await? {
+            (200, body) => {
+                let user = serde_json::from_slice::<DeUser>(&body)
+                    .map_err(|e| format!("invalid user from the callback: {e}"))?;
+                Ok(Some(UserRecord {
+                    role: parse_role(user.role.as_deref())?,
+                    ratelimit: user.ratelimit.to_override()?,
+                }))
+            }
+            (404, _) => Ok(None),
+            (status, _) => Err(format!("the callback responded with status {status}")),
+        }
+    }
+
+    /// The callback keeps track of used OTPs itself.
+    async fn update_otp_state(&self, _user_id: &UserId, _state: OtpState) -> Result<(), String> {
+        Ok(())
+    }
+}
+
+#[tokio::test]
+async fn test_http_store() {
+    use crate::users::Role;
+
+    // a stub callback which knows one user, alice, whose OTP is 1234,
+    // and which wrongly accepts any OTP for `@admin`
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let address = listener.local_addr().unwrap();
+    tokio::task::spawn(async move {
+        loop {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut request = vec![0; 4096];
+            let length = stream.read(&mut request).await.unwrap();
+            let request = String::from_utf8_lossy(&request[..length]).into_owned();
+            let (status, body) = if request.contains("\"username\":\"@admin\"") {
+                ("200 OK", "{}")
+            } else if !request.contains("\"username\":\"alice\"") {
+                ("404 Not Found", "")
+            } else if request.starts_with("POST /p2ws/lookup ") {
+                (
+                    "200 OK",
+                    r#"{"role": "moderator", "ratelimit": {"burst": 5}}"#,
+                )
+            } else if request.contains("\"one_time_password\":1234") {
+                ("200 OK", "")
+            } else {
+                ("403 Forbidden", "")
+            };
+            let response = format!(
+                "HTTP/1.1 {status}\r\nContent-Length: {}\r\n\r\n{body}",
+                body.len()
+            );
+            stream.write_all(response.as_bytes()).await.unwrap();
+        }
+    });
+
+    let store = HttpUserStore::new(&format!("http://{address}/p2ws/")).unwrap();
+    let user = |name: &str| UserId::new(name.to_owned());
+    assert!(
+        store
+            .verify_one_time_password(&user("alice"), 1234)
+            .await
+            .is_ok()
+    );
+    assert!(matches!(
+        store.verify_one_time_password(&user("alice"), 4321).await,
+        Err(AuthenticationError::InvalidOneTimePassword)
+    ));
+    assert!(matches!(
+        store.verify_one_time_password(&user("bob"), 1234).await,
+        Err(AuthenticationError::NoSuchUser(_))
+    ));
+    let record = store.lookup(&user("alice")).await.unwrap().unwrap();
+    assert_eq!(record.role, Role::Moderator);
+    assert_eq!(record.ratelimit.burst_size, Some(5));
+    assert_eq!(store.lookup(&user("bob")).await.unwrap(), None);
+    assert!(HttpUserStore::new("https://example.com").is_err());
+
+    assert!(
+        store
+            .verify_one_time_password(&user("@admin"), 1)
+            .await
+            .is_ok()
+    );
+    let users = crate::users::Users::new(store);
+    assert!(matches!(
+        users.verify_one_time_password("@admin".to_owned(), 1).await,
+        Err(AuthenticationError::NoSuchUser(_))
+    ));
+    assert!(matches!(
+        users.resume_session(user("@admin")).await,
+        Err(AuthenticationError::NoSuchUser(_))
+    ));
+    assert_eq!(users.role(&user("@spectator-1")).await, Role::Viewer);
+}
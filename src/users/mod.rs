@@ -1,52 +1,455 @@
+mod bans;
+mod http_store;
 mod save_file;
+mod sqlite_store;
+mod store;
+mod toml_store;
 
-use std::{collections::HashMap, sync::Arc};
+use std::{collections::HashMap, path::PathBuf, sync::Arc, time::SystemTime};
 
 use tokio::sync::Mutex;
 
-use crate::{one_time_password::OneTimePasswordGenerator, server::AuthenticationError};
+use crate::{
+    one_time_password::OneTimePasswordGenerator,
+    ratelimit::{RatelimitOverride, RatelimitSettings},
+    server::AuthenticationError,
+};
 
-/// Contains the users who are able to authenticate.
+pub use bans::Bans;
+pub use http_store::HttpUserStore;
+pub use sqlite_store::SqliteUserStore;
+pub use store::{UserRecord, UserStore};
+pub use toml_store::TomlUserStore;
+
+/// Contains the users who are able to authenticate, and the bans and mutes which apply to them.
 ///
 /// Can be shared using `.clone()`, as its contains `Arc<Mutex<_>>`.
 #[derive(Clone)]
 pub struct Users {
-    users: Arc<Mutex<HashMap<UserId, UserData>>>,
+    store: Arc<dyn UserStore>,
+    /// The records of users who have been looked up, so that the store isn't asked for every Put.
+    records: Arc<Mutex<HashMap<UserId, UserRecord>>>,
+    /// A user's lock is held while their OTP is verified and marked as used,
+    /// so that two connections can't use the same OTP. Logins of different users don't wait for each other.
+    /// A std `Mutex`, so that `LoginLock` can remove entries when it is dropped.
+    logins: Arc<Logins>,
+    bans: Arc<Mutex<Bans>>,
+    /// Users whose Puts are ignored, until a certain time or (if `None`) until they are unmuted.
+    /// Unlike bans, mutes are lost when the server restarts.
+    mutes: Arc<Mutex<HashMap<UserId, Option<SystemTime>>>>,
+}
+
+/// The lock of every user who is logging in
+type Logins = std::sync::Mutex<HashMap<UserId, Arc<Mutex<()>>>>;
+
+/// A user's entry in `Users::logins`, which is removed when nobody else is logging in as the user,
+/// so that the lock of every username that was tried isn't kept.
+struct LoginLock {
+    logins: Arc<Logins>,
+    user_id: UserId,
+    lock: Arc<Mutex<()>>,
+}
+
+impl LoginLock {
+    fn new(logins: &Arc<Logins>, user_id: &UserId) -> Self {
+        let lock = Arc::clone(logins.lock().unwrap().entry(user_id.clone()).or_default());
+        Self {
+            logins: Arc::clone(logins),
+            user_id: user_id.clone(),
+            lock,
+        }
+    }
+}
+
+impl Drop for LoginLock {
+    fn drop(&mut self) {
+        let mut logins = self.logins.lock().unwrap();
+        if Arc::strong_count(&self.lock) == 2 {
+            logins.remove(&self.user_id);
+        }
+    }
+}
+
+/// Where the users are stored, unless they are read from the users file.
+#[derive(Clone, Debug, PartialEq)]
+pub enum UserStoreSettings {
+    /// An SQLite database, see `SqliteUserStore`
+    Sqlite(PathBuf),
+    /// The base URL of an HTTP callback, see `HttpUserStore`
+    Http(String),
+}
+
+/// The changes made by `Users::reload`.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct ReloadedUsers {
+    pub added: Vec<UserId>,
+    /// Users whose settings changed
+    pub updated: Vec<UserId>,
+    pub removed: Vec<UserId>,
 }
 
 #[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
 pub struct UserId(String);
 
+impl UserId {
+    pub fn new(username: String) -> Self {
+        Self(username)
+    }
+
+    /// The identity used for changes made by the server's administrators, such as reverting edits.
+    /// No user can authenticate as this user, because `Users` rejects usernames which start with `@`.
+    pub fn admin() -> Self {
+        Self("@admin".to_owned())
+    }
+
+    /// The identity of a connected spectator, which is unique while it is connected.
+    /// Like `admin`, no user can authenticate as a spectator.
+    pub fn spectator(id: u64) -> Self {
+        Self(format!("@spectator-{id}"))
+    }
+
+    pub fn username(&self) -> &str {
+        &self.0
+    }
+
+    /// Usernames must be 1 to 256 bytes long, so that their length fits into a byte,
+    /// and can't start with `@`, which is reserved for identities like `admin` and `spectator`.
+    pub fn is_valid_username(username: &str) -> bool {
+        (1..=256).contains(&username.len()) && !username.starts_with('@')
+    }
+}
+
+/// What a user is allowed to do. Each role can do everything the previous roles can do.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Role {
+    /// Can view the canvas, but not paint
+    Viewer,
+    #[default]
+    Painter,
+    Moderator,
+    Admin,
+}
+
+impl Role {
+    pub const ALL: [Self; 4] = [Self::Viewer, Self::Painter, Self::Moderator, Self::Admin];
+
+    /// The name used in config files
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Viewer => "viewer",
+            Self::Painter => "painter",
+            Self::Moderator => "moderator",
+            Self::Admin => "admin",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|role| role.name() == name)
+    }
+}
+
 pub struct UserData {
     one_time_password: OneTimePasswordGenerator,
+    ratelimit: RatelimitOverride,
+    role: Role,
 }
 
 impl Users {
-    pub fn new() {}
+    pub fn new(store: impl UserStore) -> Self {
+        Self {
+            store: Arc::new(store),
+            records: Default::default(),
+            logins: Default::default(),
+            bans: Default::default(),
+            mutes: Default::default(),
+        }
+    }
+
+    /// Users from a users file which doesn't contain encrypted OTP settings.
+    pub fn from_toml(toml: &str) -> Result<Users, toml::de::Error> {
+        Ok(Self::new(TomlUserStore::from_toml(toml)?))
+    }
 
     pub async fn verify_one_time_password(
         &self,
         username: String,
         provided_one_time_password: u32,
     ) -> Result<UserId, AuthenticationError> {
+        // user stores other than the users file don't check names, so reserved identities are rejected here
+        if !UserId::is_valid_username(&username) {
+            return Err(AuthenticationError::NoSuchUser(username));
+        }
         let user_id = UserId(username);
-        match self.users.lock().await.get_mut(&user_id) {
-            Some(user) => {
-                if user.one_time_password.get_current_otp().is_some_and(
-                    |expected_one_time_password| {
-                        expected_one_time_password == provided_one_time_password
-                    },
-                ) {
-                    Ok(user_id)
-                } else {
-                    Err(AuthenticationError::InvalidOneTimePassword)
-                }
+        let login = LoginLock::new(&self.logins, &user_id);
+        self.verify_locked(&user_id, provided_one_time_password, &login.lock)
+            .await
+            .map(|()| user_id)
+    }
+
+    /// Verifies the OTP while holding the user's `login` lock.
+    async fn verify_locked(
+        &self,
+        user_id: &UserId,
+        provided_one_time_password: u32,
+        login: &Mutex<()>,
+    ) -> Result<(), AuthenticationError> {
+        let _login = login.lock().await;
+        let state = self
+            .store
+            .verify_one_time_password(user_id, provided_one_time_password)
+            .await?;
+        if let Some(until) = self.ban_of(user_id).await {
+            return Err(AuthenticationError::Banned { until });
+        }
+        self.store
+            .update_otp_state(user_id, state)
+            .await
+            .map_err(AuthenticationError::UserStore)?;
+        // the user's role or ratelimit may have changed since they last authenticated
+        self.records.lock().await.remove(user_id);
+        Ok(())
+    }
+
+    /// Lets a user with a valid session token authenticate without an OTP,
+    /// unless they have been banned or removed since the token was issued.
+    pub async fn resume_session(&self, user_id: UserId) -> Result<UserId, AuthenticationError> {
+        if !UserId::is_valid_username(&user_id.0) {
+            return Err(AuthenticationError::NoSuchUser(user_id.0));
+        }
+        if let Some(until) = self.ban_of(&user_id).await {
+            return Err(AuthenticationError::Banned { until });
+        }
+        self.records.lock().await.remove(&user_id);
+        match self.store.lookup(&user_id).await {
+            Ok(Some(_)) => Ok(user_id),
+            Ok(None) => Err(AuthenticationError::NoSuchUser(user_id.0)),
+            Err(e) => Err(AuthenticationError::UserStore(e)),
+        }
+    }
+
+    /// The user's record, which is only looked up in the store if it isn't cached.
+    async fn record(&self, user_id: &UserId) -> Option<UserRecord> {
+        if !UserId::is_valid_username(&user_id.0) {
+            return None;
+        }
+        if let Some(record) = self.records.lock().await.get(user_id) {
+            return Some(*record);
+        }
+        match self.store.lookup(user_id).await {
+            Ok(Some(record)) => {
+                self.records.lock().await.insert(user_id.clone(), record);
+                Some(record)
+            }
+            Ok(None) => None,
+            Err(e) => {
+                tracing::warn!(user = user_id.username(), "Could not look up the user: {e}");
+                None
             }
-            None => Err(AuthenticationError::NoSuchUser(user_id.0)),
         }
     }
 
-    pub fn from_toml(toml: &str) -> Result<Users, toml::de::Error> {
-        save_file::parse(toml)
+    /// The ratelimit which applies to this user, which is `default`
+    /// with the user's overrides from the user store applied.
+    pub async fn ratelimit(
+        &self,
+        user_id: &UserId,
+        default: RatelimitSettings,
+    ) -> RatelimitSettings {
+        match self.record(user_id).await {
+            Some(record) => default.with_override(&record.ratelimit),
+            None => default,
+        }
+    }
+
+    /// The user's role. Users who aren't in the user store, like spectators, are viewers,
+    /// except for `UserId::admin()`.
+    pub async fn role(&self, user_id: &UserId) -> Role {
+        if *user_id == UserId::admin() {
+            return Role::Admin;
+        }
+        self.record(user_id)
+            .await
+            .map_or(Role::Viewer, |record| record.role)
+    }
+
+    /// See `UserStore::add`.
+    pub async fn add(
+        &self,
+        user_id: UserId,
+        one_time_password: OneTimePasswordGenerator,
+        role: Role,
+    ) -> Result<bool, String> {
+        self.store.add(user_id, one_time_password, role).await
+    }
+
+    /// See `UserStore::remove`.
+    pub async fn remove(&self, user_id: &UserId) -> Result<bool, String> {
+        let removed = self.store.remove(user_id).await;
+        self.records.lock().await.remove(user_id);
+        removed
+    }
+
+    /// All users and their roles, sorted by name
+    pub async fn list(&self) -> Result<Vec<(UserId, Role)>, String> {
+        self.store.list().await
+    }
+
+    /// Replaces the user's ratelimit overrides. Returns `Ok(false)` if the user doesn't exist.
+    pub async fn set_ratelimit(
+        &self,
+        user_id: &UserId,
+        ratelimit: RatelimitOverride,
+    ) -> Result<bool, String> {
+        let updated = self.store.set_ratelimit(user_id, ratelimit).await;
+        self.records.lock().await.remove(user_id);
+        updated
+    }
+
+    /// Replaces the bans, which are empty and not saved anywhere by default.
+    pub fn with_bans(mut self, bans: Bans) -> Self {
+        self.bans = Arc::new(Mutex::new(bans));
+        self
+    }
+
+    /// If the user is banned, returns when the ban expires (`None` if never).
+    pub async fn ban_of(&self, user_id: &UserId) -> Option<Option<SystemTime>> {
+        self.bans.lock().await.ban_of(user_id, SystemTime::now())
+    }
+
+    /// Bans the user until `until`, or forever if it is `None`.
+    pub async fn ban(&self, user_id: UserId, until: Option<SystemTime>) -> std::io::Result<()> {
+        self.bans.lock().await.ban(user_id, until).await
+    }
+
+    /// Returns `false` if the user wasn't banned.
+    pub async fn unban(&self, user_id: &UserId) -> std::io::Result<bool> {
+        self.bans.lock().await.unban(user_id).await
+    }
+
+    /// Ignores the user's Puts until `until`, or until they are unmuted if it is `None`.
+    pub async fn mute(&self, user_id: UserId, until: Option<SystemTime>) {
+        self.mutes.lock().await.insert(user_id, until);
+    }
+
+    /// Returns `false` if the user wasn't muted.
+    pub async fn unmute(&self, user_id: &UserId) -> bool {
+        self.mutes.lock().await.remove(user_id).is_some()
+    }
+
+    pub async fn is_muted(&self, user_id: &UserId) -> bool {
+        let mut mutes = self.mutes.lock().await;
+        match mutes.get(user_id) {
+            Some(Some(until)) if *until <= SystemTime::now() => {
+                mutes.remove(user_id);
+                false
+            }
+            Some(_) => true,
+            None => false,
+        }
+    }
+
+    /// Reloads the user store, see `UserStore::reload`, and forgets all cached records.
+    pub async fn reload(&self) -> Result<ReloadedUsers, String> {
+        let reloaded = self.store.reload().await;
+        self.records.lock().await.clear();
+        reloaded
     }
 }
+
+#[tokio::test]
+async fn test_reload() {
+    let file = std::env::temp_dir().join(format!("p2ws-users-test-{}.toml", std::process::id()));
+    let user = |name: &str| UserId::new(name.to_owned());
+    tokio::fs::write(
+        &file,
+        "a.otp.Static = 1\nb.otp.Static = 2\nc.otp.Static = 3\n",
+    )
+    .await
+    .unwrap();
+    let users = Users::new(TomlUserStore::load(file.clone(), None).await.unwrap());
+    assert!(
+        users
+            .verify_one_time_password("a".to_owned(), 1)
+            .await
+            .is_ok()
+    );
+
+    tokio::fs::write(
+        &file,
+        "a.otp.Static = 1\nb = { otp.Static = 2, role = \"moderator\" }\nd.otp.Static = 4\n",
+    )
+    .await
+    .unwrap();
+    assert_eq!(
+        users.reload().await.unwrap(),
+        ReloadedUsers {
+            added: vec![user("d")],
+            updated: vec![user("b")],
+            removed: vec![user("c")],
+        }
+    );
+    assert_eq!(users.role(&user("b")).await, Role::Moderator);
+    assert!(
+        users
+            .verify_one_time_password("c".to_owned(), 3)
+            .await
+            .is_err()
+    );
+    tokio::fs::remove_file(&file).await.unwrap();
+}
+
+#[tokio::test]
+async fn test_slow_logins_only_block_the_same_user() {
+    use crate::one_time_password::OtpState;
+
+    /// Never answers for the user `slow`
+    struct SlowStore;
+    #[async_trait::async_trait]
+    impl UserStore for SlowStore {
+        async fn verify_one_time_password(
+            &self,
+            user_id: &UserId,
+            _one_time_password: u32,
+        ) -> Result<OtpState, AuthenticationError> {
+            if user_id.username() == "slow" {
+                std::future::pending::<()>().await;
+            }
+            Ok(OtpState::default())
+        }
+        async fn lookup(&self, _user_id: &UserId) -> Result<Option<UserRecord>, String> {
+            Ok(None)
+        }
+        async fn update_otp_state(
+            &self,
+            _user_id: &UserId,
+            _state: OtpState,
+        ) -> Result<(), String> {
+            Ok(())
+        }
+    }
+
+    let users = Users::new(SlowStore);
+    let slow = tokio::task::spawn({
+        let users = users.clone();
+        async move { users.verify_one_time_password("slow".to_owned(), 1).await }
+    });
+    tokio::task::yield_now().await;
+    let fast = users.verify_one_time_password("fast".to_owned(), 1);
+    assert!(
+        tokio::time::timeout(std::time::Duration::from_secs(1), fast)
+            .await
+            .unwrap()
+            .is_ok()
+    );
+    assert!(
+        !users
+            .logins
+            .lock()
+            .unwrap()
+            .contains_key(&UserId::new("fast".to_owned()))
+    );
+    slow.abort();
+    // the lock is removed even though the login was cancelled
+    assert!(slow.await.unwrap_err().is_cancelled());
+    assert!(users.logins.lock().unwrap().is_empty());
+}
@@ -1,39 +1,182 @@
-use std::{collections::HashMap, sync::Arc};
+use std::{collections::HashMap, time::Duration};
 
 use serde::Deserialize;
-use tokio::sync::Mutex;
 
 use crate::{
-    one_time_password::OneTimePasswordGenerator,
-    users::{UserData, UserId, Users},
+    one_time_password::{OneTimePasswordGenerator, OtpKey, Totp},
+    ratelimit::RatelimitOverride,
+    users::{Role, UserData, UserId},
 };
 
-pub fn parse(file_content: &str) -> Result<Users, toml::de::Error> {
+/// A user's ratelimit overrides, as written in the users file.
+/// The HTTP user store accepts the same fields.
+#[derive(Deserialize, Default)]
+#[serde(deny_unknown_fields)]
+pub struct DeRatelimit {
+    pub messages_per_second: Option<f64>,
+    pub burst: Option<u32>,
+    pub drop: Option<bool>,
+}
+
+impl DeRatelimit {
+    pub fn to_override(&self) -> Result<RatelimitOverride, String> {
+        let time_per_message = match self.messages_per_second {
+            // rates close to 0 would be longer than the longest possible `Duration`
+            Some(per_second) if per_second.is_finite() && per_second > 0.0 => Some(
+                Duration::try_from_secs_f64(1.0 / per_second)
+                    .map_err(|_| "ratelimit.messages_per_second is too small".to_owned())?,
+            ),
+            Some(_) => {
+                return Err("ratelimit.messages_per_second must be a positive number".to_owned());
+            }
+            None => None,
+        };
+        Ok(RatelimitOverride {
+            time_per_message,
+            burst_size: self.burst,
+            drop_instead_of_blocking: self.drop,
+        })
+    }
+}
+
+/// Parses the name of a role, which is the default role if it is `None`.
+pub fn parse_role(name: Option<&str>) -> Result<Role, String> {
+    match name {
+        Some(name) => Role::from_name(name).ok_or_else(|| {
+            format!("unknown role {name:?}, expected viewer, painter, moderator or admin")
+        }),
+        None => Ok(Role::default()),
+    }
+}
+
+/// `key` is used to decrypt encrypted OTP settings, which are an error if there is no key.
+pub fn parse(
+    file_content: &str,
+    key: Option<&OtpKey>,
+) -> Result<HashMap<UserId, UserData>, toml::de::Error> {
     #[derive(Deserialize)]
     struct DeUsersFile {
         otp: DeOtpMode,
+        #[serde(default)]
+        ratelimit: DeRatelimit,
+        role: Option<String>,
     }
     #[derive(Deserialize)]
     enum DeOtpMode {
         Static(u32),
+        /// base32 encoded secret
+        Totp(String),
+        /// encrypted with `OtpKey::encrypt`
+        Encrypted(String),
     }
-
     let de = toml::from_str::<HashMap<String, DeUsersFile>>(file_content)?;
 
-    Ok(Users {
-        users: Arc::new(Mutex::new(
-            de.into_iter()
-                .map(|(user, data)| {
-                    (
-                        UserId(user),
-                        UserData {
-                            one_time_password: match data.otp {
-                                DeOtpMode::Static(pin) => OneTimePasswordGenerator::Static(pin),
-                            },
-                        },
-                    )
-                })
-                .collect(),
-        )),
-    })
+    let mut users = HashMap::with_capacity(de.len());
+    for (user, data) in de {
+        if !UserId::is_valid_username(&user) {
+            return Err(serde::de::Error::custom(format!(
+                "user {user:?}: usernames must be 1 to 256 bytes long and can't start with `@`"
+            )));
+        }
+        let role = parse_role(data.role.as_deref())
+            .map_err(|e| serde::de::Error::custom(format!("user {user}: {e}")))?;
+        let ratelimit = data
+            .ratelimit
+            .to_override()
+            .map_err(|e| serde::de::Error::custom(format!("user {user}: {e}")))?;
+        let one_time_password = match data.otp {
+            DeOtpMode::Static(pin) => OneTimePasswordGenerator::Static(pin),
+            DeOtpMode::Totp(secret) => {
+                OneTimePasswordGenerator::Totp(Totp::from_base32(&secret).ok_or_else(|| {
+                    serde::de::Error::custom(format!(
+                        "user {user}: otp.Totp must be a base32 encoded secret"
+                    ))
+                })?)
+            }
+            DeOtpMode::Encrypted(encrypted) => {
+                let Some(key) = key else {
+                    return Err(serde::de::Error::custom(format!(
+                        "user {user}: the OTP settings are encrypted, but no key was provided"
+                    )));
+                };
+                key.decrypt(&user, &encrypted)
+                    .and_then(|plaintext| OneTimePasswordGenerator::from_plaintext(&plaintext))
+                    .ok_or_else(|| {
+                        serde::de::Error::custom(format!(
+                            "user {user}: the OTP settings could not be decrypted with the provided key"
+                        ))
+                    })?
+            }
+        };
+        users.insert(
+            UserId(user),
+            UserData {
+                one_time_password,
+                ratelimit,
+                role,
+            },
+        );
+    }
+    Ok(users)
+}
+
+#[test]
+fn test_parse_ratelimit_overrides() {
+    let users = parse(
+        r#"
+        [human]
+        otp.Static = 1234
+
+        [bot]
+        otp.Static = 5678
+        ratelimit = { messages_per_second = 100.0, burst = 50, drop = false }
+        role = "moderator"
+        "#,
+        None,
+    )
+    .unwrap();
+    assert_eq!(
+        users[&UserId("human".to_owned())].ratelimit,
+        RatelimitOverride::default()
+    );
+    assert_eq!(
+        users[&UserId("bot".to_owned())].ratelimit,
+        RatelimitOverride {
+            time_per_message: Some(Duration::from_millis(10)),
+            burst_size: Some(50),
+            drop_instead_of_blocking: Some(false),
+        }
+    );
+    assert_eq!(users[&UserId("human".to_owned())].role, Role::Painter);
+    assert_eq!(users[&UserId("bot".to_owned())].role, Role::Moderator);
+    assert!(parse("[mod]\notp.Static = 1\nrole = \"janitor\"\n", None).is_err());
+    assert!(
+        parse(
+            "[bot]\notp.Static = 1\nratelimit.messages_per_second = 0.0\n",
+            None
+        )
+        .is_err()
+    );
+    assert!(
+        parse(
+            "[bot]\notp.Static = 1\nratelimit.messages_per_second = 1e-20\n",
+            None
+        )
+        .is_err()
+    );
+
+    let key = OtpKey::generate().unwrap();
+    let encrypted = format!(
+        "[alice]\notp.Encrypted = {:?}\n",
+        key.encrypt("alice", "Static:1234").unwrap()
+    );
+    assert!(parse(&encrypted, None).is_err());
+    assert!(parse(&encrypted, Some(&OtpKey::generate().unwrap())).is_err());
+    let users = parse(&encrypted, Some(&key)).unwrap();
+    assert!(
+        users[&UserId("alice".to_owned())]
+            .one_time_password
+            .verify(1234)
+            .is_some()
+    );
 }
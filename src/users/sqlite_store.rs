@@ -0,0 +1,287 @@
+use std::{
+    path::PathBuf,
+    sync::{Arc, Mutex},
+};
+
+use async_trait::async_trait;
+use rusqlite::{Connection, OptionalExtension};
+
+use crate::{
+    one_time_password::{OneTimePasswordGenerator, OtpKey, OtpState},
+    server::AuthenticationError,
+    users::{
+        Role, UserId, UserRecord, UserStore,
+        save_file::{DeRatelimit, parse_role},
+    },
+};
+
+/// `otp` is `Static:<pin>`, `Totp:<base32 secret>`, or `Encrypted:<encrypted settings>`
+/// (see `OtpKey::encrypt`). Roles and ratelimit overrides use the same values as the users file.
+const SCHEMA: &str = "CREATE TABLE IF NOT EXISTS users (
+    username TEXT PRIMARY KEY NOT NULL,
+    otp TEXT NOT NULL,
+    role TEXT,
+    messages_per_second REAL,
+    burst INTEGER,
+    drop_messages INTEGER,
+    last_used_step INTEGER
+)";
+
+/// Users stored in an SQLite database, which other programs can change while the server is running.
+pub struct SqliteUserStore {
+    connection: Arc<Mutex<Connection>>,
+    /// The key used to decrypt encrypted OTP settings
+    key: Option<OtpKey>,
+}
+
+impl SqliteUserStore {
+    /// Opens the database, and creates the users table if it doesn't exist.
+    pub async fn open(path: PathBuf, key: Option<OtpKey>) -> Result<Self, String> {
+        let connection = tokio::task::spawn_blocking(move || {
+            let connection = Connection::open(&path)?;
+            connection.execute(SCHEMA, ())?;
+            Ok::<_, rusqlite::Error>(connection)
+        })
+        .await
+        .map_err(|e| e.to_string())?
+        .map_err(|e| format!("Could not open the user database: {e}"))?;
+        Ok(Self {
+            connection: Arc::new(Mutex::new(connection)),
+            key,
+        })
+    }
+
+    /// Runs `query` on a blocking thread, since SQLite's API is synchronous.
+    async fn query<T: Send + 'static>(
+        &self,
+        query: impl FnOnce(&Connection) -> rusqlite::Result<T> + Send + 'static,
+    ) -> Result<T, String> {
+        let connection = Arc::clone(&self.connection);
+        tokio::task::spawn_blocking(move || query(&connection.lock().unwrap()))
+            .await
+            .map_err(|e| e.to_string())?
+            .map_err(|e| e.to_string())
+    }
+
+    fn one_time_password(
+        &self,
+        user_id: &UserId,
+        otp: &str,
+    ) -> Result<OneTimePasswordGenerator, String> {
+        let plaintext = match otp.strip_prefix("Encrypted:") {
+            Some(encrypted) => {
+                let key = self.key.as_ref().ok_or_else(|| {
+                    format!(
+                        "the OTP settings of {user_id:?} are encrypted, but no key was provided"
+                    )
+                })?;
+                key.decrypt(user_id.username(), encrypted).ok_or_else(|| {
+                    format!("the OTP settings of {user_id:?} could not be decrypted")
+                })?
+            }
+            None => otp.to_owned(),
+        };
+        OneTimePasswordGenerator::from_plaintext(&plaintext)
+            .ok_or_else(|| format!("the OTP settings of {user_id:?} are invalid"))
+    }
+}
+
+#[async_trait]
+impl UserStore for SqliteUserStore {
+    async fn verify_one_time_password(
+        &self,
+        user_id: &UserId,
+        one_time_password: u32,
+    ) -> Result<OtpState, AuthenticationError> {
+        let username = user_id.username().to_owned();
+        let user = self
+            .query(move |connection| {
+                connection
+                    .query_row(
+                        "SELECT otp, last_used_step FROM users WHERE username = ?1",
+                        [username],
+                        |row| Ok((row.get::<_, String>(0)?, row.get::<_, Option<i64>>(1)?)),
+                    )
+                    .optional()
+            })
+            .await
+            .map_err(AuthenticationError::UserStore)?;
+        let Some((otp, last_used_step)) = user else {
+            return Err(AuthenticationError::NoSuchUser(
+                user_id.username().to_owned(),
+            ));
+        };
+        let mut generator = self
+            .one_time_password(user_id, &otp)
+            .map_err(AuthenticationError::UserStore)?;
+        generator.set_state(OtpState {
+            last_used_step: last_used_step.map(|step| step as u64),
+        });
+        generator
+            .verify(one_time_password)
+            .ok_or(AuthenticationError::InvalidOneTimePassword)
+    }
+
+    async fn lookup(&self, user_id: &UserId) -> Result<Option<UserRecord>, String> {
+        let username = user_id.username().to_owned();
+        let user = self
+            .query(move |connection| {
+                connection
+                    .query_row(
+                        "SELECT role, messages_per_second, burst, drop_messages FROM users WHERE username = ?1",
+                        [username],
+                        |row| {
+                            Ok((
+                                row.get::<_, Option<String>>(0)?,
+                                DeRatelimit {
+                                    messages_per_second: row.get(1)?,
+                                    burst: row.get(2)?,
+                                    drop: row.get(3)?,
+                                },
+                            ))
+                        },
+                    )
+                    .optional()
+            })
+            .await?;
+        let Some((role, ratelimit)) = user else {
+            return Ok(None);
+        };
+        Ok(Some(UserRecord {
+            role: parse_role(role.as_deref())?,
+            ratelimit: ratelimit.to_override()?,
+        }))
+    }
+
+    async fn update_otp_state(&self, user_id: &UserId, state: OtpState) -> Result<(), String> {
+        let username = user_id.username().to_owned();
+        let last_used_step = state.last_used_step.map(|step| step as i64);
+        self.query(move |connection| {
+            connection.execute(
+                "UPDATE users SET last_used_step = ?1 WHERE username = ?2",
+                (last_used_step, username),
+            )
+        })
+        .await?;
+        Ok(())
+    }
+
+    async fn list(&self) -> Result<Vec<(UserId, Role)>, String> {
+        let users = self
+            .query(|connection| {
+                connection
+                    .prepare("SELECT username, role FROM users ORDER BY username")?
+                    .query_map((), |row| {
+                        Ok((row.get::<_, String>(0)?, row.get::<_, Option<String>>(1)?))
+                    })?
+                    .collect::<rusqlite::Result<Vec<_>>>()
+            })
+            .await?;
+        users
+            .into_iter()
+            .map(|(username, role)| Ok((UserId::new(username), parse_role(role.as_deref())?)))
+            .collect()
+    }
+}
+
+#[tokio::test]
+async fn test_sqlite_store() {
+    let file = std::env::temp_dir().join(format!("p2ws-users-test-{}.sqlite", std::process::id()));
+    let store = SqliteUserStore::open(file.clone(), None).await.unwrap();
+    store
+        .query(|connection| {
+            connection.execute(
+                "INSERT INTO users (username, otp, role, messages_per_second) VALUES
+                    ('alice', 'Static:1234', 'moderator', 100.0),
+                    ('bob', 'Totp:GEZDGNBVGY3TQOJQGEZDGNBVGY3TQOJQ', NULL, NULL)",
+                (),
+            )
+        })
+        .await
+        .unwrap();
+    let user = |name: &str| UserId::new(name.to_owned());
+
+    let state = store
+        .verify_one_time_password(&user("alice"), 1234)
+        .await
+        .ok()
+        .unwrap();
+    assert_eq!(state, OtpState::default());
+    assert!(
+        store
+            .verify_one_time_password(&user("alice"), 1235)
+            .await
+            .is_err()
+    );
+    assert!(
+        store
+            .verify_one_time_password(&user("carol"), 1234)
+            .await
+            .is_err()
+    );
+
+    // the OTP state survives restarts
+    let state = OtpState {
+        last_used_step: Some(12345),
+    };
+    store.update_otp_state(&user("bob"), state).await.unwrap();
+    drop(store);
+    let store = SqliteUserStore::open(file.clone(), None).await.unwrap();
+    let last_used_step = store
+        .query(|connection| {
+            connection.query_row(
+                "SELECT last_used_step FROM users WHERE username = 'bob'",
+                (),
+                |row| row.get::<_, i64>(0),
+            )
+        })
+        .await
+        .unwrap();
+    assert_eq!(last_used_step, 12345);
+
+    assert_eq!(
+        store.lookup(&user("alice")).await.unwrap().unwrap().role,
+        Role::Moderator
+    );
+    assert_eq!(
+        store.lookup(&user("bob")).await.unwrap().unwrap().role,
+        Role::Painter
+    );
+    assert_eq!(store.lookup(&user("carol")).await.unwrap(), None);
+    assert_eq!(
+        store.list().await.unwrap(),
+        vec![
+            (user("alice"), Role::Moderator),
+            (user("bob"), Role::Painter)
+        ]
+    );
+
+    // the table doesn't restrict names, but nobody can log in as a reserved identity or with an overlong name
+    let long_name = "a".repeat(257);
+    let insert = format!(
+        "INSERT INTO users (username, otp, role) VALUES
+            ('@admin', 'Static:1', NULL), ('{long_name}', 'Static:1', NULL)"
+    );
+    store
+        .query(move |connection| connection.execute(&insert, ()))
+        .await
+        .unwrap();
+    assert!(
+        store
+            .verify_one_time_password(&user("@admin"), 1)
+            .await
+            .is_ok()
+    );
+    let users = crate::users::Users::new(store);
+    for name in ["@admin", &long_name] {
+        assert!(matches!(
+            users.verify_one_time_password(name.to_owned(), 1).await,
+            Err(AuthenticationError::NoSuchUser(_))
+        ));
+        assert!(matches!(
+            users.resume_session(user(name)).await,
+            Err(AuthenticationError::NoSuchUser(_))
+        ));
+    }
+    std::fs::remove_file(&file).unwrap();
+}
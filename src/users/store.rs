@@ -0,0 +1,72 @@
+use async_trait::async_trait;
+
+use crate::{
+    one_time_password::{OneTimePasswordGenerator, OtpState},
+    ratelimit::RatelimitOverride,
+    server::AuthenticationError,
+    users::{ReloadedUsers, Role, UserId},
+};
+
+/// What the server needs to know about a user once they have authenticated.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct UserRecord {
+    pub role: Role,
+    pub ratelimit: RatelimitOverride,
+}
+
+/// Where the users who are able to authenticate are stored.
+///
+/// Bans and mutes are handled by `Users`, so stores don't need to know about them.
+/// The methods after `update_otp_state` change the users at runtime,
+/// which not every store supports.
+#[async_trait]
+pub trait UserStore: Send + Sync + 'static {
+    /// Checks the user's OTP without marking it as used.
+    /// If it is correct, returns the state which `update_otp_state` saves to prevent it from being used again.
+    async fn verify_one_time_password(
+        &self,
+        user_id: &UserId,
+        one_time_password: u32,
+    ) -> Result<OtpState, AuthenticationError>;
+
+    /// Returns `Ok(None)` if there is no such user.
+    async fn lookup(&self, user_id: &UserId) -> Result<Option<UserRecord>, String>;
+
+    async fn update_otp_state(&self, user_id: &UserId, state: OtpState) -> Result<(), String>;
+
+    /// All users and their roles, sorted by name
+    async fn list(&self) -> Result<Vec<(UserId, Role)>, String> {
+        Err(UNSUPPORTED.to_owned())
+    }
+
+    /// Returns `Ok(false)` if a user with that name already exists.
+    async fn add(
+        &self,
+        _user_id: UserId,
+        _one_time_password: OneTimePasswordGenerator,
+        _role: Role,
+    ) -> Result<bool, String> {
+        Err(UNSUPPORTED.to_owned())
+    }
+
+    /// Returns `Ok(false)` if the user didn't exist.
+    async fn remove(&self, _user_id: &UserId) -> Result<bool, String> {
+        Err(UNSUPPORTED.to_owned())
+    }
+
+    /// Replaces the user's ratelimit overrides. Returns `Ok(false)` if the user doesn't exist.
+    async fn set_ratelimit(
+        &self,
+        _user_id: &UserId,
+        _ratelimit: RatelimitOverride,
+    ) -> Result<bool, String> {
+        Err(UNSUPPORTED.to_owned())
+    }
+
+    /// Reads the users again. Stores which always read the current users don't have to do anything.
+    async fn reload(&self) -> Result<ReloadedUsers, String> {
+        Ok(ReloadedUsers::default())
+    }
+}
+
+const UNSUPPORTED: &str = "the user store does not support this";
@@ -0,0 +1,215 @@
+use std::{collections::HashMap, path::PathBuf};
+
+use async_trait::async_trait;
+use tokio::sync::Mutex;
+
+use crate::{
+    one_time_password::{OneTimePasswordGenerator, OtpKey, OtpState},
+    ratelimit::RatelimitOverride,
+    server::AuthenticationError,
+    users::{ReloadedUsers, Role, UserData, UserId, UserRecord, UserStore, save_file},
+};
+
+/// The users from a users file, which are kept in memory.
+/// Changes made at runtime are lost when the server restarts.
+pub struct TomlUserStore {
+    users: Mutex<HashMap<UserId, UserData>>,
+    /// The users file, if the users were loaded from a file
+    file: Option<PathBuf>,
+    /// The key used to decrypt OTP settings when the file is reloaded
+    key: Option<OtpKey>,
+}
+
+impl TomlUserStore {
+    /// Parses a users file which doesn't contain encrypted OTP settings.
+    pub fn from_toml(toml: &str) -> Result<Self, toml::de::Error> {
+        Self::from_toml_with_key(toml, None)
+    }
+
+    /// Parses a users file, decrypting encrypted OTP settings with `key`.
+    pub fn from_toml_with_key(toml: &str, key: Option<&OtpKey>) -> Result<Self, toml::de::Error> {
+        Ok(Self {
+            users: Mutex::new(save_file::parse(toml, key)?),
+            file: None,
+            key: None,
+        })
+    }
+
+    /// Loads the users from a file, which `reload` reads again later.
+    pub async fn load(file: PathBuf, key: Option<OtpKey>) -> Result<Self, String> {
+        let content = tokio::fs::read_to_string(&file)
+            .await
+            .map_err(|e| format!("Could not read {}: {e}", file.display()))?;
+        let mut users = Self::from_toml_with_key(&content, key.as_ref())
+            .map_err(|e| format!("Could not parse {}: {e}", file.display()))?;
+        users.file = Some(file);
+        users.key = key;
+        Ok(users)
+    }
+}
+
+#[async_trait]
+impl UserStore for TomlUserStore {
+    async fn verify_one_time_password(
+        &self,
+        user_id: &UserId,
+        one_time_password: u32,
+    ) -> Result<OtpState, AuthenticationError> {
+        match self.users.lock().await.get(user_id) {
+            Some(user) => user
+                .one_time_password
+                .verify(one_time_password)
+                .ok_or(AuthenticationError::InvalidOneTimePassword),
+            None => Err(AuthenticationError::NoSuchUser(user_id.0.clone())),
+        }
+    }
+
+    async fn lookup(&self, user_id: &UserId) -> Result<Option<UserRecord>, String> {
+        Ok(self.users.lock().await.get(user_id).map(|user| UserRecord {
+            role: user.role,
+            ratelimit: user.ratelimit,
+        }))
+    }
+
+    async fn update_otp_state(&self, user_id: &UserId, state: OtpState) -> Result<(), String> {
+        if let Some(user) = self.users.lock().await.get_mut(user_id) {
+            user.one_time_password.set_state(state);
+        }
+        Ok(())
+    }
+
+    async fn list(&self) -> Result<Vec<(UserId, Role)>, String> {
+        let mut users = self
+            .users
+            .lock()
+            .await
+            .iter()
+            .map(|(user_id, user)| (user_id.clone(), user.role))
+            .collect::<Vec<_>>();
+        users.sort();
+        Ok(users)
+    }
+
+    /// Adds a user until the server restarts, without changing the users file.
+    async fn add(
+        &self,
+        user_id: UserId,
+        one_time_password: OneTimePasswordGenerator,
+        role: Role,
+    ) -> Result<bool, String> {
+        let mut users = self.users.lock().await;
+        if users.contains_key(&user_id) {
+            return Ok(false);
+        }
+        users.insert(
+            user_id,
+            UserData {
+                one_time_password,
+                ratelimit: RatelimitOverride::default(),
+                role,
+            },
+        );
+        Ok(true)
+    }
+
+    /// Removes a user until the server restarts, without changing the users file.
+    async fn remove(&self, user_id: &UserId) -> Result<bool, String> {
+        Ok(self.users.lock().await.remove(user_id).is_some())
+    }
+
+    async fn set_ratelimit(
+        &self,
+        user_id: &UserId,
+        ratelimit: RatelimitOverride,
+    ) -> Result<bool, String> {
+        match self.users.lock().await.get_mut(user_id) {
+            Some(user) => {
+                user.ratelimit = ratelimit;
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+
+    /// Reads the users file again and applies the changes.
+    /// Users whose OTP settings didn't change keep their OTP state, so a used OTP stays used.
+    /// Users added at runtime are removed unless they are in the file.
+    async fn reload(&self) -> Result<ReloadedUsers, String> {
+        let Some(file) = &self.file else {
+            return Err("The users were not loaded from a file".to_owned());
+        };
+        let content = tokio::fs::read_to_string(file)
+            .await
+            .map_err(|e| format!("Could not read {}: {e}", file.display()))?;
+        let new_users = save_file::parse(&content, self.key.as_ref())
+            .map_err(|e| format!("Could not parse {}: {e}", file.display()))?;
+
+        let mut users = self.users.lock().await;
+        let mut reloaded = ReloadedUsers::default();
+        users.retain(|user_id, _| {
+            let keep = new_users.contains_key(user_id);
+            if !keep {
+                reloaded.removed.push(user_id.clone());
+            }
+            keep
+        });
+        for (user_id, new_user) in new_users {
+            match users.get_mut(&user_id) {
+                Some(user) => {
+                    let same_otp = user
+                        .one_time_password
+                        .has_same_settings(&new_user.one_time_password);
+                    if !same_otp
+                        || user.ratelimit != new_user.ratelimit
+                        || user.role != new_user.role
+                    {
+                        reloaded.updated.push(user_id);
+                    }
+                    if !same_otp {
+                        user.one_time_password = new_user.one_time_password;
+                    }
+                    user.ratelimit = new_user.ratelimit;
+                    user.role = new_user.role;
+                }
+                None => {
+                    reloaded.added.push(user_id.clone());
+                    users.insert(user_id, new_user);
+                }
+            }
+        }
+        reloaded.added.sort();
+        reloaded.updated.sort();
+        reloaded.removed.sort();
+        Ok(reloaded)
+    }
+}
+
+#[tokio::test]
+async fn test_reserved_usernames() {
+    use crate::users::Users;
+
+    assert!(TomlUserStore::from_toml("\"@admin\".otp.Static = 1").is_err());
+    assert!(TomlUserStore::from_toml(&format!("{}.otp.Static = 1", "a".repeat(257))).is_err());
+
+    // users added at runtime aren't checked by the store
+    let store = TomlUserStore::from_toml("").unwrap();
+    let admin = UserId::admin();
+    store
+        .add(
+            admin.clone(),
+            OneTimePasswordGenerator::Static(1),
+            Role::Admin,
+        )
+        .await
+        .unwrap();
+    assert!(store.verify_one_time_password(&admin, 1).await.is_ok());
+    let users = Users::new(store);
+    assert!(matches!(
+        users.verify_one_time_password("@admin".to_owned(), 1).await,
+        Err(AuthenticationError::NoSuchUser(_))
+    ));
+    assert!(matches!(
+        users.resume_session(admin).await,
+        Err(AuthenticationError::NoSuchUser(_))
+    ));
+}